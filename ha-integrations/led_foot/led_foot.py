@@ -33,10 +33,10 @@ class LedFootApi:
         LedFootApi.set_rooms(self.rooms)
 
     def check_connection() -> bool:
-        '''check connection to Led Foot server'''
-        resp = requests.get(LED_FOOT_SERVER_API)
+        '''check connection to Led Foot server, and that its LEDs are connected'''
+        resp = requests.get(LED_FOOT_SERVER_API + 'ready')
         if resp.status_code != 200:
-            raise Exception('Unable to connect to Led Foot server API: ' + resp.text)
+            raise Exception('Led Foot server is not ready: ' + resp.text)
         return True
 
 
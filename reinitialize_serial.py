@@ -1,5 +1,6 @@
 # Sometimes the Raspberry Pi serial connection to the Arduino gets stuck. This
-# script "flushes" the output.
+# script "flushes" the output. Only needed with firmware that speaks version 1
+# of the serial protocol; version 2 resyncs on its own.
 #
 # Needs the `pyserial` package.
 
@@ -15,19 +15,69 @@
 #define NUM_COLORS 4
 #define NUM_ROOMS 3
 
+// Has to match `baud_rate` in the [serial] section of led_config.toml
+#define BAUD_RATE 9600
+
+// Protocol version 1 (what the firmware speaks after starting up):
+//
 // 1 magic byte for determining whether a color command is being sent, or a room relay command
 // Then, either:
 //   Color command: 8 more bytes, 2 bytes for each color channel
-//       (they're shorts, represented in Little-Endian format)
+//       (they're shorts, represented in Big-Endian format)
 //   Room relay command: up to 3 bytes plus up to 8 bytes of zero-padding
 //       Contains an array of the currently active rooms
-//       (0x1C, 0x1A, 0x18 for LIVING_ROOM, OFFICE, and BEDROOM respectively)
+//       (0x1A, 0x1C, 0x18 for LIVING_ROOM, OFFICE, and BEDROOM respectively)
+// Replies are "C\r\n", "R\r\n", or "X\r\n" for anything else.
 #define BUFSIZE 9
 
 // Magic numbers for color or room relay commands
 #define COLOR_CMD 0xC0
 #define ROOM_CMD 0xF0
 
+// Protocol version 2 (see src/protocol.rs), switched to when the server
+// sends a HELLO frame:
+//
+//   START | LEN | CMD | SEQ | PAYLOAD (LEN bytes) | CRC (2 bytes)
+//
+// The CRC is CRC-16/CCITT-FALSE, big-endian, over LEN through the payload.
+// Each frame is answered by an ACK frame with the same SEQ and the command
+// as its payload, or a NAK with an error code. Bytes that aren't a valid
+// frame are skipped by looking for the next START byte.
+//
+// HELLO is 9 bytes long, so the server can send it to version 1 firmware,
+// which answers "X\r\n".
+#define PROTOCOL_VERSION 2
+#define START_BYTE 0xA5
+#define MAX_PAYLOAD 16
+#define OVERHEAD 6
+#define MAX_FRAME (MAX_PAYLOAD + OVERHEAD)
+// Forget half a frame if the rest hasn't arrived in this long
+#define FRAME_TIMEOUT_MS 50
+
+#define CMD_HELLO 0x48
+#define CMD_COLOR COLOR_CMD
+#define CMD_ROOMS ROOM_CMD
+// Fade from the current color: flags, 8 bytes of levels to end on, then the
+// duration in milliseconds (4 bytes). A color command stops the fade.
+#define CMD_FADE 0xFA
+#define CMD_ACK 0x06
+#define CMD_NAK 0x15
+
+#define NAK_CRC 0x01
+#define NAK_UNKNOWN 0x02
+#define NAK_LENGTH 0x03
+#define NAK_FULL 0x04
+
+// Fade flag to play after the fades already queued, instead of replacing them
+#define FADE_APPEND 0x01
+#define FADE_BYTES 13
+#define MAX_FADES 16
+
+// Bits for each room in a version 2 room command
+#define LIVING_ROOM_BIT 0x01
+#define OFFICE_BIT 0x02
+#define BEDROOM_BIT 0x04
+
 const int PINS[] = {RED, GREEN, BLUE, WHITE};
 const int ROOMS[] = {LIVING_ROOM, OFFICE, BEDROOM};
 
@@ -36,6 +86,23 @@ int bytesRead = 0;
 int roomState[] = {LOW, LOW, LOW};
 bool allOff = true;
 
+// Version 2 state
+int protocolVersion = 1;
+unsigned char frameBuf[MAX_FRAME];
+int frameLen = 0;
+unsigned long lastByteTime = 0;
+
+// Fades queued by CMD_FADE; fades[0] is playing
+struct Fade {
+  unsigned int levels[NUM_COLORS];
+  unsigned long duration;
+};
+Fade fades[MAX_FADES];
+int fadeCount = 0;
+unsigned int currentLevels[NUM_COLORS];
+unsigned int fadeFrom[NUM_COLORS];
+unsigned long fadeStart = 0;
+
 // 16 bit PWM: https://arduino.stackexchange.com/a/12719
 // With help from https://arduino.stackexchange.com/questions/4877/16-bit-pwm-on-a-mega
 // Using reference diagram https://www.arduino.cc/en/uploads/Hacking/PinMap2560big.png
@@ -112,24 +179,40 @@ void allRooms(int state) {
 
 }
 
-void colorCmd(unsigned char buf[BUFSIZE]) {
-  // Convert from bytes to shorts
-  int redValue = ((int) buf[1] << 8) | (int) buf[2];
-  int greenValue = ((int) buf[3] << 8) | (int) buf[4];
-  int blueValue = ((int) buf[5] << 8) | (int) buf[6];
-  int whiteValue = ((int) buf[7] << 8) | (int) buf[8];
-  
+// Show the R, G, B, and W levels
+void applyLevels(const unsigned int levels[NUM_COLORS]) {
+  memcpy(currentLevels, levels, sizeof(currentLevels));
+
   // If it's completely black, turn off the relays, independently of what their
   // state is from the room commands
-  if (redValue == 0 && greenValue == 0 && blueValue == 0 && whiteValue == 0) {
+  if (levels[0] == 0 && levels[1] == 0 && levels[2] == 0 && levels[3] == 0) {
     allRooms(LOW);
     allOff = true;
   } else {
     restoreRelayState();
     allOff = false;
   }
-  
-  setRGBW(redValue, greenValue, blueValue, whiteValue);
+
+  setRGBW(levels[0], levels[1], levels[2], levels[3]);
+}
+
+// Convert from 8 bytes to shorts, 2 for each color channel
+void readLevels(const unsigned char *bytes, unsigned int levels[NUM_COLORS]) {
+  for (int i = 0; i < NUM_COLORS; i++) {
+    levels[i] = ((unsigned int) bytes[2 * i] << 8) | (unsigned int) bytes[2 * i + 1];
+  }
+}
+
+// Set the color from 8 bytes of levels, stopping any fade
+void setColor(const unsigned char *bytes) {
+  unsigned int levels[NUM_COLORS];
+  readLevels(bytes, levels);
+  fadeCount = 0;
+  applyLevels(levels);
+}
+
+void colorCmd(unsigned char buf[BUFSIZE]) {
+  setColor(buf + 1);
 }
 
 void clearRoomState() {
@@ -169,30 +252,180 @@ void roomCmd(unsigned char buf[BUFSIZE]) {
   }
 }
 
-void setup() {
-  Serial.begin(9600);
+// Version 2 room command, with a bit for each room
+void setRooms(unsigned char bits) {
+  roomState[0] = (bits & LIVING_ROOM_BIT) ? HIGH : LOW;
+  roomState[1] = (bits & OFFICE_BIT) ? HIGH : LOW;
+  roomState[2] = (bits & BEDROOM_BIT) ? HIGH : LOW;
 
-  // Set the LEDs to be output pins
-  for (int i = 0; i < NUM_COLORS; i++) {
-    pinMode(PINS[i], OUTPUT);
+  if (!allOff) {
+    restoreRelayState();
   }
-  memset(buf, BUFSIZE*sizeof(unsigned char), 0);
+}
 
-  setupPWM16();
+// CRC-16/CCITT-FALSE
+uint16_t crc16(const unsigned char *data, int len) {
+  uint16_t crc = 0xFFFF;
+  for (int i = 0; i < len; i++) {
+    crc ^= (uint16_t) data[i] << 8;
+    for (int bit = 0; bit < 8; bit++) {
+      crc = (crc & 0x8000) ? (crc << 1) ^ 0x1021 : crc << 1;
+    }
+  }
+  return crc;
+}
 
-  // Set output pins for relays
-  pinMode(LIVING_ROOM, OUTPUT);
-  pinMode(OFFICE, OUTPUT);
-  pinMode(BEDROOM, OUTPUT);
+void sendFrame(unsigned char cmd, unsigned char seq, const unsigned char *payload, int len) {
+  unsigned char frame[MAX_FRAME];
+  frame[0] = START_BYTE;
+  frame[1] = len;
+  frame[2] = cmd;
+  frame[3] = seq;
+  memcpy(frame + 4, payload, len);
+  uint16_t crc = crc16(frame + 1, len + 3);
+  frame[len + 4] = crc >> 8;
+  frame[len + 5] = crc & 0xFF;
+  Serial.write(frame, len + OVERHEAD);
+}
 
-  allRooms(LOW);
-  allOff = true;
-  clearRoomState();
+void sendAck(unsigned char seq, unsigned char cmd) {
+  sendFrame(CMD_ACK, seq, &cmd, 1);
+}
 
-  Serial.println("I"); // Successfully initialized
+void sendNak(unsigned char seq, unsigned char code) {
+  sendFrame(CMD_NAK, seq, &code, 1);
 }
 
-void loop() {
+// Queue a fade, returning false if there's no room for it
+bool fadeCmd(const unsigned char *payload) {
+  if (!(payload[0] & FADE_APPEND)) {
+    fadeCount = 0;
+  }
+  if (fadeCount == MAX_FADES) {
+    return false;
+  }
+
+  Fade *fade = &fades[fadeCount];
+  readLevels(payload + 1, fade->levels);
+  fade->duration = ((unsigned long) payload[9] << 24)
+    | ((unsigned long) payload[10] << 16)
+    | ((unsigned long) payload[11] << 8)
+    | (unsigned long) payload[12];
+
+  // Nothing playing, so start from the current color now
+  if (fadeCount == 0) {
+    memcpy(fadeFrom, currentLevels, sizeof(fadeFrom));
+    fadeStart = millis();
+  }
+  fadeCount++;
+  return true;
+}
+
+// Move the fade on to where it should be by now
+void updateFade() {
+  while (fadeCount > 0) {
+    unsigned long elapsed = millis() - fadeStart;
+    Fade *fade = &fades[0];
+    if (elapsed < fade->duration) {
+      float t = (float) elapsed / fade->duration;
+      unsigned int levels[NUM_COLORS];
+      for (int i = 0; i < NUM_COLORS; i++) {
+        levels[i] = fadeFrom[i] + ((float) fade->levels[i] - fadeFrom[i]) * t + 0.5;
+      }
+      applyLevels(levels);
+      return;
+    }
+
+    // Land on the color exactly, and start the next fade from when this one
+    // should have ended
+    applyLevels(fade->levels);
+    memcpy(fadeFrom, fade->levels, sizeof(fadeFrom));
+    fadeStart += fade->duration;
+    fadeCount--;
+    memmove(fades, fades + 1, fadeCount * sizeof(Fade));
+  }
+}
+
+// Is this a whole HELLO frame, asking for version 2?
+bool isHello(const unsigned char *frame, int len) {
+  if (len != 3 + OVERHEAD || frame[0] != START_BYTE || frame[1] != 3 || frame[2] != CMD_HELLO) {
+    return false;
+  }
+  uint16_t crc = ((uint16_t) frame[len - 2] << 8) | frame[len - 1];
+  return crc16(frame + 1, len - 3) == crc
+    && frame[4] == 'L' && frame[5] == 'F' && frame[6] >= 2;
+}
+
+void helloAck(unsigned char seq) {
+  unsigned char payload[] = {CMD_HELLO, PROTOCOL_VERSION};
+  sendFrame(CMD_ACK, seq, payload, 2);
+}
+
+void handleFrame(unsigned char cmd, unsigned char seq, const unsigned char *payload, int len) {
+  if (cmd == CMD_COLOR && len == 8) {
+    setColor(payload);
+    sendAck(seq, cmd);
+  } else if (cmd == CMD_ROOMS && len == 1) {
+    setRooms(payload[0]);
+    sendAck(seq, cmd);
+  } else if (cmd == CMD_FADE && len == FADE_BYTES) {
+    if (fadeCmd(payload)) {
+      sendAck(seq, cmd);
+    } else {
+      sendNak(seq, NAK_FULL);
+    }
+  } else if (cmd == CMD_HELLO && len == 3) {
+    helloAck(seq);
+  } else if (cmd == CMD_COLOR || cmd == CMD_ROOMS || cmd == CMD_HELLO || cmd == CMD_FADE) {
+    sendNak(seq, NAK_LENGTH);
+  } else {
+    sendNak(seq, NAK_UNKNOWN);
+  }
+}
+
+// Forget the first `n` bytes of frameBuf
+void dropBytes(int n) {
+  memmove(frameBuf, frameBuf + n, frameLen - n);
+  frameLen -= n;
+}
+
+// Handle every whole frame in frameBuf
+void processFrames() {
+  while (true) {
+    // Drop anything before the next START byte
+    int start = 0;
+    while (start < frameLen && frameBuf[start] != START_BYTE) {
+      start++;
+    }
+    dropBytes(start);
+    if (frameLen < 2) {
+      return;
+    }
+
+    int len = frameBuf[1];
+    if (len > MAX_PAYLOAD) {
+      // Not really a START byte, look for the next one
+      dropBytes(1);
+      continue;
+    }
+    int total = len + OVERHEAD;
+    if (frameLen < total) {
+      return;
+    }
+
+    uint16_t crc = ((uint16_t) frameBuf[total - 2] << 8) | frameBuf[total - 1];
+    if (crc16(frameBuf + 1, total - 3) != crc) {
+      sendNak(frameBuf[3], NAK_CRC);
+      dropBytes(1);
+      continue;
+    }
+
+    handleFrame(frameBuf[2], frameBuf[3], frameBuf + 4, len);
+    dropBytes(total);
+  }
+}
+
+void loopV1() {
   if (Serial.available() >= BUFSIZE*sizeof(unsigned char)) {
     bytesRead = Serial.readBytes(buf, BUFSIZE);
     if (bytesRead == BUFSIZE) {
@@ -202,12 +435,67 @@ void loop() {
       } else if (buf[0] == COLOR_CMD) {
         colorCmd(buf);
         Serial.println("C"); // Successfully changed color
+      } else if (isHello(buf, BUFSIZE)) {
+        protocolVersion = PROTOCOL_VERSION;
+        frameLen = 0;
+        helloAck(buf[3]);
       } else {
         Serial.println("X"); // Invalid command
       }
 
       bytesRead = 0;
-      memset(buf, BUFSIZE*sizeof(unsigned char), 0);
+      memset(buf, 0, BUFSIZE*sizeof(unsigned char));
+    }
+  }
+}
+
+void loopV2() {
+  if (frameLen > 0 && millis() - lastByteTime > FRAME_TIMEOUT_MS) {
+    // The rest of the frame got lost
+    frameLen = 0;
+  }
+
+  while (Serial.available() > 0) {
+    if (frameLen == MAX_FRAME) {
+      dropBytes(1);
     }
+    frameBuf[frameLen++] = Serial.read();
+    lastByteTime = millis();
+    processFrames();
+  }
+}
+
+void setup() {
+  Serial.begin(BAUD_RATE);
+
+  // Set the LEDs to be output pins
+  for (int i = 0; i < NUM_COLORS; i++) {
+    pinMode(PINS[i], OUTPUT);
+  }
+  memset(buf, 0, BUFSIZE*sizeof(unsigned char));
+
+  setupPWM16();
+
+  // Set output pins for relays
+  pinMode(LIVING_ROOM, OUTPUT);
+  pinMode(OFFICE, OUTPUT);
+  pinMode(BEDROOM, OUTPUT);
+
+  allRooms(LOW);
+  allOff = true;
+  clearRoomState();
+
+  Serial.println("I"); // Successfully initialized
+}
+
+void loop() {
+  if (fadeCount > 0) {
+    updateFade();
+  }
+
+  if (protocolVersion >= 2) {
+    loopV2();
+  } else {
+    loopV1();
   }
 }
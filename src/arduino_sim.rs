@@ -0,0 +1,353 @@
+//! Simulated Arduino running `serial-led-arduino.ino`, for testing the serial
+//! protocol without hardware
+
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::protocol::{
+    self, Frame, FrameDecoder, FrameError, CMD_ACK, CMD_COLOR, CMD_FADE,
+    CMD_HELLO, CMD_NAK, CMD_ROOMS, COLOR_CMD, FADE_APPEND, HELLO_MAGIC,
+    MAX_FADES, NAK_CRC, NAK_FULL, NAK_LENGTH, NAK_UNKNOWN, ROOM_CMD,
+    UPDATE_BYTES,
+};
+use crate::rooms::Rooms;
+
+/// The firmware's state, fed the bytes the server writes
+#[derive(Debug, Clone)]
+pub struct ArduinoSim {
+    /// Highest protocol version the firmware speaks
+    max_version: u8,
+    /// Version it's speaking now
+    pub version: u8,
+    v1_buf: Vec<u8>,
+    decoder: FrameDecoder,
+    replies: VecDeque<u8>,
+    /// PWM levels of the R, G, B and W pins
+    pub levels: [u16; 4],
+    /// Rooms from the last room command
+    pub rooms: Rooms,
+    /// Rooms with their relays switched on, which are all off while the
+    /// color is black
+    pub relays: Rooms,
+    /// Queued fades: the levels to end on, and how many milliseconds to
+    /// take. The first one is playing.
+    fades: VecDeque<([u16; 4], u32)>,
+    /// How many fades can be queued, `MAX_FADES` like the firmware
+    pub max_fades: usize,
+    fade_from: [u16; 4],
+    fade_start: u64,
+    /// The firmware's clock, in milliseconds, moved on by `advance`
+    now: u64,
+}
+
+impl ArduinoSim {
+    /// Power on firmware that speaks up to `max_version` (1 or 2)
+    pub fn new(max_version: u8) -> Self {
+        Self {
+            max_version,
+            version: 1,
+            v1_buf: Vec::with_capacity(UPDATE_BYTES),
+            decoder: FrameDecoder::new(),
+            // Sent at the end of setup()
+            replies: VecDeque::from(b"I\r\n".to_vec()),
+            levels: [0; 4],
+            rooms: Rooms::default(),
+            relays: Rooms::default(),
+            fades: VecDeque::new(),
+            max_fades: MAX_FADES,
+            fade_from: [0; 4],
+            fade_start: 0,
+            now: 0,
+        }
+    }
+
+    /// Wrap up as a serial port for `SerialManager`, keeping a handle to
+    /// look at the firmware's state
+    pub fn into_port(self) -> SimPort {
+        SimPort(Arc::new(Mutex::new(self)))
+    }
+
+    /// Let `ms` milliseconds go by, playing any fades
+    pub fn advance(&mut self, ms: u32) {
+        self.now += u64::from(ms);
+        self.update_fade();
+    }
+
+    /// Bytes from the server
+    pub fn receive(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            if self.version == 1 {
+                self.v1_buf.push(byte);
+                if self.v1_buf.len() == UPDATE_BYTES {
+                    let buf = std::mem::take(&mut self.v1_buf);
+                    self.v1_command(&buf);
+                }
+            } else {
+                self.decoder.extend(&[byte]);
+                while let Some(decoded) = self.decoder.next_frame() {
+                    self.v2_frame(decoded);
+                }
+            }
+        }
+    }
+
+    fn v1_command(&mut self, buf: &[u8]) {
+        let reply: &[u8] = match buf[0] {
+            ROOM_CMD => {
+                self.set_rooms(Rooms {
+                    living_room: buf[1..].contains(&protocol::LIVING_ROOM),
+                    office: buf[1..].contains(&protocol::OFFICE),
+                    bedroom: buf[1..].contains(&protocol::BEDROOM),
+                });
+                b"R\r\n"
+            }
+            COLOR_CMD => {
+                let payload: [u8; 8] = buf[1..].try_into().unwrap_or([0; 8]);
+                self.fades.clear();
+                self.set_levels(protocol::bytes_to_levels(&payload));
+                b"C\r\n"
+            }
+            _ if self.max_version >= 2 => {
+                // Might be HELLO, asking to switch to version 2
+                let mut decoder = FrameDecoder::new();
+                decoder.extend(buf);
+                match decoder.next_frame() {
+                    Some(Ok(frame)) if is_hello(&frame) => {
+                        self.version = frame.payload[2].min(self.max_version);
+                        self.decoder.reset();
+                        self.reply(Frame::new(
+                            CMD_ACK,
+                            frame.seq,
+                            &[CMD_HELLO, self.version],
+                        ));
+                        return;
+                    }
+                    _ => b"X\r\n",
+                }
+            }
+            _ => b"X\r\n",
+        };
+        self.replies.extend(reply);
+    }
+
+    fn v2_frame(&mut self, decoded: Result<Frame, FrameError>) {
+        let frame = match decoded {
+            Ok(frame) => frame,
+            Err(FrameError::Crc { seq }) => {
+                self.reply(Frame::new(CMD_NAK, seq, &[NAK_CRC]));
+                return;
+            }
+        };
+
+        let applied = match (frame.command, frame.payload.len()) {
+            (CMD_COLOR, 8) => {
+                let payload: [u8; 8] = frame.payload[..].try_into().unwrap();
+                self.fades.clear();
+                self.set_levels(protocol::bytes_to_levels(&payload));
+                Ok(vec![CMD_COLOR])
+            }
+            (CMD_FADE, 13) => self.queue_fade(&frame.payload),
+            (CMD_ROOMS, 1) => {
+                self.set_rooms(protocol::bits_to_rooms(frame.payload[0]));
+                Ok(vec![CMD_ROOMS])
+            }
+            (CMD_HELLO, _) if is_hello(&frame) => {
+                Ok(vec![CMD_HELLO, self.version])
+            }
+            (CMD_COLOR | CMD_ROOMS | CMD_HELLO | CMD_FADE, _) => {
+                Err(NAK_LENGTH)
+            }
+            _ => Err(NAK_UNKNOWN),
+        };
+        match applied {
+            Ok(payload) => self.reply(Frame::new(CMD_ACK, frame.seq, &payload)),
+            Err(code) => self.reply(Frame::new(CMD_NAK, frame.seq, &[code])),
+        }
+    }
+
+    /// Like fadeCmd(): the fade starts from the current levels, unless it's
+    /// appended after others
+    fn queue_fade(&mut self, payload: &[u8]) -> Result<Vec<u8>, u8> {
+        if payload[0] & FADE_APPEND == 0 {
+            self.fades.clear();
+        }
+        if self.fades.len() >= self.max_fades {
+            return Err(NAK_FULL);
+        }
+        let levels: [u8; 8] = payload[1..9].try_into().unwrap();
+        let duration = u32::from_be_bytes(payload[9..13].try_into().unwrap());
+        if self.fades.is_empty() {
+            self.fade_from = self.levels;
+            self.fade_start = self.now;
+        }
+        self.fades
+            .push_back((protocol::bytes_to_levels(&levels), duration));
+        Ok(vec![CMD_FADE])
+    }
+
+    /// Like updateFade(), which the firmware runs on every loop
+    fn update_fade(&mut self) {
+        while let Some(&(to, duration)) = self.fades.front() {
+            let elapsed = self.now - self.fade_start;
+            if elapsed < u64::from(duration) {
+                let t = elapsed as f32 / duration as f32;
+                let from = self.fade_from.map(f32::from);
+                let levels = [0, 1, 2, 3].map(|i| {
+                    (from[i] + (f32::from(to[i]) - from[i]) * t + 0.5) as u16
+                });
+                self.set_levels(levels);
+                return;
+            }
+            // Land on the color exactly, and start the next fade from when
+            // this one should have ended
+            self.set_levels(to);
+            self.fade_from = to;
+            self.fade_start += u64::from(duration);
+            self.fades.pop_front();
+        }
+    }
+
+    /// Like colorCmd(): black also switches off all the relays
+    fn set_levels(&mut self, levels: [u16; 4]) {
+        self.levels = levels;
+        self.relays = if levels == [0; 4] {
+            Rooms::default()
+        } else {
+            self.rooms.clone()
+        };
+    }
+
+    /// Like roomCmd(): the relays only follow while the color isn't black
+    fn set_rooms(&mut self, rooms: Rooms) {
+        self.rooms = rooms;
+        if self.levels != [0; 4] {
+            self.relays = self.rooms.clone();
+        }
+    }
+
+    fn reply(&mut self, frame: Frame) {
+        self.replies.extend(frame.encode());
+    }
+}
+
+fn is_hello(frame: &Frame) -> bool {
+    frame.command == CMD_HELLO
+        && frame.payload.len() == 3
+        && frame.payload[..2] == HELLO_MAGIC
+}
+
+/// `ArduinoSim` as a serial port. Reads time out like a real port once
+/// there are no replies left.
+#[derive(Debug, Clone)]
+pub struct SimPort(Arc<Mutex<ArduinoSim>>);
+
+impl SimPort {
+    pub fn sim(&self) -> MutexGuard<'_, ArduinoSim> {
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Read for SimPort {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut sim = self.sim();
+        if sim.replies.is_empty() && !buf.is_empty() {
+            return Err(Error::new(ErrorKind::TimedOut, "No reply"));
+        }
+        let n = buf.len().min(sim.replies.len());
+        for (byte, reply) in buf.iter_mut().zip(sim.replies.drain(..n)) {
+            *byte = reply;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for SimPort {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.sim().receive(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_v1() {
+        let mut sim = ArduinoSim::new(1);
+        sim.receive(&protocol::rooms_to_bytes(&Rooms {
+            living_room: true,
+            office: false,
+            bedroom: false,
+        }));
+        sim.receive(&protocol::color_to_bytes(&[1, 2, 3, 4]));
+        sim.receive(&Frame::hello(0).encode());
+        assert_eq!(sim.version, 1);
+        assert_eq!(sim.levels, [1, 2, 3, 4]);
+        assert!(sim.relays.living_room && !sim.relays.office);
+        let replies: Vec<u8> = sim.replies.drain(..).collect();
+        assert_eq!(replies, b"I\r\nR\r\nC\r\nX\r\n");
+    }
+
+    #[test]
+    fn test_v2() {
+        let mut sim = ArduinoSim::new(2);
+        sim.replies.clear();
+        sim.receive(&Frame::hello(1).encode());
+        assert_eq!(sim.version, 2);
+
+        // A frame cut short by a lost byte only loses that frame
+        let mut cut = Frame::color(2, &[9, 9, 9, 9]).encode();
+        cut.remove(5);
+        sim.receive(&cut);
+        sim.receive(&Frame::color(3, &[5, 6, 7, 8]).encode());
+        sim.receive(&Frame::new(0x77, 4, &[]).encode());
+        assert_eq!(sim.levels, [5, 6, 7, 8]);
+
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&sim.replies.drain(..).collect::<Vec<_>>());
+        let replies: Vec<_> = std::iter::from_fn(|| decoder.next_frame())
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            replies,
+            vec![
+                Frame::new(CMD_ACK, 1, &[CMD_HELLO, 2]),
+                Frame::new(CMD_NAK, 2, &[NAK_CRC]),
+                Frame::new(CMD_ACK, 3, &[CMD_COLOR]),
+                Frame::new(CMD_NAK, 4, &[NAK_UNKNOWN]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fade() {
+        let mut sim = ArduinoSim::new(2);
+        sim.receive(&Frame::hello(1).encode());
+        sim.receive(&Frame::color(2, &[1000, 0, 0, 0]).encode());
+        sim.receive(&Frame::fade(3, &[3000, 0, 0, 0], 100, false).encode());
+        sim.receive(&Frame::fade(4, &[0, 0, 0, 0], 50, true).encode());
+
+        sim.advance(50);
+        assert_eq!(sim.levels, [2000, 0, 0, 0]);
+        sim.advance(75);
+        assert_eq!(sim.levels, [1500, 0, 0, 0]);
+        sim.advance(25);
+        assert_eq!(sim.levels, [0; 4]);
+        assert!(sim.fades.is_empty());
+
+        // A color stops the fade
+        sim.receive(&Frame::fade(5, &[3000, 0, 0, 0], 100, false).encode());
+        sim.advance(50);
+        sim.receive(&Frame::color(6, &[7, 7, 7, 7]).encode());
+        sim.advance(50);
+        assert_eq!(sim.levels, [7, 7, 7, 7]);
+    }
+}
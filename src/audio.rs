@@ -0,0 +1,590 @@
+//! Audio-reactive colors, from a WAV file or raw PCM on stdin
+//!
+//! Each frame's worth of audio is analyzed for its loudness and the energy in
+//! the bass, mid and treble bands, which are mixed into RGBW by an
+//! `AudioMapping`. To react to a microphone or line in, pipe ALSA capture
+//! into the server, e.g.
+//!
+//! ```text
+//! arecord -f S16_LE -r 44100 -c 1 | led-foot
+//! ```
+
+use std::io::{Error, ErrorKind, Read};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::color::Color;
+use crate::led_sequence::{plain_file, sequence_dir, RESOLUTION};
+use crate::sequence_source::SequenceSource;
+
+/// Name of the input that reads raw PCM from stdin
+pub const STDIN_INPUT: &str = "-";
+
+/// Frequency bands, in Hz
+const BASS_BAND: (f32, f32) = (20.0, 250.0);
+const MID_BAND: (f32, f32) = (250.0, 4000.0);
+const TREBLE_BAND: (f32, f32) = (4000.0, 16000.0);
+
+/// Longest WAV `fmt ` chunk, for `WAVE_FORMAT_EXTENSIBLE`
+const MAX_FMT_CHUNK_LEN: usize = 40;
+
+/// Where the audio comes from and how it turns into colors
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AudioParams {
+    /// WAV file name in `sequence_dir()`, or `-` for raw signed 16-bit
+    /// little-endian PCM on stdin
+    pub input: String,
+    /// Sample rate of raw PCM (WAV files have their own)
+    pub sample_rate: u32,
+    /// Number of channels in raw PCM (WAV files have their own)
+    pub channels: u16,
+    /// Multiplier on all features before mapping them to colors
+    pub gain: f32,
+    /// How much of the previous frame's value is kept when a feature drops
+    /// (0.0 to 1.0), so the lights decay instead of flickering
+    pub decay: f32,
+    pub mapping: AudioMapping,
+}
+
+impl Default for AudioParams {
+    fn default() -> Self {
+        Self {
+            input: STDIN_INPUT.to_string(),
+            sample_rate: 44100,
+            channels: 1,
+            gain: 1.0,
+            decay: 0.5,
+            mapping: AudioMapping::default(),
+        }
+    }
+}
+
+/// How much of each audio feature goes into a color channel
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FeatureWeights {
+    pub loudness: f32,
+    pub bass: f32,
+    pub mid: f32,
+    pub treble: f32,
+}
+
+/// Weights of the audio features for each color channel
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AudioMapping {
+    pub r: FeatureWeights,
+    pub g: FeatureWeights,
+    pub b: FeatureWeights,
+    pub w: FeatureWeights,
+}
+
+impl Default for AudioMapping {
+    /// Bass in red, mids in green, treble in blue
+    fn default() -> Self {
+        Self {
+            r: FeatureWeights {
+                bass: 1.0,
+                ..Default::default()
+            },
+            g: FeatureWeights {
+                mid: 1.0,
+                ..Default::default()
+            },
+            b: FeatureWeights {
+                treble: 1.0,
+                ..Default::default()
+            },
+            w: FeatureWeights::default(),
+        }
+    }
+}
+
+/// What's going on in a frame's worth of audio, each 0.0 to 1.0 for a
+/// full-scale signal
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AudioFeatures {
+    pub loudness: f32,
+    pub bass: f32,
+    pub mid: f32,
+    pub treble: f32,
+}
+
+impl AudioMapping {
+    pub fn color(&self, features: &AudioFeatures) -> Color {
+        let mix = |weights: &FeatureWeights| {
+            weights.loudness * features.loudness
+                + weights.bass * features.bass
+                + weights.mid * features.mid
+                + weights.treble * features.treble
+        };
+        Color::new(mix(&self.r), mix(&self.g), mix(&self.b), mix(&self.w))
+            .clamped()
+    }
+}
+
+/// Sample rate and channel layout of PCM audio
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AudioFormat {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub bits_per_sample: u16,
+}
+
+/// Reads PCM audio a frame at a time, mixed down to mono
+pub struct PcmReader<R: Read> {
+    reader: R,
+    pub format: AudioFormat,
+}
+
+impl<R: Read> PcmReader<R> {
+    /// Raw signed 16-bit little-endian PCM
+    pub fn raw(reader: R, sample_rate: u32, channels: u16) -> Self {
+        Self {
+            reader,
+            format: AudioFormat {
+                sample_rate,
+                channels: channels.max(1),
+                bits_per_sample: 16,
+            },
+        }
+    }
+
+    /// WAV file with 8- or 16-bit integer PCM; reads up to the start of the
+    /// audio data
+    pub fn wav(mut reader: R) -> Result<Self, Error> {
+        let mut header = [0; 12];
+        reader.read_exact(&mut header)?;
+        if &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+            return Err(Error::new(ErrorKind::InvalidData, "Not a WAV file"));
+        }
+
+        let mut format = None;
+        loop {
+            let mut chunk_header = [0; 8];
+            reader.read_exact(&mut chunk_header)?;
+            let chunk_len = u32::from_le_bytes([
+                chunk_header[4],
+                chunk_header[5],
+                chunk_header[6],
+                chunk_header[7],
+            ]) as usize;
+
+            match &chunk_header[0..4] {
+                b"fmt " => {
+                    // The length comes from the file, so check it before
+                    // allocating anything
+                    if chunk_len > MAX_FMT_CHUNK_LEN {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            "WAV format chunk is too long",
+                        ));
+                    }
+                    let mut fmt = vec![0; chunk_len + chunk_len % 2];
+                    reader.read_exact(&mut fmt)?;
+                    if fmt.len() < 16 {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            "WAV format chunk is too short",
+                        ));
+                    }
+                    let audio_format = u16::from_le_bytes([fmt[0], fmt[1]]);
+                    let bits_per_sample =
+                        u16::from_le_bytes([fmt[14], fmt[15]]);
+                    if audio_format != 1
+                        || !(bits_per_sample == 8 || bits_per_sample == 16)
+                    {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            "Only 8- and 16-bit integer PCM WAV files are supported",
+                        ));
+                    }
+                    format = Some(AudioFormat {
+                        channels: u16::from_le_bytes([fmt[2], fmt[3]]).max(1),
+                        sample_rate: u32::from_le_bytes([
+                            fmt[4], fmt[5], fmt[6], fmt[7],
+                        ]),
+                        bits_per_sample,
+                    });
+                }
+                b"data" => {
+                    return format
+                        .map(|format| Self { reader, format })
+                        .ok_or_else(|| {
+                            Error::new(
+                                ErrorKind::InvalidData,
+                                "WAV data came before its format",
+                            )
+                        });
+                }
+                _ => {
+                    // Skip any other chunks (padded to an even length)
+                    let skip = (chunk_len + chunk_len % 2) as u64;
+                    std::io::copy(
+                        &mut (&mut reader).take(skip),
+                        &mut std::io::sink(),
+                    )?;
+                }
+            }
+        }
+    }
+
+    /// Number of (mono) samples in one LED frame
+    pub fn samples_per_frame(&self) -> usize {
+        ((self.format.sample_rate as f32 / RESOLUTION) as usize).max(1)
+    }
+
+    /// Read the next frame's worth of samples (-1.0 to 1.0), or `None` at the
+    /// end of the audio. The last frame may be short.
+    pub fn read_frame(&mut self) -> Result<Option<Vec<f32>>, Error> {
+        let bytes_per_sample = (self.format.bits_per_sample / 8) as usize;
+        let channels = self.format.channels as usize;
+        let frame_bytes =
+            self.samples_per_frame() * channels * bytes_per_sample;
+
+        let mut buf = vec![0; frame_bytes];
+        let mut filled = 0;
+        while filled < frame_bytes {
+            match self.reader.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        let sample_bytes = channels * bytes_per_sample;
+        let whole_samples = filled / sample_bytes;
+        if whole_samples == 0 {
+            return Ok(None);
+        }
+
+        let samples = buf[..whole_samples * sample_bytes]
+            .chunks(sample_bytes)
+            .map(|sample| {
+                let sum: f32 = sample
+                    .chunks(bytes_per_sample)
+                    .map(|channel| match channel {
+                        [byte] => (*byte as f32 - 128.0) / 128.0,
+                        [low, high] => {
+                            i16::from_le_bytes([*low, *high]) as f32 / 32768.0
+                        }
+                        _ => 0.0,
+                    })
+                    .sum();
+                sum / channels as f32
+            })
+            .collect();
+
+        Ok(Some(samples))
+    }
+}
+
+/// Turns frames of audio into features
+pub struct AudioAnalyzer {
+    sample_rate: u32,
+    fft: Arc<dyn Fft<f32>>,
+    fft_size: usize,
+    gain: f32,
+    decay: f32,
+    previous: AudioFeatures,
+}
+
+impl AudioAnalyzer {
+    pub fn new(
+        format: &AudioFormat,
+        samples_per_frame: usize,
+        params: &AudioParams,
+    ) -> Self {
+        let fft_size = samples_per_frame.next_power_of_two();
+        Self {
+            sample_rate: format.sample_rate,
+            fft: FftPlanner::<f32>::new().plan_fft_forward(fft_size),
+            fft_size,
+            gain: params.gain,
+            decay: params.decay.clamp(0.0, 1.0),
+            previous: AudioFeatures::default(),
+        }
+    }
+
+    /// Analyze one frame of mono samples
+    pub fn analyze(&mut self, samples: &[f32]) -> AudioFeatures {
+        if samples.is_empty() {
+            return self.smooth(AudioFeatures::default());
+        }
+
+        // RMS, scaled so that a full-scale sine is 1.0
+        let mean_square =
+            samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32;
+        let loudness = (mean_square * 2.0).sqrt();
+
+        // Hann-windowed FFT, zero padded up to the FFT size
+        let window_len = samples.len() as f32;
+        let mut buffer: Vec<Complex<f32>> = samples
+            .iter()
+            .enumerate()
+            .map(|(i, s)| {
+                let hann = 0.5
+                    - 0.5
+                        * (2.0 * std::f32::consts::PI * i as f32 / window_len)
+                            .cos();
+                Complex::new(s * hann, 0.0)
+            })
+            .collect();
+        buffer.resize(self.fft_size, Complex::new(0.0, 0.0));
+        self.fft.process(&mut buffer);
+
+        // Peak magnitude in each band, scaled so that a full-scale sine is
+        // about 1.0 (the Hann window halves the amplitude)
+        let scale = 4.0 / window_len;
+        let bin_hz = self.sample_rate as f32 / self.fft_size as f32;
+        let band = |(low, high): (f32, f32)| {
+            buffer[..self.fft_size / 2]
+                .iter()
+                .enumerate()
+                .filter(|(bin, _)| {
+                    let hz = *bin as f32 * bin_hz;
+                    hz >= low && hz < high
+                })
+                .map(|(_, c)| c.norm() * scale)
+                .fold(0.0, f32::max)
+        };
+
+        let features = AudioFeatures {
+            loudness: (loudness * self.gain).min(1.0),
+            bass: (band(BASS_BAND) * self.gain).min(1.0),
+            mid: (band(MID_BAND) * self.gain).min(1.0),
+            treble: (band(TREBLE_BAND) * self.gain).min(1.0),
+        };
+        self.smooth(features)
+    }
+
+    /// Let features fall off gradually instead of dropping straight away
+    fn smooth(&mut self, features: AudioFeatures) -> AudioFeatures {
+        let decay = |new: f32, old: f32| {
+            if new < old {
+                old * self.decay + new * (1.0 - self.decay)
+            } else {
+                new
+            }
+        };
+        let smoothed = AudioFeatures {
+            loudness: decay(features.loudness, self.previous.loudness),
+            bass: decay(features.bass, self.previous.bass),
+            mid: decay(features.mid, self.previous.mid),
+            treble: decay(features.treble, self.previous.treble),
+        };
+        self.previous = smoothed.clone();
+        smoothed
+    }
+}
+
+/// Analyze a whole audio stream up front, one color per LED frame
+pub fn color_frames<R: Read>(
+    mut pcm: PcmReader<R>,
+    params: &AudioParams,
+) -> Result<Vec<Color>, Error> {
+    let mut analyzer =
+        AudioAnalyzer::new(&pcm.format, pcm.samples_per_frame(), params);
+    let mut colors = Vec::new();
+    while let Some(samples) = pcm.read_frame()? {
+        colors.push(params.mapping.color(&analyzer.analyze(&samples)));
+    }
+    Ok(colors)
+}
+
+/// Latest color from the audio, shared with the thread that analyzes it
+#[derive(Debug, Default)]
+struct AudioShared {
+    color: Mutex<Color>,
+}
+
+/// Sequence that shows whatever the audio is doing right now
+///
+/// Audio is read and analyzed on its own thread, which stops when the
+/// sequence is no longer playing (or the audio ends)
+#[derive(Debug)]
+pub struct AudioSource {
+    shared: Arc<AudioShared>,
+}
+
+impl AudioSource {
+    /// Open the input and start analyzing it
+    pub fn start(params: &AudioParams) -> Result<Self, Error> {
+        let shared = Arc::new(AudioShared::default());
+        let weak = Arc::downgrade(&shared);
+        let thread_params = params.clone();
+
+        if params.input == STDIN_INPUT {
+            let pcm = PcmReader::raw(
+                std::io::stdin(),
+                params.sample_rate,
+                params.channels,
+            );
+            std::thread::spawn(move || {
+                audio_worker(pcm, thread_params, weak, false)
+            });
+        } else {
+            let path = plain_file(&sequence_dir(), &params.input)?;
+            let pcm = PcmReader::wav(std::io::BufReader::new(
+                std::fs::File::open(path)?,
+            ))?;
+            // Files can be read faster than they play, so pace them
+            std::thread::spawn(move || {
+                audio_worker(pcm, thread_params, weak, true)
+            });
+        }
+
+        Ok(Self { shared })
+    }
+}
+
+impl SequenceSource for AudioSource {
+    fn color_at(&self, _t: f32) -> Color {
+        self.shared
+            .color
+            .lock()
+            .map(|c| c.clone())
+            .unwrap_or_default()
+    }
+
+    fn duration(&self) -> f32 {
+        // Colors don't depend on time, so just loop every second
+        1.0
+    }
+}
+
+fn audio_worker<R: Read>(
+    mut pcm: PcmReader<R>,
+    params: AudioParams,
+    shared: Weak<AudioShared>,
+    paced: bool,
+) {
+    let mut analyzer =
+        AudioAnalyzer::new(&pcm.format, pcm.samples_per_frame(), &params);
+    let frame_time = Duration::from_secs_f32(
+        pcm.samples_per_frame() as f32 / pcm.format.sample_rate.max(1) as f32,
+    );
+    let start = Instant::now();
+    let mut frame: u32 = 0;
+
+    debug!("Started audio analysis of {:?}", params.input);
+    loop {
+        let samples = match pcm.read_frame() {
+            Ok(Some(samples)) => samples,
+            Ok(None) => {
+                debug!("Audio input {:?} ended", params.input);
+                break;
+            }
+            Err(e) => {
+                error!("Unable to read audio input {:?}: {}", params.input, e);
+                break;
+            }
+        };
+
+        let color = params.mapping.color(&analyzer.analyze(&samples));
+        // Stop once nothing is listening anymore
+        let Some(shared) = shared.upgrade() else {
+            break;
+        };
+        if let Ok(mut shared_color) = shared.color.lock() {
+            *shared_color = color;
+        }
+        drop(shared);
+
+        frame += 1;
+        if paced {
+            if let Some(wait) =
+                (frame_time * frame).checked_sub(start.elapsed())
+            {
+                std::thread::sleep(wait);
+            }
+        }
+    }
+
+    if let Some(shared) = shared.upgrade() {
+        if let Ok(mut shared_color) = shared.color.lock() {
+            *shared_color = Color::default();
+        }
+    }
+    debug!("Stopped audio analysis of {:?}", params.input);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const SAMPLE_RATE: u32 = 8000;
+
+    /// 16-bit mono WAV file of the given samples
+    fn wav_bytes(samples: &[f32]) -> Vec<u8> {
+        let data_len = (samples.len() * 2) as u32;
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"RIFF");
+        bytes.extend_from_slice(&(36 + data_len).to_le_bytes());
+        bytes.extend_from_slice(b"WAVEfmt ");
+        bytes.extend_from_slice(&16u32.to_le_bytes());
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+        bytes.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes());
+        bytes.extend_from_slice(&2u16.to_le_bytes());
+        bytes.extend_from_slice(&16u16.to_le_bytes());
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&data_len.to_le_bytes());
+        for s in samples {
+            bytes.extend_from_slice(&((s * 32767.0) as i16).to_le_bytes());
+        }
+        bytes
+    }
+
+    fn sine(hz: f32, amplitude: f32, seconds: f32) -> Vec<f32> {
+        (0..(SAMPLE_RATE as f32 * seconds) as usize)
+            .map(|i| {
+                amplitude
+                    * (2.0 * std::f32::consts::PI * hz * i as f32
+                        / SAMPLE_RATE as f32)
+                        .sin()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_wav_frames() {
+        let mut samples = sine(100.0, 0.8, 1.0);
+        samples.extend(sine(2000.0, 0.8, 1.0));
+        samples.extend(vec![0.0; SAMPLE_RATE as usize]);
+
+        let pcm = PcmReader::wav(Cursor::new(wav_bytes(&samples))).unwrap();
+        assert_eq!(pcm.format.sample_rate, SAMPLE_RATE);
+        let samples_per_frame = pcm.samples_per_frame();
+        let frames = color_frames(pcm, &AudioParams::default()).unwrap();
+        assert_eq!(frames.len(), samples.len().div_ceil(samples_per_frame));
+
+        // Bass tone shows up as red
+        let bass = &frames[15];
+        assert!(bass.r > 0.6 && bass.g < 0.1 && bass.b < 0.1, "{:?}", bass);
+
+        // Mid tone shows up as green, once the red has decayed
+        let mid = &frames[45];
+        assert!(mid.g > 0.6 && mid.r < 0.1 && mid.b < 0.1, "{:?}", mid);
+
+        // Silence fades to black
+        let silence = frames.last().unwrap();
+        assert!(silence.r < 0.01 && silence.g < 0.01 && silence.b < 0.01);
+    }
+
+    #[test]
+    fn test_wav_fmt_too_long() {
+        let mut bytes = wav_bytes(&[0.0; 4]);
+        bytes[16..20].copy_from_slice(&u32::MAX.to_le_bytes());
+        let error = PcmReader::wav(Cursor::new(bytes)).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,473 @@
+//! Optional access control for the HTTP API: API tokens with read-only or
+//! control scopes, logins for the web UI, and trusted networks that don't
+//! need either. Set up in the `[auth]` section of `led_config.toml`.
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::http::header::{self, ContentType};
+use actix_web::http::Method;
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Serializer;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::led_state::led_config;
+
+/// Cookie that holds the web UI's session id
+const SESSION_COOKIE: &str = "led_foot_session";
+
+/// Logged in web UI sessions, by session id
+static SESSIONS: Mutex<BTreeMap<String, Session>> = Mutex::new(BTreeMap::new());
+
+/// What a token or login is allowed to do. Control includes read.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Only `GET` requests, e.g. for dashboards
+    Read,
+    /// Everything, including changing the LEDs
+    Control,
+}
+
+fn default_scope() -> Scope {
+    Scope::Control
+}
+
+/// Keep secrets out of `/api/diagnostics`
+fn redact<S: Serializer>(_: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str("<redacted>")
+}
+
+/// Token for scripts and integrations, sent as `Authorization: Bearer <token>`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ApiToken {
+    /// Who the token is for, for the logs
+    pub name: String,
+    #[serde(serialize_with = "redact")]
+    pub token: String,
+    #[serde(default = "default_scope")]
+    pub scope: Scope,
+}
+
+/// Name and password for logging in to the web UI
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserLogin {
+    pub name: String,
+    #[serde(serialize_with = "redact")]
+    pub password: String,
+    #[serde(default = "default_scope")]
+    pub scope: Scope,
+}
+
+/// IP network in CIDR notation, like `192.168.1.0/24`, or a single address
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix));
+                let mask = mask.unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix));
+                let mask = mask.unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl TryFrom<String> for IpNetwork {
+    type Error = String;
+
+    fn try_from(network: String) -> Result<Self, Self::Error> {
+        let (addr, prefix) = match network.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (network.as_str(), None),
+        };
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|e| format!("Invalid network {:?}: {}", network, e))?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max_prefix)
+                .ok_or_else(|| format!("Invalid prefix in {:?}", network))?,
+            None => max_prefix,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl From<IpNetwork> for String {
+    fn from(network: IpNetwork) -> String {
+        format!("{}/{}", network.addr, network.prefix)
+    }
+}
+
+/// The `[auth]` section of `led_config.toml`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    /// Without this, anyone who can reach the server can do anything
+    pub enabled: bool,
+    pub tokens: Vec<ApiToken>,
+    pub users: Vec<UserLogin>,
+    /// Requests from these networks have full control without logging in,
+    /// e.g. for WLED and Hue clients that can't send tokens
+    pub trusted_networks: Vec<IpNetwork>,
+    /// How long web UI logins last, in hours
+    pub session_hours: f32,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            tokens: Vec::new(),
+            users: Vec::new(),
+            trusted_networks: Vec::new(),
+            session_hours: 24.0,
+        }
+    }
+}
+
+impl AuthConfig {
+    fn session_duration(&self) -> Duration {
+        Duration::from_secs_f32(self.session_hours.max(0.0) * 3600.0)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Session {
+    name: String,
+    scope: Scope,
+    expires: Instant,
+}
+
+/// Compare secrets without leaking how much of them matched through timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Paths anyone can use: the web UI, logging in and out, liveness checks and
+/// the Hue bridge description for discovery
+fn is_public(path: &str) -> bool {
+    matches!(
+        path,
+        "/" | "/api/login" | "/api/logout" | "/api/health" | "/description.xml"
+    ) || path.starts_with("/static/")
+}
+
+/// Scope needed for a request, if any
+pub fn required_scope(req: &HttpRequest) -> Option<Scope> {
+    if is_public(req.path()) {
+        None
+    } else if req.method() == Method::GET || req.method() == Method::HEAD {
+        Some(Scope::Read)
+    } else {
+        Some(Scope::Control)
+    }
+}
+
+fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+fn session_scope(id: &str) -> Option<Scope> {
+    let sessions = SESSIONS.lock().ok()?;
+    sessions
+        .get(id)
+        .filter(|session| session.expires > Instant::now())
+        .map(|session| session.scope)
+}
+
+/// Scope that the request's network, token or session grants, if any
+fn granted_scope(config: &AuthConfig, req: &HttpRequest) -> Option<Scope> {
+    if let Some(peer) = req.peer_addr() {
+        let ip = peer.ip();
+        if config.trusted_networks.iter().any(|n| n.contains(&ip)) {
+            return Some(Scope::Control);
+        }
+    }
+
+    if let Some(token) = bearer_token(req) {
+        return config
+            .tokens
+            .iter()
+            .find(|t| constant_time_eq(t.token.as_bytes(), token.as_bytes()))
+            .map(|t| {
+                trace!("Request to {} with token {:?}", req.path(), t.name);
+                t.scope
+            });
+    }
+
+    req.cookie(SESSION_COOKIE)
+        .and_then(|cookie| session_scope(cookie.value()))
+}
+
+/// Why a request was turned away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Denied {
+    /// No token, login or trusted network
+    Unauthorized,
+    /// The token or login only has read access
+    Forbidden,
+}
+
+impl Denied {
+    /// Response to send instead of handling the request
+    pub fn response(self) -> HttpResponse {
+        match self {
+            Denied::Unauthorized => HttpResponse::Unauthorized()
+                .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+                .content_type(ContentType::plaintext())
+                .body("Log in or send an API token"),
+            Denied::Forbidden => HttpResponse::Forbidden()
+                .content_type(ContentType::plaintext())
+                .body("This token can't control the LEDs"),
+        }
+    }
+}
+
+/// Check a request against the config
+pub fn authorize(config: &AuthConfig, req: &HttpRequest) -> Result<(), Denied> {
+    if !config.enabled {
+        return Ok(());
+    }
+    let Some(required) = required_scope(req) else {
+        return Ok(());
+    };
+
+    match granted_scope(config, req) {
+        Some(scope) if scope >= required => Ok(()),
+        Some(_) => Err(Denied::Forbidden),
+        None => {
+            debug!(
+                "Unauthorized request to {} from {:?}",
+                req.path(),
+                req.peer_addr()
+            );
+            Err(Denied::Unauthorized)
+        }
+    }
+}
+
+/// Random id for a new session
+fn new_session_id() -> Result<String, getrandom::Error> {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Body of `/api/login`
+#[derive(Debug, Clone, Deserialize)]
+pub struct LoginRequest {
+    pub name: String,
+    pub password: String,
+}
+
+/// Log in to the web UI, setting the session cookie
+pub async fn login(
+    req: HttpRequest,
+    payload: web::Json<LoginRequest>,
+) -> HttpResponse {
+    let led_config = led_config();
+    let config = &led_config.auth;
+    let Some(user) = config.users.iter().find(|user| {
+        user.name == payload.name
+            && constant_time_eq(
+                user.password.as_bytes(),
+                payload.password.as_bytes(),
+            )
+    }) else {
+        warn!("Failed login for {:?}", payload.name);
+        return HttpResponse::Unauthorized()
+            .content_type(ContentType::plaintext())
+            .body("Wrong name or password");
+    };
+
+    let id = match new_session_id() {
+        Ok(id) => id,
+        Err(e) => {
+            error!("Error on /api/login: can't generate session id: {}", e);
+            return HttpResponse::InternalServerError().into();
+        }
+    };
+
+    if let Ok(mut sessions) = SESSIONS.lock() {
+        let now = Instant::now();
+        sessions.retain(|_, session| session.expires > now);
+        sessions.insert(
+            id.clone(),
+            Session {
+                name: user.name.clone(),
+                scope: user.scope,
+                expires: now + config.session_duration(),
+            },
+        );
+    } else {
+        error!("Error on /api/login: can't get lock on sessions");
+        return HttpResponse::InternalServerError().into();
+    }
+
+    info!("{:?} logged in", user.name);
+    let cookie = Cookie::build(SESSION_COOKIE, id)
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        // Don't send the cookie over plain HTTP once logged in over HTTPS
+        .secure(req.connection_info().scheme() == "https")
+        .max_age(actix_web::cookie::time::Duration::seconds_f32(
+            config.session_hours.max(0.0) * 3600.0,
+        ))
+        .finish();
+    HttpResponse::Ok()
+        .cookie(cookie)
+        .content_type(ContentType::plaintext())
+        .body(format!("Logged in as {}", user.name))
+}
+
+/// Log out of the web UI, ending the session
+pub async fn logout(req: HttpRequest) -> HttpResponse {
+    if let Some(cookie) = req.cookie(SESSION_COOKIE) {
+        if let Ok(mut sessions) = SESSIONS.lock() {
+            if let Some(session) = sessions.remove(cookie.value()) {
+                info!("{:?} logged out", session.name);
+            }
+        }
+    }
+
+    let mut cookie = Cookie::new(SESSION_COOKIE, "");
+    cookie.set_path("/");
+    cookie.make_removal();
+    HttpResponse::Ok()
+        .cookie(cookie)
+        .content_type(ContentType::plaintext())
+        .body("Logged out")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn config() -> AuthConfig {
+        AuthConfig {
+            enabled: true,
+            tokens: vec![
+                ApiToken {
+                    name: "dashboard".to_string(),
+                    token: "read-token".to_string(),
+                    scope: Scope::Read,
+                },
+                ApiToken {
+                    name: "home-assistant".to_string(),
+                    token: "control-token".to_string(),
+                    scope: Scope::Control,
+                },
+            ],
+            trusted_networks: vec!["192.168.1.0/24"
+                .to_string()
+                .try_into()
+                .unwrap()],
+            ..Default::default()
+        }
+    }
+
+    fn status(req: TestRequest) -> u16 {
+        let req = req
+            .peer_addr("10.0.0.5:1234".parse().unwrap())
+            .to_http_request();
+        match authorize(&config(), &req) {
+            Ok(()) => 200,
+            Err(denied) => denied.response().status().as_u16(),
+        }
+    }
+
+    #[test]
+    fn test_scopes() {
+        let bearer =
+            |token: &str| (header::AUTHORIZATION, format!("Bearer {}", token));
+
+        assert_eq!(status(TestRequest::get().uri("/api/get-color")), 401);
+        assert_eq!(status(TestRequest::get().uri("/")), 200);
+        assert_eq!(status(TestRequest::get().uri("/api/health")), 200);
+
+        let read = || bearer("read-token");
+        assert_eq!(
+            status(
+                TestRequest::get()
+                    .uri("/api/get-color")
+                    .insert_header(read())
+            ),
+            200
+        );
+        assert_eq!(
+            status(
+                TestRequest::post()
+                    .uri("/api/set-color")
+                    .insert_header(read())
+            ),
+            403
+        );
+
+        let control = || bearer("control-token");
+        assert_eq!(
+            status(
+                TestRequest::post()
+                    .uri("/api/set-color")
+                    .insert_header(control())
+            ),
+            200
+        );
+        assert_eq!(
+            status(
+                TestRequest::post()
+                    .uri("/api/set-color")
+                    .insert_header(bearer("wrong"))
+            ),
+            401
+        );
+    }
+
+    #[test]
+    fn test_trusted_networks() {
+        let network: IpNetwork =
+            "192.168.1.0/24".to_string().try_into().unwrap();
+        assert!(network.contains(&"192.168.1.77".parse().unwrap()));
+        assert!(network.contains(&"::ffff:192.168.1.77".parse().unwrap()));
+        assert!(!network.contains(&"192.168.2.1".parse().unwrap()));
+
+        let network: IpNetwork = "fd00::/8".to_string().try_into().unwrap();
+        assert!(network.contains(&"fd12::1".parse().unwrap()));
+        assert!(IpNetwork::try_from("10.0.0.0/33".to_string()).is_err());
+
+        let req = TestRequest::post()
+            .uri("/api/set-color")
+            .peer_addr("192.168.1.20:5555".parse().unwrap())
+            .to_http_request();
+        assert!(authorize(&config(), &req).is_ok());
+    }
+}
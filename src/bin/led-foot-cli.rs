@@ -0,0 +1,588 @@
+//! Command-line client for the led-foot server, for scripting the lights
+//! instead of hand-writing `curl` commands
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::{Parser, Subcommand};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use led_foot::color::Color;
+use led_foot::led_sequence::{
+    LedSequence, LedSequenceInfo, LedSequenceType, RESOLUTION,
+};
+use led_foot::led_state::LedStatus;
+use led_foot::mock_output::ansi_color_bars;
+use led_foot::rooms::{Room, Rooms};
+use led_foot::scenes::Scenes;
+use led_foot::sequence_tools::{self, GRADIENT_HEIGHT, GRADIENT_WIDTH};
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "led-foot-cli",
+    version,
+    about = "Control the led-foot server from the command line"
+)]
+struct Cli {
+    /// URL of the led-foot server
+    #[arg(
+        long,
+        global = true,
+        env = "LED_FOOT_URL",
+        default_value = "http://localhost:5000"
+    )]
+    server: String,
+
+    /// API token, if the server has `[auth]` enabled
+    #[arg(long, global = true, env = "LED_FOOT_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    /// Print JSON instead of human-readable output
+    #[arg(long, global = true)]
+    json: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Show the color, rooms, sequence and outputs
+    Status,
+    /// Fade to an RGBW color, with components from 0 to 1
+    Color {
+        r: f32,
+        g: f32,
+        b: f32,
+        #[arg(default_value_t = 0.0)]
+        w: f32,
+        /// How long to fade for, e.g. `5s`, `500ms` or `2m`
+        #[arg(long, value_parser = parse_duration)]
+        transition: Option<Duration>,
+    },
+    /// Show the rooms, or turn them on (`+office`) and off (`-bedroom`)
+    Rooms {
+        #[arg(allow_hyphen_values = true)]
+        changes: Vec<String>,
+    },
+    /// Play and list sequences
+    #[command(subcommand)]
+    Seq(SeqCommand),
+    /// Apply and list the scenes from the server's config
+    #[command(subcommand)]
+    Scene(SceneCommand),
+    /// Print the status every time it changes, until interrupted
+    Watch {
+        /// How often to check for changes
+        #[arg(long, default_value = "500ms", value_parser = parse_duration)]
+        interval: Duration,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum SeqCommand {
+    /// Play a sequence, by its file name with or without the extension
+    Play { name: String },
+    /// List the sequences on the server
+    List,
+    /// Show a sequence file's duration and frame count (offline)
+    Info { file: PathBuf },
+    /// Render a sequence file to a png strip, in the layout gradients use
+    /// (offline)
+    Render {
+        file: PathBuf,
+        /// Defaults to `<name>-preview.png` in the current folder
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        #[arg(long, default_value_t = GRADIENT_WIDTH)]
+        width: u32,
+        #[arg(long, default_value_t = GRADIENT_HEIGHT)]
+        height: u32,
+    },
+    /// Print a sequence file to the terminal in truecolor (offline)
+    Preview {
+        file: PathBuf,
+        /// Columns to print the whole sequence across
+        #[arg(long, default_value_t = 80)]
+        width: usize,
+        /// Play it frame by frame in real time instead, like the mock output
+        #[arg(long)]
+        play: bool,
+    },
+    /// Convert a png gradient to json color points, or json back to a png
+    /// (offline)
+    Convert {
+        file: PathBuf,
+        /// Defaults to the name the server looks for, next to the input
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// How far the json's colors may stray from the png's (0 to 1)
+        #[arg(long, default_value_t = 0.01)]
+        tolerance: f32,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum SceneCommand {
+    /// Apply a scene by name
+    Apply { name: String },
+    /// List the scenes
+    List,
+}
+
+/// Parse durations like `5s`, `500ms`, `2m` or just `5` (seconds)
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let (number, scale) = if let Some(ms) = s.strip_suffix("ms") {
+        (ms, 0.001)
+    } else if let Some(secs) = s.strip_suffix('s') {
+        (secs, 1.0)
+    } else if let Some(mins) = s.strip_suffix('m') {
+        (mins, 60.0)
+    } else {
+        (s, 1.0)
+    };
+
+    let value = number
+        .trim()
+        .parse::<f64>()
+        .map_err(|e| format!("Invalid duration {:?}: {}", s, e))?;
+    Duration::try_from_secs_f64(value * scale)
+        .map_err(|e| format!("Invalid duration {:?}: {}", s, e))
+}
+
+/// Talks to the server's HTTP API
+struct Client {
+    server: String,
+    token: Option<String>,
+}
+
+impl Client {
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.server.trim_end_matches('/'), path)
+    }
+
+    /// Request with the token, if there is one
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        let request = ureq::request(method, &self.url(path));
+        match self.token {
+            Some(ref token) => {
+                request.set("Authorization", &format!("Bearer {}", token))
+            }
+            None => request,
+        }
+    }
+
+    fn get_text(&self, path: &str) -> Result<String, String> {
+        let response = self.request("GET", path).call();
+        Self::text(path, response)
+    }
+
+    fn get_json<T: DeserializeOwned>(&self, path: &str) -> Result<T, String> {
+        let text = self.get_text(path)?;
+        serde_json::from_str(&text)
+            .map_err(|e| format!("Invalid response from {}: {}", path, e))
+    }
+
+    fn post_text(&self, path: &str, body: &str) -> Result<String, String> {
+        let response = self.request("POST", path).send_string(body);
+        Self::text(path, response)
+    }
+
+    fn post_json<T: Serialize>(
+        &self,
+        path: &str,
+        body: &T,
+    ) -> Result<String, String> {
+        let response = self.request("POST", path).send_json(body);
+        Self::text(path, response)
+    }
+
+    fn text(
+        path: &str,
+        response: Result<ureq::Response, ureq::Error>,
+    ) -> Result<String, String> {
+        match response {
+            Ok(response) => response.into_string().map_err(|e| {
+                format!("Unable to read response from {}: {}", path, e)
+            }),
+            Err(ureq::Error::Status(code, response)) => Err(format!(
+                "{} failed ({}): {}",
+                path,
+                code,
+                response.into_string().unwrap_or_default()
+            )),
+            Err(e) => Err(format!("Unable to reach the server: {}", e)),
+        }
+    }
+}
+
+fn print_json<T: Serialize>(value: &T) {
+    match serde_json::to_string_pretty(value) {
+        Ok(json) => println!("{}", json),
+        Err(e) => eprintln!("Unable to print JSON: {}", e),
+    }
+}
+
+/// Print a message from the server, as `{"message": ...}` for JSON output
+fn print_message(cli: &Cli, message: &str) {
+    if cli.json {
+        print_json(&serde_json::json!({ "message": message }));
+    } else {
+        println!("{}", message);
+    }
+}
+
+fn format_color(color: &Color) -> String {
+    format!(
+        "r {:.2} g {:.2} b {:.2} w {:.2}",
+        color.r, color.g, color.b, color.w
+    )
+}
+
+fn format_rooms(rooms: &Rooms) -> String {
+    let on = [
+        (rooms.living_room, "living room"),
+        (rooms.office, "office"),
+        (rooms.bedroom, "bedroom"),
+    ]
+    .iter()
+    .filter(|(on, _)| *on)
+    .map(|(_, name)| *name)
+    .collect::<Vec<_>>();
+
+    if on.is_empty() {
+        "none".to_string()
+    } else {
+        on.join(", ")
+    }
+}
+
+fn print_status(status: &LedStatus) {
+    println!("{}", ansi_color_bars(&status.color, 40));
+    println!("Color:    {}", format_color(&status.color));
+    if status.future_color != status.color {
+        println!("Fading to {}", format_color(&status.future_color));
+    }
+    println!("Rooms:    {}", format_rooms(&status.rooms));
+    println!("Sequence: {}", status.sequence.as_deref().unwrap_or("none"));
+    if let Some(ref item) = status.playlist.current {
+        println!(
+            "Playlist: {} ({} queued)",
+            item.sequence,
+            status.playlist.queue.len()
+        );
+    }
+    if status.live {
+        println!("Live:     controlled over DMX");
+    }
+    for output in &status.outputs {
+        println!(
+            "Output:   {:?} {} - {}",
+            output.kind,
+            if output.connected {
+                "ok"
+            } else {
+                "disconnected"
+            },
+            output.detail
+        );
+    }
+    println!(
+        "Frames:   {} ({} skipped), jitter {:.1}ms mean / {:.1}ms max",
+        status.frames.frames,
+        status.frames.skipped,
+        status.frames.mean_jitter_ms,
+        status.frames.max_jitter_ms
+    );
+}
+
+/// Sequence name without the folder or extension
+fn sequence_stem(path: &str) -> String {
+    Path::new(path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.to_string())
+}
+
+fn list_sequences(client: &Client) -> Result<Vec<String>, String> {
+    Ok(client
+        .get_text("/api/list-sequences")?
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// What `seq info` reports about a sequence file
+#[derive(Debug, Serialize)]
+struct SequenceReport {
+    info: LedSequenceInfo,
+    /// Seconds, without the fade in from the previous color
+    duration: f32,
+    /// Frames, without the fade in from the previous color
+    frames: usize,
+    /// Frames the server plays, with the fade in
+    total_frames: usize,
+}
+
+fn type_name(sequence_type: &LedSequenceType) -> &'static str {
+    match sequence_type {
+        LedSequenceType::Color => "color",
+        LedSequenceType::Gradient => "gradient",
+        LedSequenceType::Script(_) => "script",
+        LedSequenceType::Audio(_) => "audio",
+        _ => "effect",
+    }
+}
+
+fn load_sequence(file: &Path) -> Result<LedSequence, String> {
+    sequence_tools::load(file)
+        .map_err(|e| format!("Unable to load {:?}: {}", file, e))
+}
+
+/// Run the offline sequence tools, which don't need the server
+fn run_sequence_tool(cli: &Cli, command: &SeqCommand) -> Result<(), String> {
+    match command {
+        SeqCommand::Info { file } => {
+            let seq = load_sequence(file)?;
+            let frames = sequence_tools::sample_frames(&seq);
+            let report = SequenceReport {
+                info: seq.info.clone(),
+                duration: (frames.len() - 1) as f32 / RESOLUTION,
+                frames: frames.len(),
+                total_frames: seq.frame_count(),
+            };
+
+            if cli.json {
+                print_json(&report);
+            } else {
+                println!("Name:     {}", report.info.name);
+                println!("Type:     {}", type_name(&report.info.sequence_type));
+                println!(
+                    "Duration: {:.2}s ({} frames at {}fps, {} with the fade in)",
+                    report.duration, report.frames, RESOLUTION, report.total_frames
+                );
+                println!(
+                    "Repeat:   {}",
+                    if report.info.repeat { "yes" } else { "no" }
+                );
+            }
+        }
+        SeqCommand::Render {
+            file,
+            output,
+            width,
+            height,
+        } => {
+            let seq = load_sequence(file)?;
+            let output = output.clone().unwrap_or_else(|| {
+                PathBuf::from(format!(
+                    "{}-preview.png",
+                    sequence_stem(&file.to_string_lossy())
+                ))
+            });
+            sequence_tools::write_gradient_png(
+                &sequence_tools::sample_frames(&seq),
+                &output,
+                *width,
+                *height,
+            )
+            .map_err(|e| format!("Unable to write {:?}: {}", output, e))?;
+            print_message(cli, &format!("Rendered to {}", output.display()));
+        }
+        SeqCommand::Preview { file, width, play } => {
+            let mut seq = load_sequence(file)?;
+            if *play {
+                // Redraw in place, two lines per frame
+                let frame_time = Duration::from_secs_f32(1.0 / RESOLUTION);
+                for (i, color) in (&mut seq).enumerate() {
+                    if i > 0 {
+                        print!("\x1b[2A");
+                    }
+                    println!("{}", ansi_color_bars(&color, *width));
+                    std::thread::sleep(frame_time);
+                }
+            } else {
+                let frames = sequence_tools::sample_frames(&seq);
+                println!("{}", sequence_tools::ansi_strip(&frames, *width));
+            }
+        }
+        SeqCommand::Convert {
+            file,
+            output,
+            tolerance,
+        } => {
+            let seq = load_sequence(file)?;
+            let to_png = file.extension().is_some_and(|e| e == "json");
+
+            let (output, written) = if to_png {
+                let frames = sequence_tools::sample_frames(&seq);
+                let duration = (frames.len() - 1) as f32 / RESOLUTION;
+                let output = output.clone().unwrap_or_else(|| {
+                    file.with_file_name(sequence_tools::gradient_file_name(
+                        &seq.info, duration,
+                    ))
+                });
+                sequence_tools::write_gradient_png(
+                    &frames,
+                    &output,
+                    GRADIENT_WIDTH,
+                    GRADIENT_HEIGHT,
+                )
+                .map_err(|e| format!("Unable to write {:?}: {}", output, e))?;
+                (output, "png gradient".to_string())
+            } else {
+                let points = sequence_tools::to_color_points(&seq, *tolerance);
+                let output = output
+                    .clone()
+                    .unwrap_or_else(|| file.with_extension("json"));
+                let json =
+                    serde_json::to_string_pretty(&points).map_err(|e| {
+                        format!("Unable to serialize points: {}", e)
+                    })?;
+                std::fs::write(&output, json).map_err(|e| {
+                    format!("Unable to write {:?}: {}", output, e)
+                })?;
+                (
+                    output,
+                    format!("{} control points", points.color_points.len()),
+                )
+            };
+            print_message(
+                cli,
+                &format!("Converted to {} ({})", output.display(), written),
+            );
+        }
+        SeqCommand::Play { .. } | SeqCommand::List => {
+            unreachable!("Not an offline sequence tool")
+        }
+    }
+
+    Ok(())
+}
+
+fn run(cli: &Cli) -> Result<(), String> {
+    let client = Client {
+        server: cli.server.clone(),
+        token: cli.token.clone(),
+    };
+
+    match cli.command {
+        Command::Status => {
+            let status: LedStatus = client.get_json("/api/get-status")?;
+            if cli.json {
+                print_json(&status);
+            } else {
+                print_status(&status);
+            }
+        }
+        Command::Color {
+            r,
+            g,
+            b,
+            w,
+            transition,
+        } => {
+            let path = match transition {
+                Some(t) => {
+                    format!("/api/set-color?transition={}", t.as_secs_f32())
+                }
+                None => "/api/set-color".to_string(),
+            };
+            let message = client.post_json(&path, &Color::new(r, g, b, w))?;
+            print_message(cli, &message);
+        }
+        Command::Rooms { ref changes } => {
+            let mut rooms: Rooms = client.get_json("/api/get-rooms")?;
+            if !changes.is_empty() {
+                for change in changes {
+                    let (name, on) =
+                        if let Some(name) = change.strip_prefix('-') {
+                            (name, false)
+                        } else {
+                            (change.trim_start_matches('+'), true)
+                        };
+                    rooms.set(&name.parse::<Room>()?, on);
+                }
+                client.post_json("/api/set-rooms", &rooms)?;
+            }
+
+            if cli.json {
+                print_json(&rooms);
+            } else {
+                println!("Rooms: {}", format_rooms(&rooms));
+            }
+        }
+        Command::Seq(SeqCommand::Play { ref name }) => {
+            // Accept the bare name, and find the file it's in
+            let path = list_sequences(&client)?
+                .into_iter()
+                .find(|path| path == name || sequence_stem(path) == *name)
+                .unwrap_or_else(|| name.clone());
+            let message = client.post_text("/api/set-sequence", &path)?;
+            print_message(cli, &message);
+        }
+        Command::Seq(SeqCommand::List) => {
+            let sequences = list_sequences(&client)?;
+            if cli.json {
+                print_json(&sequences);
+            } else {
+                for path in sequences {
+                    println!("{}", sequence_stem(&path));
+                }
+            }
+        }
+        Command::Seq(ref tool) => run_sequence_tool(cli, tool)?,
+        Command::Scene(SceneCommand::Apply { ref name }) => {
+            let message = client.post_text("/api/apply-scene", name)?;
+            print_message(cli, &message);
+        }
+        Command::Scene(SceneCommand::List) => {
+            let scenes: Scenes = client.get_json("/api/list-scenes")?;
+            if cli.json {
+                print_json(&scenes);
+            } else {
+                for name in scenes.keys() {
+                    println!("{}", name);
+                }
+            }
+        }
+        Command::Watch { interval } => {
+            let mut last = None;
+            loop {
+                let status: LedStatus = client.get_json("/api/get-status")?;
+                let json = serde_json::to_string(&status).map_err(|e| {
+                    format!("Unable to serialize status: {}", e)
+                })?;
+
+                if last.as_ref() != Some(&json) {
+                    if cli.json {
+                        // One object per line, for piping into other tools
+                        println!("{}", json);
+                    } else {
+                        println!(
+                            "[{}]",
+                            chrono::Local::now().format("%H:%M:%S%.3f")
+                        );
+                        print_status(&status);
+                        println!();
+                    }
+                    last = Some(json);
+                }
+                std::thread::sleep(interval);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn main() {
+    let cli = Cli::parse();
+    if let Err(e) = run(&cli) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
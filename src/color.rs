@@ -6,13 +6,7 @@ fn lerp_component(low: f32, high: f32, percent: f32) -> f32 {
 }
 
 fn clamp_component(component: f32) -> f32 {
-    if component > 1.0 {
-        1.0
-    } else if component < 0.0 {
-        0.0
-    } else {
-        component
-    }
+    component.clamp(0.0, 1.0)
 }
 
 /// RGBW color (float representation, 0.0 to 1.0)
@@ -47,6 +41,40 @@ impl Color {
         }
     }
 
+    /// Create a color from hue (0.0 to 1.0, wrapping), saturation and value,
+    /// plus a separate white component
+    pub fn from_hsv(hue: f32, saturation: f32, value: f32, w: f32) -> Self {
+        let hue = hue.rem_euclid(1.0) * 6.0;
+        let chroma = value * saturation;
+        let x = chroma * (1.0 - (hue % 2.0 - 1.0).abs());
+        let (r, g, b) = match hue as u32 {
+            0 => (chroma, x, 0.0),
+            1 => (x, chroma, 0.0),
+            2 => (0.0, chroma, x),
+            3 => (0.0, x, chroma),
+            4 => (x, 0.0, chroma),
+            _ => (chroma, 0.0, x),
+        };
+        let m = value - chroma;
+        Self::new(r + m, g + m, b + m, w)
+    }
+
+    /// Brightest component, which is how bright the color looks overall
+    pub fn brightness(&self) -> f32 {
+        self.r.max(self.g).max(self.b).max(self.w)
+    }
+
+    /// Scale the color so its brightest component is `brightness`
+    pub fn with_brightness(&self, brightness: f32) -> Self {
+        let current = self.brightness();
+        if current <= 0.0 {
+            // Black has no hue to keep, so use the white LEDs
+            Self::new(0.0, 0.0, 0.0, brightness)
+        } else {
+            self.clone() * (brightness / current)
+        }
+    }
+
     pub fn update_clone(&mut self, reference: &Self) {
         self.r = reference.r;
         self.g = reference.g;
@@ -78,10 +106,10 @@ impl Color {
 impl From<&[u8; 4]> for Color {
     fn from(bytes: &[u8; 4]) -> Self {
         Self {
-            r: f32::from(bytes[0]) / f32::from(<u8>::max_value()),
-            g: f32::from(bytes[1]) / f32::from(<u8>::max_value()),
-            b: f32::from(bytes[2]) / f32::from(<u8>::max_value()),
-            w: f32::from(bytes[3]) / f32::from(<u8>::max_value()),
+            r: f32::from(bytes[0]) / f32::from(u8::MAX),
+            g: f32::from(bytes[1]) / f32::from(u8::MAX),
+            b: f32::from(bytes[2]) / f32::from(u8::MAX),
+            w: f32::from(bytes[3]) / f32::from(u8::MAX),
         }
     }
 }
@@ -0,0 +1,115 @@
+//! Health checks and diagnostics, for monitoring and for telling whether the
+//! LEDs are really connected
+
+use std::time::Duration;
+
+use actix_web::http::header::ContentType;
+use actix_web::HttpResponse;
+use serde_derive::Serialize;
+
+use crate::frame_clock::FrameStats;
+use crate::led_output::OutputDiagnostics;
+use crate::led_state::{led_config, LED_OUTPUTS, LED_STATE, STARTED};
+
+/// How long the LED worker can go without looping before it's considered
+/// stuck or dead
+const WORKER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Everything about how the server is doing, from `/api/diagnostics`
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostics {
+    pub version: &'static str,
+    pub uptime_secs: f64,
+    /// Is the LED worker thread still looping?
+    pub worker_alive: bool,
+    pub frames: FrameStats,
+    pub outputs: Vec<OutputDiagnostics>,
+    /// Current configuration
+    pub config: serde_json::Value,
+}
+
+/// Is the LED worker thread still looping?
+fn worker_alive() -> bool {
+    LED_STATE.get().read().is_ok_and(|state| {
+        state
+            .worker_heartbeat
+            .is_some_and(|beat| beat.elapsed() < WORKER_TIMEOUT)
+    })
+}
+
+/// Outputs from the config that aren't connected to their hardware
+fn missing_outputs() -> Vec<String> {
+    match LED_OUTPUTS.get().read() {
+        Ok(outputs) => outputs
+            .iter()
+            .map(|output| output.status())
+            .filter(|status| !status.connected)
+            .map(|status| format!("{:?}: {}", status.kind, status.detail))
+            .collect(),
+        Err(_) => vec!["Unable to get lock on outputs".to_string()],
+    }
+}
+
+pub fn collect() -> Diagnostics {
+    let frames = LED_STATE
+        .get()
+        .read()
+        .map(|state| state.frame_stats.clone())
+        .unwrap_or_default();
+    let outputs = LED_OUTPUTS
+        .get()
+        .read()
+        .map(|outputs| outputs.iter().map(|o| o.diagnostics()).collect())
+        .unwrap_or_default();
+
+    Diagnostics {
+        version: env!("CARGO_PKG_VERSION"),
+        uptime_secs: STARTED.get().elapsed().as_secs_f64(),
+        worker_alive: worker_alive(),
+        frames,
+        outputs,
+        config: serde_json::to_value(&*led_config())
+            .unwrap_or(serde_json::Value::Null),
+    }
+}
+
+/// Liveness: the server is up and the LED worker is running
+pub async fn get_health() -> HttpResponse {
+    if worker_alive() {
+        HttpResponse::Ok()
+            .content_type(ContentType::plaintext())
+            .body("OK")
+    } else {
+        error!("Error on /api/health: LED worker isn't running");
+        HttpResponse::ServiceUnavailable()
+            .content_type(ContentType::plaintext())
+            .body("LED worker isn't running")
+    }
+}
+
+/// Readiness: alive, and every output in the config is connected, so the
+/// LEDs will actually change
+pub async fn get_ready() -> HttpResponse {
+    if !worker_alive() {
+        return HttpResponse::ServiceUnavailable()
+            .content_type(ContentType::plaintext())
+            .body("LED worker isn't running");
+    }
+
+    let missing = missing_outputs();
+    if missing.is_empty() {
+        HttpResponse::Ok()
+            .content_type(ContentType::plaintext())
+            .body("OK")
+    } else {
+        HttpResponse::ServiceUnavailable()
+            .content_type(ContentType::plaintext())
+            .body(format!("Outputs not connected: {}", missing.join("; ")))
+    }
+}
+
+pub async fn get_diagnostics() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .json(collect())
+}
@@ -0,0 +1,78 @@
+//! Temporal dithering, for showing colors between an output's levels
+
+use crate::color::Color;
+
+/// Error diffusion across frames: the rounding error of each component is
+/// carried into the next frame, so over a few frames the output averages out
+/// to the exact color instead of the nearest level.
+///
+/// This keeps very slow fades at low brightness (e.g. 2% to off over half an
+/// hour) from moving in visible steps, at the cost of the lowest bit
+/// alternating between frames.
+#[derive(Debug, Clone)]
+pub struct TemporalDither {
+    max_level: f32,
+    error: [f32; 4],
+}
+
+impl TemporalDither {
+    /// Dither to levels from 0 to `max_level`, e.g. `u16::MAX` for the
+    /// Arduino's 16-bit PWM
+    pub fn new(max_level: u16) -> Self {
+        Self {
+            max_level: f32::from(max_level),
+            error: [0.0; 4],
+        }
+    }
+
+    /// Levels for the R, G, B and W components of the next frame
+    pub fn next(&mut self, color: &Color) -> [u16; 4] {
+        let color = color.clamped();
+        let components = [color.r, color.g, color.b, color.w];
+        let mut levels = [0; 4];
+
+        for (i, component) in components.into_iter().enumerate() {
+            let exact = component * self.max_level;
+            let level = if component <= 0.0 || component >= 1.0 {
+                // Fully off and fully on are exact, and shouldn't flicker
+                self.error[i] = 0.0;
+                exact
+            } else {
+                let target = exact + self.error[i];
+                let level = target.round().clamp(0.0, self.max_level);
+                self.error[i] = target - level;
+                level
+            };
+            levels[i] = level as u16;
+        }
+
+        levels
+    }
+
+    /// Forget the carried error, e.g. after the output was reconnected
+    pub fn reset(&mut self) {
+        self.error = [0.0; 4];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dither_averages_to_exact() {
+        let mut dither = TemporalDither::new(u16::MAX);
+        // A quarter of the way between levels 3 and 4
+        let component = 3.25 / f32::from(u16::MAX);
+        let color = Color::new(component, 0.0, 0.0, 1.0);
+
+        let frames = (0..100).map(|_| dither.next(&color)).collect::<Vec<_>>();
+        let total = frames.iter().map(|l| u32::from(l[0])).sum::<u32>();
+        assert!((324..=326).contains(&total));
+        assert!(frames.iter().all(|l| l[0] == 3 || l[0] == 4));
+        assert!(frames.iter().all(|l| l[1] == 0 && l[3] == u16::MAX));
+
+        // Off stays off, whatever error was left over
+        assert_eq!(dither.next(&Color::default()), [0; 4]);
+    }
+}
@@ -0,0 +1,379 @@
+//! Art-Net and E1.31 (sACN) packets, for talking to DMX lighting over UDP
+
+use std::net::Ipv4Addr;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::color::Color;
+use crate::rooms::Rooms;
+
+/// UDP ports each protocol uses
+pub const ARTNET_PORT: u16 = 6454;
+pub const SACN_PORT: u16 = 5568;
+
+/// Number of channels in a DMX universe
+pub const UNIVERSE_SIZE: usize = 512;
+
+// Art-Net constants
+const ARTNET_ID: &[u8; 8] = b"Art-Net\0";
+const ARTNET_OP_DMX: u16 = 0x5000;
+const ARTNET_PROTOCOL_VERSION: u16 = 14;
+const ARTNET_HEADER_BYTES: usize = 18;
+
+// E1.31 constants
+const SACN_ID: &[u8; 12] = b"ASC-E1.17\0\0\0";
+const SACN_ROOT_VECTOR: u32 = 0x0000_0004;
+const SACN_FRAMING_VECTOR: u32 = 0x0000_0002;
+const SACN_DMP_VECTOR: u8 = 0x02;
+const SACN_HEADER_BYTES: usize = 126;
+const SACN_SOURCE_NAME_BYTES: usize = 64;
+const SACN_DEFAULT_PRIORITY: u8 = 100;
+/// Identifies this server as an sACN source
+const SACN_CID: [u8; 16] = *b"led-foot-server\0";
+
+/// Which protocol to speak
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DmxProtocol {
+    Artnet,
+    Sacn,
+}
+
+impl DmxProtocol {
+    pub fn default_port(&self) -> u16 {
+        match self {
+            DmxProtocol::Artnet => ARTNET_PORT,
+            DmxProtocol::Sacn => SACN_PORT,
+        }
+    }
+}
+
+/// Where the colors live in a DMX universe, set in the `[dmx]` section of
+/// `led_config.toml`:
+///
+/// ```toml
+/// [dmx]
+/// protocol = "artnet"
+/// target = "192.168.1.50"
+/// universe = 0
+/// address = 1
+/// sixteen_bit = false
+/// rooms = true
+/// ```
+///
+/// Colors take 4 channels starting at `address` (R, G, B, W), or 8 channels
+/// if `sixteen_bit` (coarse then fine byte for each). If `rooms` is set, the
+/// living room, office and bedroom follow as one channel each (255 for on).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DmxConfig {
+    pub protocol: DmxProtocol,
+    /// Host (and optionally port) to send to. Defaults to broadcast for
+    /// Art-Net, and the universe's multicast group for sACN.
+    pub target: Option<String>,
+    pub universe: u16,
+    /// First channel, starting from 1
+    pub address: u16,
+    pub sixteen_bit: bool,
+    pub rooms: bool,
+}
+
+impl Default for DmxConfig {
+    fn default() -> Self {
+        Self {
+            protocol: DmxProtocol::Artnet,
+            target: None,
+            universe: 0,
+            address: 1,
+            sixteen_bit: false,
+            rooms: false,
+        }
+    }
+}
+
+impl DmxConfig {
+    /// Index in the universe of the first color channel
+    pub fn color_offset(&self) -> usize {
+        (self.address.max(1) as usize - 1).min(UNIVERSE_SIZE)
+    }
+
+    /// Index in the universe of the first room channel
+    pub fn rooms_offset(&self) -> usize {
+        let color_channels = if self.sixteen_bit { 8 } else { 4 };
+        self.color_offset() + color_channels
+    }
+}
+
+/// Color as DMX channel values, in the layout described on `DmxConfig`
+pub fn color_channels(color: &Color, sixteen_bit: bool) -> Vec<u8> {
+    let color = color.clamped();
+    let components = [color.r, color.g, color.b, color.w];
+    if sixteen_bit {
+        components
+            .iter()
+            .flat_map(|c| {
+                ((c * f32::from(u16::MAX)).round() as u16).to_be_bytes()
+            })
+            .collect()
+    } else {
+        components
+            .iter()
+            .map(|c| (c * f32::from(u8::MAX)).round() as u8)
+            .collect()
+    }
+}
+
+/// Rooms as DMX channel values, one for each room
+pub fn rooms_channels(rooms: &Rooms) -> [u8; 3] {
+    let channel = |on: bool| if on { 0xFF } else { 0x00 };
+    [
+        channel(rooms.living_room),
+        channel(rooms.office),
+        channel(rooms.bedroom),
+    ]
+}
+
+/// Copy channels into a universe, ignoring any that don't fit
+pub fn write_channels(universe: &mut [u8], offset: usize, channels: &[u8]) {
+    for (slot, value) in universe.iter_mut().skip(offset).zip(channels) {
+        *slot = *value;
+    }
+}
+
+/// ArtDmx packet carrying a universe's channels
+pub fn artnet_packet(universe: u16, sequence: u8, data: &[u8]) -> Vec<u8> {
+    let data = &data[..data.len().min(UNIVERSE_SIZE)];
+    // Art-Net requires an even number of channels, at least 2
+    let length = (data.len() + data.len() % 2).max(2);
+
+    let mut packet = Vec::with_capacity(ARTNET_HEADER_BYTES + length);
+    packet.extend_from_slice(ARTNET_ID);
+    packet.extend_from_slice(&ARTNET_OP_DMX.to_le_bytes());
+    packet.extend_from_slice(&ARTNET_PROTOCOL_VERSION.to_be_bytes());
+    packet.push(sequence);
+    // Physical input port (informational only)
+    packet.push(0);
+    // Port address: SubUni, then Net
+    packet.push((universe & 0xFF) as u8);
+    packet.push(((universe >> 8) & 0x7F) as u8);
+    packet.extend_from_slice(&(length as u16).to_be_bytes());
+    packet.extend_from_slice(data);
+    packet.resize(ARTNET_HEADER_BYTES + length, 0);
+    packet
+}
+
+/// E1.31 data packet carrying a universe's channels
+pub fn sacn_packet(
+    universe: u16,
+    sequence: u8,
+    source_name: &str,
+    data: &[u8],
+) -> Vec<u8> {
+    let data = &data[..data.len().min(UNIVERSE_SIZE)];
+    let total = SACN_HEADER_BYTES + data.len();
+    let flags_and_length =
+        |start: usize| (0x7000 | (total - start) as u16).to_be_bytes();
+
+    let mut packet = Vec::with_capacity(total);
+
+    // Root layer
+    packet.extend_from_slice(&0x0010u16.to_be_bytes());
+    packet.extend_from_slice(&0x0000u16.to_be_bytes());
+    packet.extend_from_slice(SACN_ID);
+    packet.extend_from_slice(&flags_and_length(16));
+    packet.extend_from_slice(&SACN_ROOT_VECTOR.to_be_bytes());
+    packet.extend_from_slice(&SACN_CID);
+
+    // Framing layer
+    packet.extend_from_slice(&flags_and_length(38));
+    packet.extend_from_slice(&SACN_FRAMING_VECTOR.to_be_bytes());
+    let mut name = [0; SACN_SOURCE_NAME_BYTES];
+    for (slot, byte) in name
+        .iter_mut()
+        .zip(source_name.bytes().take(SACN_SOURCE_NAME_BYTES - 1))
+    {
+        *slot = byte;
+    }
+    packet.extend_from_slice(&name);
+    packet.push(SACN_DEFAULT_PRIORITY);
+    // Synchronization address (unused)
+    packet.extend_from_slice(&0u16.to_be_bytes());
+    packet.push(sequence);
+    // Options
+    packet.push(0);
+    packet.extend_from_slice(&universe.to_be_bytes());
+
+    // DMP layer
+    packet.extend_from_slice(&flags_and_length(115));
+    packet.push(SACN_DMP_VECTOR);
+    // Address type & data type
+    packet.push(0xA1);
+    // First property address
+    packet.extend_from_slice(&0u16.to_be_bytes());
+    // Address increment
+    packet.extend_from_slice(&1u16.to_be_bytes());
+    // Property values: start code plus the channels
+    packet.extend_from_slice(&(data.len() as u16 + 1).to_be_bytes());
+    packet.push(0);
+    packet.extend_from_slice(data);
+
+    packet
+}
+
+/// Multicast group that sACN uses for a universe
+pub fn sacn_multicast_addr(universe: u16) -> Ipv4Addr {
+    let [high, low] = universe.to_be_bytes();
+    Ipv4Addr::new(239, 255, high, low)
+}
+
+/// Channels received in an Art-Net or sACN packet
+#[derive(Debug, Clone, PartialEq)]
+pub struct DmxFrame<'a> {
+    pub protocol: DmxProtocol,
+    pub universe: u16,
+    pub data: &'a [u8],
+}
+
+/// Parse an ArtDmx or E1.31 data packet, returning `None` for anything else
+pub fn parse_packet(packet: &[u8]) -> Option<DmxFrame<'_>> {
+    if packet.starts_with(ARTNET_ID) {
+        parse_artnet(packet)
+    } else if packet.len() >= SACN_HEADER_BYTES && &packet[4..16] == SACN_ID {
+        parse_sacn(packet)
+    } else {
+        None
+    }
+}
+
+fn parse_artnet(packet: &[u8]) -> Option<DmxFrame<'_>> {
+    if packet.len() < ARTNET_HEADER_BYTES
+        || u16::from_le_bytes([packet[8], packet[9]]) != ARTNET_OP_DMX
+    {
+        return None;
+    }
+    let universe = u16::from_le_bytes([packet[14], packet[15] & 0x7F]);
+    let length = u16::from_be_bytes([packet[16], packet[17]]) as usize;
+    let data = packet.get(ARTNET_HEADER_BYTES..ARTNET_HEADER_BYTES + length)?;
+    Some(DmxFrame {
+        protocol: DmxProtocol::Artnet,
+        universe,
+        data,
+    })
+}
+
+fn parse_sacn(packet: &[u8]) -> Option<DmxFrame<'_>> {
+    let root_vector =
+        u32::from_be_bytes([packet[18], packet[19], packet[20], packet[21]]);
+    let framing_vector =
+        u32::from_be_bytes([packet[40], packet[41], packet[42], packet[43]]);
+    // Only take DMX data (start code 0), not e.g. per-channel priorities
+    if root_vector != SACN_ROOT_VECTOR
+        || framing_vector != SACN_FRAMING_VECTOR
+        || packet[117] != SACN_DMP_VECTOR
+        || packet[125] != 0
+    {
+        return None;
+    }
+    let universe = u16::from_be_bytes([packet[113], packet[114]]);
+    let values = u16::from_be_bytes([packet[123], packet[124]]) as usize;
+    let data = packet
+        .get(SACN_HEADER_BYTES..SACN_HEADER_BYTES + values.saturating_sub(1))?;
+    Some(DmxFrame {
+        protocol: DmxProtocol::Sacn,
+        universe,
+        data,
+    })
+}
+
+/// Color from DMX channels, in the layout described on `DmxConfig`
+pub fn channels_color(
+    data: &[u8],
+    offset: usize,
+    sixteen_bit: bool,
+) -> Option<Color> {
+    if sixteen_bit {
+        let channels = data.get(offset..offset + 8)?;
+        let component = |i: usize| {
+            f32::from(u16::from_be_bytes([channels[i], channels[i + 1]]))
+                / f32::from(u16::MAX)
+        };
+        Some(Color::new(
+            component(0),
+            component(2),
+            component(4),
+            component(6),
+        ))
+    } else {
+        let channels = data.get(offset..offset + 4)?;
+        Some(Color::from(&[
+            channels[0],
+            channels[1],
+            channels[2],
+            channels[3],
+        ]))
+    }
+}
+
+/// Rooms from DMX channels, on if the channel is at least half way up
+pub fn channels_rooms(data: &[u8], offset: usize) -> Option<Rooms> {
+    let channels = data.get(offset..offset + 3)?;
+    Some(Rooms {
+        living_room: channels[0] >= 0x80,
+        office: channels[1] >= 0x80,
+        bedroom: channels[2] >= 0x80,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packet_round_trip() {
+        let color = Color::new(1.0, 0.0, 0.5, 0.25);
+        let rooms = Rooms {
+            living_room: false,
+            office: true,
+            bedroom: true,
+        };
+        let config = DmxConfig {
+            address: 20,
+            sixteen_bit: true,
+            rooms: true,
+            ..Default::default()
+        };
+
+        let mut universe = [0; UNIVERSE_SIZE];
+        write_channels(
+            &mut universe,
+            config.color_offset(),
+            &color_channels(&color, true),
+        );
+        write_channels(
+            &mut universe,
+            config.rooms_offset(),
+            &rooms_channels(&rooms),
+        );
+
+        for packet in [
+            artnet_packet(300, 1, &universe),
+            sacn_packet(300, 1, "test", &universe),
+        ] {
+            let frame = parse_packet(&packet).unwrap();
+            assert_eq!(frame.universe, 300);
+            assert_eq!(frame.data, &universe[..]);
+
+            let received =
+                channels_color(frame.data, config.color_offset(), true)
+                    .unwrap();
+            assert!((received.b - 0.5).abs() < 0.001);
+            assert!((received.w - 0.25).abs() < 0.001);
+            assert_eq!(
+                channels_rooms(frame.data, config.rooms_offset()),
+                Some(rooms.clone())
+            );
+        }
+
+        assert_eq!(parse_packet(b"not a dmx packet"), None);
+    }
+}
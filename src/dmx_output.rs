@@ -0,0 +1,258 @@
+//! Output that sends colors to DMX fixtures through an Art-Net or sACN node
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+
+use crate::color::Color;
+use crate::dmx::{self, DmxConfig, DmxProtocol, UNIVERSE_SIZE};
+use crate::led_output::{
+    LedOutput, OutputDiagnostics, OutputKind, OutputStatus,
+};
+use crate::rooms::Rooms;
+
+/// Name this server goes by in sACN packets
+const SACN_SOURCE_NAME: &str = "LED Foot";
+
+/// Sends the whole universe as a UDP packet every time a color or the rooms
+/// change
+pub struct DmxOutput {
+    config: DmxConfig,
+    socket: Option<UdpSocket>,
+    target: Option<SocketAddr>,
+    universe: [u8; UNIVERSE_SIZE],
+    sequence: u8,
+    last_error: Option<String>,
+}
+
+impl DmxOutput {
+    pub fn new(config: &DmxConfig) -> Self {
+        let mut output = Self {
+            config: config.clone(),
+            socket: None,
+            target: None,
+            universe: [0; UNIVERSE_SIZE],
+            sequence: 0,
+            last_error: None,
+        };
+
+        match output.open() {
+            Ok((socket, target)) => {
+                info!("Sending {:?} to {}", config.protocol, target);
+                output.socket = Some(socket);
+                output.target = Some(target);
+            }
+            Err(e) => {
+                error!("Unable to set up {:?} output: {}", config.protocol, e);
+                output.last_error = Some(e.to_string());
+            }
+        }
+
+        output
+    }
+
+    fn open(&self) -> Result<(UdpSocket, SocketAddr), std::io::Error> {
+        let port = self.config.protocol.default_port();
+        let target = match self.config.target {
+            Some(ref host) => {
+                // Use the protocol's port if none was given
+                let addr = if let Ok(addr) = host.parse::<SocketAddr>() {
+                    Some(addr)
+                } else if let Ok(ip) = host.parse::<IpAddr>() {
+                    Some(SocketAddr::new(ip, port))
+                } else if host.contains(':') {
+                    host.to_socket_addrs()?.next()
+                } else {
+                    (host.as_str(), port).to_socket_addrs()?.next()
+                };
+                addr.ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        format!("Unable to resolve {}", host),
+                    )
+                })?
+            }
+            None => match self.config.protocol {
+                DmxProtocol::Artnet => {
+                    SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), port)
+                }
+                DmxProtocol::Sacn => SocketAddr::new(
+                    IpAddr::V4(dmx::sacn_multicast_addr(self.config.universe)),
+                    port,
+                ),
+            },
+        };
+
+        let bind_addr: SocketAddr = if target.is_ipv6() {
+            "[::]:0".parse().expect("Invalid IPv6 bind address")
+        } else {
+            "0.0.0.0:0".parse().expect("Invalid IPv4 bind address")
+        };
+        let socket = UdpSocket::bind(bind_addr)?;
+        socket.set_broadcast(true)?;
+        Ok((socket, target))
+    }
+
+    /// Send the universe as it is now
+    fn send_universe(&mut self) {
+        let (Some(socket), Some(target)) = (&self.socket, self.target)
+        else {
+            return;
+        };
+
+        // Art-Net skips 0, which means "no sequence numbers"
+        self.sequence = self.sequence.wrapping_add(1).max(1);
+        let packet = match self.config.protocol {
+            DmxProtocol::Artnet => dmx::artnet_packet(
+                self.config.universe,
+                self.sequence,
+                &self.universe,
+            ),
+            DmxProtocol::Sacn => dmx::sacn_packet(
+                self.config.universe,
+                self.sequence,
+                SACN_SOURCE_NAME,
+                &self.universe,
+            ),
+        };
+
+        match socket.send_to(&packet, target) {
+            Ok(_) => self.last_error = None,
+            Err(e) => {
+                // Only complain once, instead of every frame
+                if self.last_error.is_none() {
+                    error!(
+                        "Unable to send {:?} packet: {}",
+                        self.config.protocol, e
+                    );
+                }
+                self.last_error = Some(e.to_string());
+            }
+        }
+    }
+}
+
+impl LedOutput for DmxOutput {
+    fn send_color(&mut self, color: &Color) {
+        let channels = dmx::color_channels(color, self.config.sixteen_bit);
+        dmx::write_channels(
+            &mut self.universe,
+            self.config.color_offset(),
+            &channels,
+        );
+        self.send_universe();
+    }
+
+    fn send_rooms(&mut self, rooms: &Rooms) {
+        if !self.config.rooms {
+            return;
+        }
+        dmx::write_channels(
+            &mut self.universe,
+            self.config.rooms_offset(),
+            &dmx::rooms_channels(rooms),
+        );
+        self.send_universe();
+    }
+
+    fn status(&self) -> OutputStatus {
+        OutputStatus {
+            kind: OutputKind::Dmx,
+            connected: self.socket.is_some() && self.last_error.is_none(),
+            detail: match (&self.last_error, self.target) {
+                (Some(e), _) => e.clone(),
+                (None, Some(target)) => format!(
+                    "Sending {:?} universe {} to {}",
+                    self.config.protocol, self.config.universe, target
+                ),
+                (None, None) => "Not set up".to_string(),
+            },
+        }
+    }
+
+    fn diagnostics(&self) -> OutputDiagnostics {
+        OutputDiagnostics {
+            status: self.status(),
+            device: self.target.map(|target| target.to_string()),
+            last_handshake_secs: None,
+            replies: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn listener() -> (UdpSocket, String) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+        (socket, addr)
+    }
+
+    #[test]
+    fn test_artnet_output() {
+        let (socket, addr) = listener();
+        let mut output = DmxOutput::new(&DmxConfig {
+            protocol: DmxProtocol::Artnet,
+            target: Some(addr),
+            universe: 0x0123,
+            address: 10,
+            sixteen_bit: false,
+            rooms: true,
+        });
+
+        output.send_rooms(&Rooms {
+            living_room: true,
+            office: false,
+            bedroom: true,
+        });
+        output.send_color(&Color::new(1.0, 0.5, 0.0, 0.2));
+
+        let mut buf = [0; 1024];
+        // Rooms first, then the color
+        socket.recv(&mut buf).unwrap();
+        let len = socket.recv(&mut buf).unwrap();
+
+        assert_eq!(len, 18 + UNIVERSE_SIZE);
+        assert_eq!(&buf[0..8], b"Art-Net\0");
+        assert_eq!(&buf[8..10], &[0x00, 0x50]);
+        assert_eq!(buf[12], 2);
+        assert_eq!(&buf[14..16], &[0x23, 0x01]);
+        assert_eq!(&buf[16..18], &[0x02, 0x00]);
+        let data = &buf[18..len];
+        assert_eq!(&data[9..13], &[255, 128, 0, 51]);
+        assert_eq!(&data[13..16], &[255, 0, 255]);
+        assert!(output.status().connected);
+    }
+
+    #[test]
+    fn test_sacn_output() {
+        let (socket, addr) = listener();
+        let mut output = DmxOutput::new(&DmxConfig {
+            protocol: DmxProtocol::Sacn,
+            target: Some(addr),
+            universe: 7,
+            address: 1,
+            sixteen_bit: true,
+            rooms: false,
+        });
+
+        output.send_color(&Color::new(1.0, 0.0, 0.5, 0.0));
+
+        let mut buf = [0; 1024];
+        let len = socket.recv(&mut buf).unwrap();
+
+        assert_eq!(len, 126 + UNIVERSE_SIZE);
+        assert_eq!(&buf[4..16], b"ASC-E1.17\0\0\0");
+        assert_eq!(&buf[16..18], &(0x7000 | (len - 16) as u16).to_be_bytes());
+        assert_eq!(&buf[44..52], b"LED Foot");
+        assert_eq!(&buf[113..115], &[0, 7]);
+        assert_eq!(&buf[123..125], &(UNIVERSE_SIZE as u16 + 1).to_be_bytes());
+        assert_eq!(buf[125], 0);
+        let data = &buf[126..len];
+        assert_eq!(&data[0..8], &[0xFF, 0xFF, 0, 0, 0x80, 0x00, 0, 0]);
+    }
+}
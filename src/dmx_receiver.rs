@@ -0,0 +1,172 @@
+//! Receives Art-Net or sACN from lighting software (QLC+, xLights, etc.), so
+//! it can drive the LEDs live
+
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::color::Color;
+use crate::dmx::{self, DmxConfig, DmxProtocol, UNIVERSE_SIZE};
+use crate::led_state::{LiveFrame, LED_STATE};
+use crate::rooms::Rooms;
+
+/// Set in the `[dmx_input]` section of `led_config.toml`:
+///
+/// ```toml
+/// [dmx_input]
+/// enabled = true
+/// protocol = "sacn"
+/// universe = 1
+/// address = 1
+/// sixteen_bit = false
+/// rooms = true
+/// timeout = 5.0
+/// ```
+///
+/// Channels use the same layout as the `[dmx]` output.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DmxInputConfig {
+    pub enabled: bool,
+    pub protocol: DmxProtocol,
+    pub universe: u16,
+    /// First channel, starting from 1
+    pub address: u16,
+    pub sixteen_bit: bool,
+    pub rooms: bool,
+    /// Seconds without any packets before going back to normal
+    pub timeout: f32,
+    /// Address to listen on (defaults to all interfaces, on the protocol's
+    /// port)
+    pub bind: Option<String>,
+}
+
+impl Default for DmxInputConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            protocol: DmxProtocol::Sacn,
+            universe: 1,
+            address: 1,
+            sixteen_bit: false,
+            rooms: false,
+            timeout: 5.0,
+            bind: None,
+        }
+    }
+}
+
+impl DmxInputConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs_f32(self.timeout.max(0.0))
+    }
+
+    /// The channel layout, which is shared with the output
+    fn layout(&self) -> DmxConfig {
+        DmxConfig {
+            protocol: self.protocol,
+            target: None,
+            universe: self.universe,
+            address: self.address,
+            sixteen_bit: self.sixteen_bit,
+            rooms: self.rooms,
+        }
+    }
+}
+
+/// Start listening for live frames on a separate thread, if enabled
+pub fn start(config: &DmxInputConfig) -> Result<(), std::io::Error> {
+    if !config.enabled {
+        return Ok(());
+    }
+
+    let bind_addr = match config.bind {
+        Some(ref addr) => addr.parse::<SocketAddr>().map_err(|e| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+        })?,
+        None => SocketAddr::from((
+            Ipv4Addr::UNSPECIFIED,
+            config.protocol.default_port(),
+        )),
+    };
+    let socket = UdpSocket::bind(bind_addr)?;
+    if config.protocol == DmxProtocol::Sacn {
+        let group = dmx::sacn_multicast_addr(config.universe);
+        if let Err(e) = socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)
+        {
+            warn!("Unable to join sACN multicast group {}: {}", group, e);
+        }
+    }
+
+    info!(
+        "Listening for {:?} universe {} on {}",
+        config.protocol, config.universe, bind_addr
+    );
+    let config = config.clone();
+    std::thread::spawn(move || receiver_worker(socket, config));
+    Ok(())
+}
+
+fn receiver_worker(socket: UdpSocket, config: DmxInputConfig) {
+    let layout = config.layout();
+    let mut buf = [0; 2 * UNIVERSE_SIZE];
+
+    loop {
+        let len = match socket.recv(&mut buf) {
+            Ok(len) => len,
+            Err(e) => {
+                error!("Unable to receive DMX packet: {}", e);
+                continue;
+            }
+        };
+
+        let Some(frame) = dmx::parse_packet(&buf[..len]) else {
+            continue;
+        };
+        if frame.protocol != config.protocol
+            || frame.universe != config.universe
+        {
+            continue;
+        }
+
+        let Some(color) = dmx::channels_color(
+            frame.data,
+            layout.color_offset(),
+            layout.sixteen_bit,
+        ) else {
+            continue;
+        };
+        let rooms = if layout.rooms {
+            dmx::channels_rooms(frame.data, layout.rooms_offset())
+        } else {
+            None
+        };
+
+        update_live_frame(color, rooms);
+    }
+}
+
+fn update_live_frame(color: Color, rooms: Option<Rooms>) {
+    if let Ok(mut state) = LED_STATE.get().write() {
+        if state.shutting_down {
+            return;
+        }
+        let color_before = match state.live_frame {
+            Some(ref live) => live.color_before.clone(),
+            None => {
+                info!("Live DMX input started");
+                state.current_color.clone()
+            }
+        };
+        trace!("Live DMX frame {:?}, {:?}", color, rooms);
+        state.live_frame = Some(LiveFrame {
+            color,
+            rooms,
+            received: Instant::now(),
+            color_before,
+        });
+    } else {
+        error!("Unable to get lock on state for live DMX frame");
+    }
+}
@@ -0,0 +1,536 @@
+//! Built-in parametric effects, which are generated procedurally instead of
+//! being authored as png gradients or color points
+
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::color::Color;
+use crate::led_sequence::LedSequenceType;
+use crate::sequence_source::SequenceSource;
+
+/// Flashing faster than this can trigger photosensitive seizures, so strobes
+/// are capped to it
+pub const MAX_STROBE_FREQUENCY: f32 = 3.0;
+
+/// How long the noise-based effects and scripts play before looping, if no
+/// duration is given
+pub const DEFAULT_LOOP_DURATION: f32 = 60.0;
+
+/// Names of all effects, as accepted by `/api/set-effect`
+pub const EFFECT_NAMES: [&str; 6] = [
+    "Breathe",
+    "Candle",
+    "HueWheel",
+    "PastelDrift",
+    "Lightning",
+    "Strobe",
+];
+
+/// Slowly pulse a color's brightness up and down
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BreatheParams {
+    pub color: Color,
+    /// Seconds for one full breath
+    pub period: f32,
+    /// Brightness (0.0 to 1.0) at the bottom of each breath
+    pub min_brightness: f32,
+}
+
+impl Default for BreatheParams {
+    fn default() -> Self {
+        Self {
+            color: Color::new(1.0, 0.3, 0.0, 0.5),
+            period: 4.0,
+            min_brightness: 0.1,
+        }
+    }
+}
+
+/// Flickering candle / fire
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CandleParams {
+    pub color: Color,
+    /// Seed for the flicker noise; different seeds flicker differently
+    pub seed: u64,
+    /// How much the brightness dips when flickering (0.0 to 1.0)
+    pub flicker: f32,
+    /// Roughly how many flickers per second
+    pub speed: f32,
+}
+
+impl Default for CandleParams {
+    fn default() -> Self {
+        Self {
+            color: Color::new(1.0, 0.35, 0.0, 0.6),
+            seed: 0,
+            flicker: 0.3,
+            speed: 8.0,
+        }
+    }
+}
+
+/// Cycle through every hue of the color wheel
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HueWheelParams {
+    /// Seconds for one trip around the wheel
+    pub period: f32,
+    pub saturation: f32,
+    pub value: f32,
+    pub white: f32,
+}
+
+impl Default for HueWheelParams {
+    fn default() -> Self {
+        Self {
+            period: 30.0,
+            saturation: 1.0,
+            value: 1.0,
+            white: 0.0,
+        }
+    }
+}
+
+/// Drift between random pastel colors
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PastelDriftParams {
+    pub seed: u64,
+    /// Seconds spent drifting from one color to the next
+    pub hold: f32,
+    /// How many colors to drift between before looping
+    pub colors: usize,
+    pub white: f32,
+}
+
+impl Default for PastelDriftParams {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            hold: 10.0,
+            colors: 8,
+            white: 0.2,
+        }
+    }
+}
+
+/// Occasional bursts of lightning flashes over a background color
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LightningParams {
+    pub color: Color,
+    pub background: Color,
+    pub seed: u64,
+    /// Average number of lightning strikes per minute
+    pub strikes_per_minute: f32,
+}
+
+impl Default for LightningParams {
+    fn default() -> Self {
+        Self {
+            color: Color::new(0.8, 0.8, 1.0, 1.0),
+            background: Color::new(0.0, 0.0, 0.05, 0.0),
+            seed: 0,
+            strikes_per_minute: 6.0,
+        }
+    }
+}
+
+/// Alternate between colors, e.g. red and blue for an alert
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StrobeParams {
+    pub colors: Vec<Color>,
+    /// Color changes per second, capped to `MAX_STROBE_FREQUENCY`
+    pub frequency: f32,
+}
+
+impl Default for StrobeParams {
+    fn default() -> Self {
+        Self {
+            colors: vec![
+                Color::new(1.0, 0.0, 0.0, 0.0),
+                Color::new(0.0, 0.0, 1.0, 0.0),
+            ],
+            frequency: 2.0,
+        }
+    }
+}
+
+/// Create an effect by its name in `EFFECT_NAMES`, with default parameters
+pub fn default_effect(name: &str) -> Option<LedSequenceType> {
+    Some(match name {
+        "Breathe" => LedSequenceType::Breathe(BreatheParams::default()),
+        "Candle" => LedSequenceType::Candle(CandleParams::default()),
+        "HueWheel" => LedSequenceType::HueWheel(HueWheelParams::default()),
+        "PastelDrift" => {
+            LedSequenceType::PastelDrift(PastelDriftParams::default())
+        }
+        "Lightning" => LedSequenceType::Lightning(LightningParams::default()),
+        "Strobe" => LedSequenceType::Strobe(StrobeParams::default()),
+        _ => return None,
+    })
+}
+
+/// Name of the effect in `EFFECT_NAMES`, if the sequence type is one
+pub fn effect_name(sequence_type: &LedSequenceType) -> Option<&'static str> {
+    match sequence_type {
+        LedSequenceType::Breathe(_) => Some("Breathe"),
+        LedSequenceType::Candle(_) => Some("Candle"),
+        LedSequenceType::HueWheel(_) => Some("HueWheel"),
+        LedSequenceType::PastelDrift(_) => Some("PastelDrift"),
+        LedSequenceType::Lightning(_) => Some("Lightning"),
+        LedSequenceType::Strobe(_) => Some("Strobe"),
+        _ => None,
+    }
+}
+
+/// Create the generator for an effect, which loops every `duration` seconds
+/// (for effects that don't have a natural period). Returns `None` for
+/// sequence types that aren't effects.
+pub fn effect_source(
+    sequence_type: &LedSequenceType,
+    duration: f32,
+) -> Option<Arc<dyn SequenceSource>> {
+    let duration = if duration > 0.0 {
+        duration
+    } else {
+        DEFAULT_LOOP_DURATION
+    };
+
+    let source: Arc<dyn SequenceSource> = match sequence_type {
+        LedSequenceType::Color
+        | LedSequenceType::Gradient
+        | LedSequenceType::Script(_)
+        | LedSequenceType::Audio(_) => return None,
+        LedSequenceType::Breathe(params) => Arc::new(Breathe {
+            params: params.clone(),
+            period: positive_or(params.period, 4.0),
+        }),
+        LedSequenceType::Candle(params) => Arc::new(Candle {
+            params: params.clone(),
+            duration,
+        }),
+        LedSequenceType::HueWheel(params) => Arc::new(HueWheel {
+            params: params.clone(),
+            period: positive_or(params.period, 30.0),
+        }),
+        LedSequenceType::PastelDrift(params) => Arc::new(PastelDrift {
+            params: params.clone(),
+            hold: positive_or(params.hold, 10.0),
+            colors: params.colors.max(2),
+        }),
+        LedSequenceType::Lightning(params) => Arc::new(Lightning {
+            params: params.clone(),
+            duration,
+        }),
+        LedSequenceType::Strobe(params) => {
+            let frequency = if params.frequency > MAX_STROBE_FREQUENCY {
+                warn!(
+                    "Strobe frequency {}Hz is unsafe, capping to {}Hz",
+                    params.frequency, MAX_STROBE_FREQUENCY
+                );
+                MAX_STROBE_FREQUENCY
+            } else {
+                positive_or(params.frequency, MAX_STROBE_FREQUENCY)
+            };
+            Arc::new(Strobe {
+                params: params.clone(),
+                frequency,
+            })
+        }
+    };
+
+    Some(source)
+}
+
+fn positive_or(value: f32, default: f32) -> f32 {
+    if value > 0.0 {
+        value
+    } else {
+        default
+    }
+}
+
+/// Random value in 0.0..1.0 for a seed and an integer position (splitmix64)
+fn hash_noise(seed: u64, n: u64) -> f32 {
+    let mut z = seed
+        .wrapping_mul(0x9e37_79b9_7f4a_7c15)
+        .wrapping_add(n)
+        .wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+    (z >> 40) as f32 / (1u64 << 24) as f32
+}
+
+/// Smoothly varying noise in 0.0..1.0, changing about once per unit of `x`
+fn value_noise(seed: u64, x: f32) -> f32 {
+    let x = x.max(0.0);
+    let i = x.floor();
+    let fraction = x - i;
+    let smooth = fraction * fraction * (3.0 - 2.0 * fraction);
+    let low = hash_noise(seed, i as u64);
+    let high = hash_noise(seed, i as u64 + 1);
+    low + (high - low) * smooth
+}
+
+#[derive(Debug)]
+struct Breathe {
+    params: BreatheParams,
+    period: f32,
+}
+
+impl SequenceSource for Breathe {
+    fn color_at(&self, t: f32) -> Color {
+        let min = self.params.min_brightness.clamp(0.0, 1.0);
+        let wave = 0.5 - 0.5 * (2.0 * PI * t / self.period).cos();
+        self.params.color.clone() * (min + (1.0 - min) * wave)
+    }
+
+    fn duration(&self) -> f32 {
+        self.period
+    }
+}
+
+#[derive(Debug)]
+struct Candle {
+    params: CandleParams,
+    duration: f32,
+}
+
+impl SequenceSource for Candle {
+    fn color_at(&self, t: f32) -> Color {
+        let x = t * self.params.speed;
+        let noise = 0.6 * value_noise(self.params.seed, x)
+            + 0.4 * value_noise(self.params.seed.wrapping_add(1), x * 2.7);
+        let brightness = 1.0 - self.params.flicker.clamp(0.0, 1.0) * noise;
+        (self.params.color.clone() * brightness).clamped()
+    }
+
+    fn duration(&self) -> f32 {
+        self.duration
+    }
+}
+
+#[derive(Debug)]
+struct HueWheel {
+    params: HueWheelParams,
+    period: f32,
+}
+
+impl SequenceSource for HueWheel {
+    fn color_at(&self, t: f32) -> Color {
+        Color::from_hsv(
+            t / self.period,
+            self.params.saturation,
+            self.params.value,
+            self.params.white,
+        )
+        .clamped()
+    }
+
+    fn duration(&self) -> f32 {
+        self.period
+    }
+}
+
+#[derive(Debug)]
+struct PastelDrift {
+    params: PastelDriftParams,
+    hold: f32,
+    colors: usize,
+}
+
+impl PastelDrift {
+    fn pastel(&self, index: usize) -> Color {
+        let index = (index % self.colors) as u64;
+        Color::from_hsv(
+            hash_noise(self.params.seed, index),
+            0.35,
+            1.0,
+            self.params.white,
+        )
+        .clamped()
+    }
+}
+
+impl SequenceSource for PastelDrift {
+    fn color_at(&self, t: f32) -> Color {
+        let position = (t / self.hold).max(0.0);
+        let index = position.floor() as usize;
+        let fraction = position.fract();
+        let smooth = fraction * fraction * (3.0 - 2.0 * fraction);
+        self.pastel(index).lerp(&self.pastel(index + 1), smooth)
+    }
+
+    fn duration(&self) -> f32 {
+        self.hold * self.colors as f32
+    }
+}
+
+#[derive(Debug)]
+struct Lightning {
+    params: LightningParams,
+    duration: f32,
+}
+
+impl Lightning {
+    /// Length of each flash in a strike
+    const FLASH: f32 = 0.05;
+    /// Time from the start of one flash to the next, kept slow enough to be
+    /// safe even between strikes
+    const CYCLE: f32 = 1.0 / MAX_STROBE_FREQUENCY;
+    const MAX_FLASHES: u32 = 3;
+}
+
+impl SequenceSource for Lightning {
+    fn color_at(&self, t: f32) -> Color {
+        let seed = self.params.seed;
+        let strike_length = Self::MAX_FLASHES as f32 * Self::CYCLE;
+        let slot_length = (60.0
+            / positive_or(self.params.strikes_per_minute, 6.0))
+        .max(strike_length);
+        let slot = (t / slot_length).floor().max(0.0) as u64;
+        let strike_start = slot as f32 * slot_length
+            + hash_noise(seed, slot) * (slot_length - strike_length);
+        let flashes = (1
+            + (hash_noise(seed.wrapping_add(1), slot)
+                * Self::MAX_FLASHES as f32) as u32)
+            .min(Self::MAX_FLASHES);
+
+        let since_strike = t - strike_start;
+        let flash = (since_strike / Self::CYCLE).floor();
+        let intensity = if since_strike >= 0.0
+            && flash < flashes as f32
+            && since_strike - flash * Self::CYCLE < Self::FLASH
+        {
+            // Each flash in a strike is a bit dimmer than the one before it
+            1.0 - flash * 0.25
+        } else {
+            0.0
+        };
+
+        self.params.background.lerp(&self.params.color, intensity)
+    }
+
+    fn duration(&self) -> f32 {
+        self.duration
+    }
+}
+
+#[derive(Debug)]
+struct Strobe {
+    params: StrobeParams,
+    frequency: f32,
+}
+
+impl SequenceSource for Strobe {
+    fn color_at(&self, t: f32) -> Color {
+        if self.params.colors.is_empty() {
+            return Color::default();
+        }
+        let index = (t.max(0.0) * self.frequency).floor() as usize;
+        self.params.colors[index % self.params.colors.len()].clone()
+    }
+
+    fn duration(&self) -> f32 {
+        self.params.colors.len().max(1) as f32 / self.frequency
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Shortest time between flashes, sampling every millisecond for a
+    /// minute. `starts` tells whether going from one color to the next is
+    /// the start of a flash.
+    fn shortest_flash_gap(
+        source: &dyn SequenceSource,
+        starts: impl Fn(&Color, &Color) -> bool,
+    ) -> f32 {
+        let mut shortest = f32::INFINITY;
+        let mut last_start = None;
+        let mut last = source.color_at(0.0);
+        for ms in 1..60_000 {
+            let t = ms as f32 / 1000.0;
+            let color = source.color_at(t);
+            if starts(&last, &color) {
+                if let Some(last_start) = last_start {
+                    shortest = shortest.min(t - last_start);
+                }
+                last_start = Some(t);
+            }
+            last = color;
+        }
+        shortest
+    }
+
+    #[test]
+    fn test_strobe_frequency() {
+        let safe = 1.0 / MAX_STROBE_FREQUENCY - 0.002;
+        for frequency in [-1.0, 0.0, 1.0, 3.0, 50.0, f32::INFINITY, f32::NAN] {
+            let params = StrobeParams {
+                frequency,
+                ..Default::default()
+            };
+            let source =
+                effect_source(&LedSequenceType::Strobe(params), 0.0).unwrap();
+            let gap = shortest_flash_gap(source.as_ref(), |a, b| a != b);
+            assert!(gap >= safe, "{}Hz strobes every {}s", frequency, gap);
+        }
+    }
+
+    #[test]
+    fn test_lightning_frequency() {
+        let safe = 1.0 / MAX_STROBE_FREQUENCY - 0.002;
+        for strikes_per_minute in
+            [-1.0, 0.0, 6.0, 60.0, 6000.0, f32::INFINITY, f32::NAN]
+        {
+            for seed in 0..4 {
+                let params = LightningParams {
+                    seed,
+                    strikes_per_minute,
+                    ..Default::default()
+                };
+                let background = params.background.clone();
+                let source =
+                    effect_source(&LedSequenceType::Lightning(params), 0.0)
+                        .unwrap();
+                let gap = shortest_flash_gap(source.as_ref(), |a, b| {
+                    *a == background && *b != background
+                });
+                assert!(
+                    gap >= safe,
+                    "{} strikes per minute flash every {}s",
+                    strikes_per_minute,
+                    gap
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_candle_seed() {
+        let candle = |seed| {
+            let params = CandleParams {
+                seed,
+                ..Default::default()
+            };
+            effect_source(&LedSequenceType::Candle(params), 0.0).unwrap()
+        };
+        let colors = |source: Arc<dyn SequenceSource>| {
+            (0..600)
+                .map(|i| source.color_at(i as f32 / 30.0))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(colors(candle(7)), colors(candle(7)));
+        assert_ne!(colors(candle(7)), colors(candle(8)));
+    }
+}
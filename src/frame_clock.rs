@@ -0,0 +1,263 @@
+//! Frame timing for the LED worker, separate from the LEDs themselves so it
+//! can be tested with a fake clock
+
+use std::time::{Duration, Instant};
+
+use serde_derive::{Deserialize, Serialize};
+
+/// Source of time for a `FrameClock`
+pub trait Clock {
+    /// Time since the clock was created
+    fn elapsed(&self) -> Duration;
+
+    /// Block for `duration`
+    fn sleep(&mut self, duration: Duration);
+}
+
+/// Real time, sleeping the current thread
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+/// Time that only moves when told to, for tests. Sleeping moves it forward
+/// by exactly the time slept.
+#[derive(Debug, Default)]
+pub struct FakeClock {
+    pub now: Duration,
+}
+
+impl FakeClock {
+    /// Pretend `duration` passed, e.g. while writing to serial
+    pub fn advance(&mut self, duration: Duration) {
+        self.now += duration;
+    }
+}
+
+impl Clock for FakeClock {
+    fn elapsed(&self) -> Duration {
+        self.now
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        self.advance(duration);
+    }
+}
+
+/// How well frames have kept to their deadlines
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FrameStats {
+    /// Frames waited for
+    pub frames: u64,
+    /// Frames that were already over by the time they were waited for
+    pub skipped: u64,
+    /// Average time frames started after their deadline, in milliseconds
+    pub mean_jitter_ms: f64,
+    /// Latest any frame started after its deadline, in milliseconds
+    pub max_jitter_ms: f64,
+    /// Frames per second over the last second or so
+    pub fps: f64,
+}
+
+/// Schedules frames against absolute deadlines (`start + frame / fps`), so
+/// the time spent sending each frame doesn't add up into drift. When sending
+/// takes longer than a whole frame, the missed frames are skipped, keeping
+/// sequences at their real duration instead of slowing them down.
+pub struct FrameClock<C: Clock> {
+    clock: C,
+    fps: f64,
+    start: Duration,
+    frame: u64,
+
+    frames: u64,
+    skipped: u64,
+    total_jitter: Duration,
+    max_jitter: Duration,
+    last_jitter: Duration,
+
+    fps_window_start: Duration,
+    fps_window_frames: u64,
+    measured_fps: f64,
+}
+
+impl<C: Clock> FrameClock<C> {
+    pub fn new(clock: C, fps: f32) -> Self {
+        let start = clock.elapsed();
+        Self {
+            clock,
+            fps: f64::from(fps),
+            start,
+            frame: 0,
+            frames: 0,
+            skipped: 0,
+            total_jitter: Duration::ZERO,
+            max_jitter: Duration::ZERO,
+            last_jitter: Duration::ZERO,
+            fps_window_start: start,
+            fps_window_frames: 0,
+            measured_fps: 0.0,
+        }
+    }
+
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    pub fn clock_mut(&mut self) -> &mut C {
+        &mut self.clock
+    }
+
+    /// Frames since the clock (re)started
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// Time since the clock (re)started
+    pub fn elapsed(&self) -> Duration {
+        self.clock.elapsed().saturating_sub(self.start)
+    }
+
+    /// Start counting frames from now, e.g. when a new sequence starts
+    pub fn restart(&mut self) {
+        self.start = self.clock.elapsed();
+        self.frame = 0;
+    }
+
+    /// When `frame` is due
+    fn deadline(&self, frame: u64) -> Duration {
+        self.start + Duration::from_secs_f64(frame as f64 / self.fps)
+    }
+
+    /// Wait until the next frame is due. Returns how many frames that moved
+    /// on by: 1, or more if frames were skipped because the last one ran late.
+    pub fn wait_for_next_frame(&mut self) -> u64 {
+        let now = self.clock.elapsed();
+        let current =
+            (now.saturating_sub(self.start).as_secs_f64() * self.fps) as u64;
+        let next = (self.frame + 1).max(current);
+        self.skipped += next - (self.frame + 1);
+
+        let deadline = self.deadline(next);
+        if let Some(wait) = deadline.checked_sub(now) {
+            self.clock.sleep(wait);
+        }
+
+        let now = self.clock.elapsed();
+        let jitter = now.saturating_sub(deadline);
+        self.frames += 1;
+        self.total_jitter += jitter;
+        self.max_jitter = self.max_jitter.max(jitter);
+        self.last_jitter = jitter;
+
+        self.fps_window_frames += 1;
+        let window = now.saturating_sub(self.fps_window_start);
+        if window >= Duration::from_secs(1) {
+            self.measured_fps =
+                self.fps_window_frames as f64 / window.as_secs_f64();
+            self.fps_window_start = now;
+            self.fps_window_frames = 0;
+        }
+
+        let advanced = next - self.frame;
+        self.frame = next;
+        advanced
+    }
+
+    /// How late the last frame started after its deadline
+    pub fn last_jitter(&self) -> Duration {
+        self.last_jitter
+    }
+
+    /// Jitter statistics since the clock was created
+    pub fn stats(&self) -> FrameStats {
+        let to_ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        FrameStats {
+            frames: self.frames,
+            skipped: self.skipped,
+            mean_jitter_ms: if self.frames > 0 {
+                to_ms(self.total_jitter) / self.frames as f64
+            } else {
+                0.0
+            },
+            max_jitter_ms: to_ms(self.max_jitter),
+            fps: self.measured_fps,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frames_on_time() {
+        let mut clock = FrameClock::new(FakeClock::default(), 30.0);
+        for _ in 0..30 {
+            // Sending a frame takes a while, but less than a frame
+            clock.clock_mut().advance(Duration::from_millis(20));
+            assert_eq!(clock.wait_for_next_frame(), 1);
+        }
+
+        // No drift from the time spent sending
+        assert_eq!(clock.elapsed(), Duration::from_secs(1));
+        assert_eq!(clock.frame(), 30);
+        let stats = clock.stats();
+        assert_eq!(stats.skipped, 0);
+        assert_eq!(stats.max_jitter_ms, 0.0);
+        assert_eq!(stats.fps, 30.0);
+    }
+
+    #[test]
+    fn test_slow_frames_are_skipped() {
+        let mut clock = FrameClock::new(FakeClock::default(), 30.0);
+        let mut advanced = 0;
+        for _ in 0..20 {
+            // Sending takes longer than a whole frame
+            clock.clock_mut().advance(Duration::from_millis(50));
+            advanced += clock.wait_for_next_frame();
+        }
+
+        // The frames keep up with real time instead of slowing down
+        assert_eq!(clock.elapsed(), Duration::from_secs(1));
+        assert_eq!(advanced, 30);
+        assert_eq!(clock.frame(), 30);
+        let stats = clock.stats();
+        assert_eq!(stats.frames, 20);
+        assert_eq!(stats.skipped, 10);
+        assert!(stats.max_jitter_ms < 1000.0 / 30.0);
+    }
+
+    #[test]
+    fn test_restart() {
+        let mut clock = FrameClock::new(FakeClock::default(), 10.0);
+        clock.wait_for_next_frame();
+        clock.clock_mut().advance(Duration::from_millis(250));
+        clock.restart();
+        assert_eq!(clock.frame(), 0);
+        assert_eq!(clock.wait_for_next_frame(), 1);
+        assert_eq!(clock.clock().now, Duration::from_millis(450));
+    }
+}
@@ -0,0 +1,144 @@
+//! Where the HTTP server listens, and HTTPS with rustls, set up in the
+//! `[http]` section of `led_config.toml`
+
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use serde_derive::{Deserialize, Serialize};
+
+/// Prefix for Unix domain socket paths in `listen`
+const UNIX_PREFIX: &str = "unix:";
+
+/// Somewhere to accept connections
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum ListenAddr {
+    /// `host:port`, e.g. `0.0.0.0:5000` or `[::]:5000`
+    Tcp(String),
+    /// `unix:/path/to/socket`, e.g. for a reverse proxy on the same machine
+    Unix(PathBuf),
+}
+
+impl From<String> for ListenAddr {
+    fn from(addr: String) -> Self {
+        match addr.strip_prefix(UNIX_PREFIX) {
+            Some(path) => Self::Unix(PathBuf::from(path)),
+            None => Self::Tcp(addr),
+        }
+    }
+}
+
+impl From<ListenAddr> for String {
+    fn from(addr: ListenAddr) -> String {
+        match addr {
+            ListenAddr::Tcp(addr) => addr,
+            ListenAddr::Unix(path) => {
+                format!("{}{}", UNIX_PREFIX, path.display())
+            }
+        }
+    }
+}
+
+/// Certificate and key for HTTPS, e.g.
+///
+/// ```toml
+/// [http.tls]
+/// cert = "/etc/led-foot/cert.pem"
+/// key = "/etc/led-foot/key.pem"
+/// listen = ["0.0.0.0:5443"]
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain
+    pub cert: PathBuf,
+    /// PEM file with the private key
+    pub key: PathBuf,
+    /// Addresses to serve HTTPS on
+    pub listen: Vec<String>,
+}
+
+/// The `[http]` section of `led_config.toml`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    /// Addresses to serve plain HTTP on
+    pub listen: Vec<ListenAddr>,
+    pub tls: Option<TlsConfig>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            listen: vec![ListenAddr::Tcp("0.0.0.0:5000".to_string())],
+            tls: None,
+        }
+    }
+}
+
+/// Load the certificate and key for HTTPS
+pub fn load_tls(config: &TlsConfig) -> Result<rustls::ServerConfig, Error> {
+    let open = |path: &Path| {
+        File::open(path).map(BufReader::new).map_err(|e| {
+            Error::new(e.kind(), format!("Unable to open {:?}: {}", path, e))
+        })
+    };
+
+    let certs = rustls_pemfile::certs(&mut open(&config.cert)?)
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("No certificates in {:?}", config.cert),
+        ));
+    }
+    let key = rustls_pemfile::private_key(&mut open(&config.key)?)?
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("No private key in {:?}", config.key),
+            )
+        })?;
+
+    rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+/// Remove a socket left over from the last run, which would stop binding to
+/// its path. Anything else at the path is left alone.
+#[cfg(unix)]
+pub fn remove_stale_socket(path: &Path) -> Result<(), Error> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listen_addrs() {
+        let config: HttpConfig = toml::from_str(
+            r#"listen = ["0.0.0.0:5000", "[::]:5000", "unix:/run/led-foot.sock"]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.listen,
+            vec![
+                ListenAddr::Tcp("0.0.0.0:5000".to_string()),
+                ListenAddr::Tcp("[::]:5000".to_string()),
+                ListenAddr::Unix(PathBuf::from("/run/led-foot.sock")),
+            ]
+        );
+        assert!(config.tls.is_none());
+
+        let config: HttpConfig = toml::from_str("").unwrap();
+        assert_eq!(config.listen, HttpConfig::default().listen);
+    }
+}
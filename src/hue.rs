@@ -0,0 +1,716 @@
+//! Emulates a Philips Hue bridge, so voice assistants that only speak Hue can
+//! control the LEDs
+//!
+//! The bridge is found over SSDP, then lights are controlled through the
+//! `/api/<username>/lights` subset of the Hue REST API. Light 1 is the whole
+//! strip, and lights 2-4 are the rooms; changing a room's color changes the
+//! strip's, since they all share it.
+
+use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpResponse};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::color::Color;
+use crate::led_sequence::LedSequence;
+use crate::led_state::{led_config, LedState, LED_STATE};
+use crate::network;
+use crate::rooms::Room;
+
+/// Where SSDP searches are multicast
+const SSDP_ADDR: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+const SSDP_PORT: u16 = 1900;
+
+/// Hue brightness and saturation go up to 254, not 255
+const HUE_MAX: f32 = 254.0;
+
+/// Hue API version the responses are modeled after
+const API_VERSION: &str = "1.16.0";
+
+/// Lights the bridge exposes, with ids starting from 1
+static LIGHTS: [(&str, Option<Room>); 4] = [
+    ("LED Strip", None),
+    ("Living Room", Some(Room::LivingRoom)),
+    ("Office", Some(Room::Office)),
+    ("Bedroom", Some(Room::Bedroom)),
+];
+
+/// Set in the `[hue]` section of `led_config.toml`:
+///
+/// ```toml
+/// [hue]
+/// enabled = true
+/// name = "LED Foot"
+/// port = 80
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HueConfig {
+    pub enabled: bool,
+    /// Name the bridge shows up as
+    pub name: String,
+    /// Port the server is reachable on. Voice assistants only look for
+    /// bridges on port 80, so forward it there if the server isn't on it.
+    pub port: u16,
+    /// IP address to advertise, if the detected one is wrong
+    pub advertise_ip: Option<IpAddr>,
+}
+
+impl Default for HueConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            name: "LED Foot".to_string(),
+            port: 5000,
+            advertise_ip: None,
+        }
+    }
+}
+
+impl HueConfig {
+    fn ip(&self) -> IpAddr {
+        self.advertise_ip.unwrap_or_else(network::local_ip)
+    }
+}
+
+/// Body of `PUT /api/<username>/lights/<id>/state`. Anything else Hue
+/// supports (transitions, alerts, effects) is ignored.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct HueStateUpdate {
+    pub on: Option<bool>,
+    /// Brightness, 1 to 254
+    pub bri: Option<u8>,
+    /// Hue, 0 to 65535 around the color wheel
+    pub hue: Option<u16>,
+    /// Saturation, 0 to 254
+    pub sat: Option<u8>,
+    /// Color temperature in mireds, 153 (cold) to 500 (warm)
+    pub ct: Option<u16>,
+    /// CIE xy color
+    pub xy: Option<[f32; 2]>,
+}
+
+fn gamma_expand(c: f32) -> f32 {
+    if c > 0.04045 {
+        ((c + 0.055) / 1.055).powf(2.4)
+    } else {
+        c / 12.92
+    }
+}
+
+fn gamma_compress(c: f32) -> f32 {
+    if c <= 0.0031308 {
+        12.92 * c
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Move the part of the color that all of r, g and b share onto the white
+/// LEDs, at full brightness
+fn extract_white(r: f32, g: f32, b: f32) -> Color {
+    let w = r.min(g).min(b).max(0.0);
+    Color::new(r - w, g - w, b - w, w).with_brightness(1.0)
+}
+
+/// Convert CIE xy (using the wide gamut Hue lights use) to a full brightness
+/// color
+pub fn xy_to_color(x: f32, y: f32) -> Color {
+    if y <= 0.0 {
+        return Color::new(0.0, 0.0, 0.0, 1.0);
+    }
+    let big_x = x / y;
+    let big_z = (1.0 - x - y) / y;
+
+    let r = big_x * 1.656492 - 0.354851 - big_z * 0.255038;
+    let g = -big_x * 0.707196 + 1.655397 + big_z * 0.036152;
+    let b = big_x * 0.051713 - 0.121364 + big_z * 1.01153;
+
+    let max = r.max(g).max(b);
+    if max <= 0.0 {
+        return Color::new(0.0, 0.0, 0.0, 1.0);
+    }
+    let compress = |c: f32| gamma_compress(c.max(0.0) / max);
+    extract_white(compress(r), compress(g), compress(b))
+}
+
+/// Convert a color to CIE xy, with the white LEDs counting as white light
+pub fn color_to_xy(color: &Color) -> [f32; 2] {
+    let expand = |c: f32| gamma_expand((c + color.w).clamp(0.0, 1.0));
+    let (r, g, b) = (expand(color.r), expand(color.g), expand(color.b));
+
+    let big_x = r * 0.664511 + g * 0.154324 + b * 0.162028;
+    let big_y = r * 0.283881 + g * 0.668433 + b * 0.047685;
+    let big_z = r * 0.000088 + g * 0.072310 + b * 0.986039;
+    let sum = big_x + big_y + big_z;
+    if sum <= 0.0 {
+        // D65 white
+        return [0.3127, 0.329];
+    }
+    [big_x / sum, big_y / sum]
+}
+
+/// Convert a color temperature in mireds to a full brightness color
+pub fn ct_to_color(mireds: u16) -> Color {
+    let kelvin = 1_000_000.0 / f32::from(mireds.clamp(153, 500));
+    let temp = kelvin / 100.0;
+
+    // Approximation of the black body color, 0 to 255
+    let r = if temp <= 66.0 {
+        255.0
+    } else {
+        329.69873 * (temp - 60.0).powf(-0.13320476)
+    };
+    let g = if temp <= 66.0 {
+        99.4708 * temp.ln() - 161.11957
+    } else {
+        288.12216 * (temp - 60.0).powf(-0.075514846)
+    };
+    let b = if temp >= 66.0 {
+        255.0
+    } else if temp <= 19.0 {
+        0.0
+    } else {
+        138.51773 * (temp - 10.0).ln() - 305.0448
+    };
+
+    let scale = |c: f32| c.clamp(0.0, 255.0) / 255.0;
+    extract_white(scale(r), scale(g), scale(b))
+}
+
+/// Hue (0 to 1) and saturation (0 to 1) of a color, ignoring white
+fn hue_saturation(color: &Color) -> (f32, f32) {
+    let max = color.r.max(color.g).max(color.b);
+    let min = color.r.min(color.g).min(color.b);
+    let chroma = max - min;
+    if max <= 0.0 || chroma <= 0.0 {
+        return (0.0, 0.0);
+    }
+
+    let hue = if max == color.r {
+        ((color.g - color.b) / chroma).rem_euclid(6.0)
+    } else if max == color.g {
+        (color.b - color.r) / chroma + 2.0
+    } else {
+        (color.r - color.g) / chroma + 4.0
+    };
+    (hue / 6.0, chroma / max)
+}
+
+/// The light with the given id, from the path
+fn light(id: &str) -> Option<(usize, &'static str, Option<&'static Room>)> {
+    let id = id.parse::<usize>().ok()?;
+    let (name, room) = LIGHTS.get(id.checked_sub(1)?)?;
+    Some((id, name, room.as_ref()))
+}
+
+fn light_json(
+    state: &LedState,
+    id: usize,
+    name: &str,
+    room: Option<&Room>,
+) -> Value {
+    let color = state.future_color();
+    let strip_on = color.brightness() > 0.0;
+    let on = strip_on && room.is_none_or(|r| state.current_rooms.is_on(r));
+
+    // While off, report the color it'll turn back on with
+    let color = if strip_on {
+        color
+    } else {
+        state
+            .last_on_color
+            .clone()
+            .unwrap_or_else(|| Color::new(0.0, 0.0, 0.0, 1.0))
+    };
+    let (hue, sat) = hue_saturation(&color);
+    let mac = network::mac_address();
+
+    json!({
+        "state": {
+            "on": on,
+            "bri": (color.brightness() * HUE_MAX).round().max(1.0) as u8,
+            "hue": (hue * 65535.0).round() as u16,
+            "sat": (sat * HUE_MAX).round() as u8,
+            "effect": "none",
+            "xy": color_to_xy(&color),
+            "ct": 366,
+            "alert": "none",
+            "colormode": "xy",
+            "mode": "homeautomation",
+            "reachable": true,
+        },
+        "type": "Extended color light",
+        "name": name,
+        "modelid": "LCT015",
+        "manufacturername": "Signify Netherlands B.V.",
+        "productname": "Hue color lamp",
+        "uniqueid": format!(
+            "{}:{}:{}:{}:{}:{}:00:{:02x}-0b",
+            &mac[0..2], &mac[2..4], &mac[4..6], &mac[6..8], &mac[8..10],
+            &mac[10..12], id,
+        ),
+        "swversion": "1.46.13_r26312",
+    })
+}
+
+fn lights_json(state: &LedState) -> Value {
+    LIGHTS
+        .iter()
+        .enumerate()
+        .map(|(i, (name, room))| {
+            let id = i + 1;
+            (id.to_string(), light_json(state, id, name, room.as_ref()))
+        })
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+fn config_json(config: &HueConfig) -> Value {
+    let mac = network::mac_address();
+    json!({
+        "name": config.name,
+        "bridgeid": bridge_id(&mac),
+        "mac": mac,
+        "ipaddress": config.ip(),
+        "modelid": "BSB002",
+        "apiversion": API_VERSION,
+        "swversion": "1935144040",
+        "linkbutton": true,
+        "dhcp": true,
+        "portalservices": false,
+    })
+}
+
+/// Bridge ids are the MAC address with FFFE in the middle
+fn bridge_id(mac: &str) -> String {
+    format!("{}FFFE{}", &mac[0..6], &mac[6..12]).to_uppercase()
+}
+
+/// Apply a light state change, returning the paths that changed for the
+/// response
+fn update_light(
+    state: &mut LedState,
+    id: usize,
+    room: Option<&Room>,
+    update: &HueStateUpdate,
+) -> Vec<(String, Value)> {
+    let target = state.future_color();
+    let strip_on = target.brightness() > 0.0;
+
+    if let (Some(room), Some(on)) = (room, update.on) {
+        state.current_rooms.set(room, on);
+    }
+
+    let color = if let Some([x, y]) = update.xy {
+        Some(xy_to_color(x, y))
+    } else if let Some(ct) = update.ct {
+        Some(ct_to_color(ct))
+    } else if update.hue.is_some() || update.sat.is_some() {
+        let (hue, sat) = hue_saturation(&target);
+        let hue = update.hue.map_or(hue, |h| f32::from(h) / 65535.0);
+        let sat = update.sat.map_or(sat, |s| f32::from(s) / HUE_MAX);
+        let rgb = Color::from_hsv(hue, sat, 1.0, 0.0);
+        Some(extract_white(rgb.r, rgb.g, rgb.b))
+    } else {
+        None
+    };
+    let brightness = update.bri.map(|bri| f32::from(bri.max(1)) / HUE_MAX);
+
+    let turn_off = room.is_none() && update.on == Some(false);
+    let turn_on = update.on == Some(true) && !strip_on;
+    if turn_off {
+        if strip_on {
+            state.last_on_color = Some(target);
+            state.playlist.clear();
+            state.current_sequence = Some(LedSequence::from_color_lerp(
+                &state.current_color,
+                &Color::default(),
+            ));
+        }
+    } else if turn_on || color.is_some() || brightness.is_some() {
+        let base = if strip_on {
+            target.clone()
+        } else {
+            state
+                .last_on_color
+                .clone()
+                .unwrap_or_else(|| Color::new(0.0, 0.0, 0.0, 1.0))
+        };
+        let new_color = color
+            .unwrap_or_else(|| base.clone())
+            .with_brightness(brightness.unwrap_or_else(|| base.brightness()));
+
+        if new_color != target {
+            state.playlist.clear();
+            state.current_sequence = Some(LedSequence::from_color_lerp(
+                &state.current_color,
+                &new_color,
+            ));
+        }
+    }
+
+    let path = |field: &str| format!("/lights/{}/state/{}", id, field);
+    let mut changed = Vec::new();
+    if let Some(on) = update.on {
+        changed.push((path("on"), json!(on)));
+    }
+    if let Some(bri) = update.bri {
+        changed.push((path("bri"), json!(bri)));
+    }
+    if let Some(hue) = update.hue {
+        changed.push((path("hue"), json!(hue)));
+    }
+    if let Some(sat) = update.sat {
+        changed.push((path("sat"), json!(sat)));
+    }
+    if let Some(ct) = update.ct {
+        changed.push((path("ct"), json!(ct)));
+    }
+    if let Some(xy) = update.xy {
+        changed.push((path("xy"), json!(xy)));
+    }
+    changed
+}
+
+/// Errors are sent with a 200 status, like a real bridge does
+fn hue_error(kind: u32, address: &str, description: &str) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .json(json!([{
+            "error": {
+                "type": kind,
+                "address": address,
+                "description": description,
+            }
+        }]))
+}
+
+/// UPnP description that SSDP responses point to
+async fn description() -> HttpResponse {
+    let led_config = led_config();
+    let config = &led_config.hue;
+    let ip = config.ip();
+    let mac = network::mac_address();
+    HttpResponse::Ok()
+        .content_type(ContentType::xml())
+        .body(format!(
+            r#"<?xml version="1.0" encoding="UTF-8" ?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+  <specVersion><major>1</major><minor>0</minor></specVersion>
+  <URLBase>http://{ip}:{port}/</URLBase>
+  <device>
+    <deviceType>urn:schemas-upnp-org:device:Basic:1</deviceType>
+    <friendlyName>{name} ({ip})</friendlyName>
+    <manufacturer>Signify</manufacturer>
+    <manufacturerURL>http://www.philips-hue.com</manufacturerURL>
+    <modelDescription>Philips hue Personal Wireless Lighting</modelDescription>
+    <modelName>Philips hue bridge 2015</modelName>
+    <modelNumber>BSB002</modelNumber>
+    <modelURL>http://www.philips-hue.com</modelURL>
+    <serialNumber>{mac}</serialNumber>
+    <UDN>uuid:2f402f80-da50-11e1-9b23-{mac}</UDN>
+    <presentationURL>index.html</presentationURL>
+  </device>
+</root>
+"#,
+            ip = ip,
+            port = config.port,
+            name = config.name,
+            mac = mac,
+        ))
+}
+
+/// Pair with the bridge. There's no link button to press, so anyone can.
+async fn create_user() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .json(json!([{ "success": { "username": "led-foot" } }]))
+}
+
+/// Everything the bridge knows about, for the username in the path
+async fn get_all() -> HttpResponse {
+    if let Ok(led_state) = LED_STATE.get().read() {
+        HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .json(json!({
+                "lights": lights_json(&led_state),
+                "groups": {},
+                "config": config_json(&led_config().hue),
+                "schedules": {},
+                "scenes": {},
+                "rules": {},
+                "sensors": {},
+                "resourcelinks": {},
+            }))
+    } else {
+        error!("Error on Hue /api/<username>: can't get lock on state");
+        HttpResponse::InternalServerError().into()
+    }
+}
+
+async fn get_config() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .json(config_json(&led_config().hue))
+}
+
+async fn get_groups() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .json(json!({}))
+}
+
+async fn get_lights() -> HttpResponse {
+    if let Ok(led_state) = LED_STATE.get().read() {
+        HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .json(lights_json(&led_state))
+    } else {
+        error!("Error on Hue /lights: can't get lock on state");
+        HttpResponse::InternalServerError().into()
+    }
+}
+
+async fn get_light(path: web::Path<(String, String)>) -> HttpResponse {
+    let (_, id) = path.into_inner();
+    let Some((id, name, room)) = light(&id) else {
+        let address = format!("/lights/{}", id);
+        return hue_error(3, &address, "resource not available");
+    };
+
+    if let Ok(led_state) = LED_STATE.get().read() {
+        HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .json(light_json(&led_state, id, name, room))
+    } else {
+        error!("Error on Hue /lights/{}: can't get lock on state", id);
+        HttpResponse::InternalServerError().into()
+    }
+}
+
+/// Turn a light on or off, or change its brightness or color
+///
+/// Takes over from the playlist, if one is running. The body is parsed by
+/// hand, since some clients don't send a JSON content type.
+async fn set_light_state(
+    path: web::Path<(String, String)>,
+    body: web::Bytes,
+) -> HttpResponse {
+    let (_, id) = path.into_inner();
+    let Some((id, _, room)) = light(&id) else {
+        let address = format!("/lights/{}/state", id);
+        return hue_error(3, &address, "resource not available");
+    };
+    let update: HueStateUpdate = match serde_json::from_slice(&body) {
+        Ok(update) => update,
+        Err(e) => {
+            let address = format!("/lights/{}/state", id);
+            return hue_error(
+                2,
+                &address,
+                &format!("body contains invalid JSON: {}", e),
+            );
+        }
+    };
+    debug!("Hue light {} state: {:?}", id, update);
+
+    if let Ok(mut led_state) = LED_STATE.get().write() {
+        let changed = update_light(&mut led_state, id, room, &update);
+        HttpResponse::Ok().content_type(ContentType::json()).json(
+            changed
+                .into_iter()
+                .map(|(path, value)| json!({ "success": { path: value } }))
+                .collect::<Vec<_>>(),
+        )
+    } else {
+        error!("Error on Hue /lights/{}/state: can't get lock on state", id);
+        HttpResponse::InternalServerError().into()
+    }
+}
+
+/// Add the bridge's routes. Register these after the rest of `/api`, since
+/// `/api/{username}` matches anything.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/description.xml", web::get().to(description))
+        .route("/api", web::post().to(create_user))
+        .route("/api/{username}", web::get().to(get_all))
+        .route("/api/{username}/config", web::get().to(get_config))
+        .route("/api/{username}/groups", web::get().to(get_groups))
+        .route("/api/{username}/lights", web::get().to(get_lights))
+        .route("/api/{username}/lights/{id}", web::get().to(get_light))
+        .route(
+            "/api/{username}/lights/{id}/state",
+            web::put().to(set_light_state),
+        );
+}
+
+/// Answer SSDP searches on a separate thread, if enabled, so the bridge can
+/// be discovered
+pub fn start_discovery(config: &HueConfig) -> Result<(), std::io::Error> {
+    if !config.enabled {
+        return Ok(());
+    }
+
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, SSDP_PORT))?;
+    socket.join_multicast_v4(&SSDP_ADDR, &Ipv4Addr::UNSPECIFIED)?;
+
+    let mac = network::mac_address();
+    let location =
+        format!("http://{}:{}/description.xml", config.ip(), config.port);
+    info!("Advertising Hue bridge at {}", location);
+
+    std::thread::spawn(move || {
+        let mut buf = [0; 2048];
+        loop {
+            let (len, from) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) => {
+                    error!("Unable to receive SSDP packet: {}", e);
+                    continue;
+                }
+            };
+
+            let request = String::from_utf8_lossy(&buf[..len]);
+            let Some(search_target) = ssdp_search_target(&request) else {
+                continue;
+            };
+            trace!("SSDP search for {} from {}", search_target, from);
+
+            let response = ssdp_response(&location, &mac, search_target);
+            if let Err(e) = socket.send_to(response.as_bytes(), from) {
+                warn!("Unable to answer SSDP search from {}: {}", from, e);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// The search target to answer with, if `request` is a search for a bridge
+fn ssdp_search_target(request: &str) -> Option<&'static str> {
+    if !request.starts_with("M-SEARCH") || !request.contains("ssdp:discover") {
+        return None;
+    }
+    let target = request
+        .lines()
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.trim()
+                .eq_ignore_ascii_case("ST")
+                .then_some(value.trim())
+        })?
+        .to_lowercase();
+
+    match target.as_str() {
+        "ssdp:all" | "urn:schemas-upnp-org:device:basic:1" => {
+            Some("urn:schemas-upnp-org:device:basic:1")
+        }
+        "upnp:rootdevice" => Some("upnp:rootdevice"),
+        _ => None,
+    }
+}
+
+fn ssdp_response(location: &str, mac: &str, search_target: &str) -> String {
+    format!(
+        "HTTP/1.1 200 OK\r\n\
+         HOST: {addr}:{port}\r\n\
+         CACHE-CONTROL: max-age=100\r\n\
+         EXT:\r\n\
+         LOCATION: {location}\r\n\
+         SERVER: Linux/3.14.0 UPnP/1.0 IpBridge/1.16.0\r\n\
+         hue-bridgeid: {bridge_id}\r\n\
+         ST: {search_target}\r\n\
+         USN: uuid:2f402f80-da50-11e1-9b23-{mac}::{search_target}\r\n\
+         \r\n",
+        addr = SSDP_ADDR,
+        port = SSDP_PORT,
+        location = location,
+        bridge_id = bridge_id(mac),
+        search_target = search_target,
+        mac = mac,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: &Color, b: &Color) {
+        for (x, y) in [(a.r, b.r), (a.g, b.g), (a.b, b.b), (a.w, b.w)] {
+            assert!((x - y).abs() < 0.01, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_color_conversions() {
+        for color in [
+            Color::new(1.0, 0.0, 0.0, 0.0),
+            Color::new(0.0, 1.0, 0.0, 0.0),
+            Color::new(0.0, 0.0, 1.0, 0.0),
+            Color::new(1.0, 0.5, 0.0, 0.0),
+            Color::new(0.0, 0.0, 0.0, 1.0),
+        ] {
+            let [x, y] = color_to_xy(&color);
+            assert_close(&xy_to_color(x, y), &color);
+        }
+
+        // Cold white is mostly the white LEDs, warm white adds red
+        let cold = ct_to_color(153);
+        assert!(cold.w > 0.99 && cold.r < 0.05);
+        let warm = ct_to_color(500);
+        assert!(warm.r > 0.5 && warm.b == 0.0);
+    }
+
+    #[test]
+    fn test_update_light() {
+        let mut state = LedState::default();
+
+        // Turning a room on turns the strip on too
+        let update: HueStateUpdate =
+            serde_json::from_str(r#"{"on": true, "bri": 127}"#).unwrap();
+        let changed = update_light(&mut state, 3, Some(&Room::Office), &update);
+        assert!(state.current_rooms.office);
+        assert_close(&state.future_color(), &Color::new(0.0, 0.0, 0.0, 0.5));
+        assert_eq!(changed[0].0, "/lights/3/state/on");
+
+        let update: HueStateUpdate =
+            serde_json::from_str(r#"{"xy": [0.7006, 0.2993]}"#).unwrap();
+        update_light(&mut state, 1, None, &update);
+        assert_close(&state.future_color(), &Color::new(0.5, 0.0, 0.0, 0.0));
+
+        let update: HueStateUpdate =
+            serde_json::from_str(r#"{"on": false}"#).unwrap();
+        update_light(&mut state, 1, None, &update);
+        assert_eq!(state.future_color(), Color::default());
+        // Kept in the state, so WLED turns it back on with the same color
+        assert_close(
+            state.last_on_color.as_ref().unwrap(),
+            &Color::new(0.5, 0.0, 0.0, 0.0),
+        );
+    }
+
+    #[test]
+    fn test_ssdp_search() {
+        let search = "M-SEARCH * HTTP/1.1\r\n\
+                      HOST: 239.255.255.250:1900\r\n\
+                      MAN: \"ssdp:discover\"\r\n\
+                      MX: 3\r\n\
+                      ST: urn:schemas-upnp-org:device:basic:1\r\n\r\n";
+        assert_eq!(
+            ssdp_search_target(search),
+            Some("urn:schemas-upnp-org:device:basic:1")
+        );
+        assert_eq!(ssdp_search_target(&search.replace("basic:1", "foo")), None);
+
+        let response = ssdp_response(
+            "http://10.0.0.2:80/description.xml",
+            "b827ebaabbcc",
+            "upnp:rootdevice",
+        );
+        assert!(response.contains("hue-bridgeid: B827EBFFFEAABBCC\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
+    }
+}
@@ -1,39 +1,538 @@
-use toml::Table;
-use std::fs;
+//! Configuration, layered from defaults, `led_config.toml`, `LED_FOOT__*`
+//! environment variables and command line flags
 
-const LED_CONFIG_PATH: &str = "led_config.toml";
+use std::fmt;
+use std::path::{Path, PathBuf};
 
-/// Configuration for LEDs, which is loaded on system startup
-// TODO: Use config crate
-#[derive(Debug)]
-pub struct LedConfig {
+use serde_derive::{Deserialize, Serialize};
+
+use crate::auth::AuthConfig;
+use crate::color::Color;
+use crate::dmx::DmxConfig;
+use crate::dmx_receiver::DmxInputConfig;
+use crate::http_server::{HttpConfig, ListenAddr};
+use crate::hue::HueConfig;
+use crate::led_output::OutputKind;
+use crate::led_sequence::{RESOLUTION, SEQUENCE_PATH};
+use crate::protocol::MAX_ACK_WINDOW;
+use crate::rooms::Rooms;
+use crate::scenes::Scenes;
+use crate::schedules::Schedule;
+use crate::shutdown::{ShutdownConfig, ShutdownPolicy};
+
+/// Config file used when none is given
+pub const LED_CONFIG_PATH: &str = "led_config.toml";
+
+/// Prefix for environment variables that override the config file, with
+/// `__` between sections and keys, e.g. `LED_FOOT__SERIAL__TTY_NAME`
+const ENV_PREFIX: &str = "LED_FOOT";
+
+/// Top level keys from before `[serial]`, still read for old config files
+const LEGACY_SERIAL_KEYS: [&str; 2] = ["tty_name", "dither"];
+
+/// Top level keys from old config files that nothing reads any more
+const UNUSED_KEYS: [&str; 1] = ["sequence_resolution"];
+
+/// The `[serial]` section, for the Arduino
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SerialConfig {
     pub tty_name: String,
-    pub sequence_resolution: f32,
+    /// Temporally dither colors into the 16-bit PWM levels
+    pub dither: bool,
+    /// Has to match `BAUD_RATE` in the firmware
+    pub baud_rate: u32,
+    /// Frames that can be sent before waiting for the Arduino to acknowledge
+    /// them, with protocol v2. 1 waits for each frame's reply.
+    pub ack_window: u8,
+    /// Have the Arduino play fades by itself rather than sending every
+    /// frame, with protocol v2
+    pub firmware_fades: bool,
+}
+
+impl Default for SerialConfig {
+    fn default() -> Self {
+        Self {
+            tty_name: "/dev/ttyACM0".to_string(),
+            dither: true,
+            baud_rate: 9600,
+            ack_window: 1,
+            firmware_fades: true,
+        }
+    }
+}
+
+/// The `[defaults]` section, for what the LEDs do when the server starts
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DefaultsConfig {
+    /// Color to fade in to
+    pub color: Option<Color>,
+    /// Room relays to switch on or off
+    pub rooms: Option<Rooms>,
+    /// Scene to apply, after the color and rooms
+    pub scene: Option<String>,
+}
+
+/// The `[paths]` section, for files the server reads. Relative paths are from
+/// the working directory.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PathsConfig {
+    /// Folder with the sequences, scripts and audio files
+    pub sequences: PathBuf,
+    /// Folder to serve `/static` from instead of the files built into the
+    /// server, e.g. while working on the web UI
+    pub static_dir: Option<PathBuf>,
+}
+
+impl Default for PathsConfig {
+    fn default() -> Self {
+        Self {
+            sequences: PathBuf::from(SEQUENCE_PATH),
+            static_dir: None,
+        }
+    }
 }
 
+/// Where the config comes from, kept so a reload reads it the same way
+#[derive(Debug, Clone, Default)]
+pub struct ConfigSource {
+    /// File given on the command line, instead of `led_config.toml`
+    pub path: Option<PathBuf>,
+    /// Values from command line flags, on top of the file and environment
+    pub overrides: Vec<(String, config::Value)>,
+}
+
+impl ConfigSource {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        Self {
+            path,
+            overrides: Vec::new(),
+        }
+    }
+
+    /// Override a value, by its key in the file (e.g. `http.listen`)
+    pub fn set(&mut self, key: &str, value: impl Into<config::Value>) {
+        self.overrides.push((key.to_string(), value.into()));
+    }
+}
+
+/// Configuration for LEDs, which is loaded on system startup and can be
+/// reloaded while running (see `reload`)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LedConfig {
+    pub serial: SerialConfig,
+    pub paths: PathsConfig,
+    pub outputs: Vec<OutputKind>,
+    pub dmx: DmxConfig,
+    pub dmx_input: DmxInputConfig,
+    pub hue: HueConfig,
+    pub http: HttpConfig,
+    pub auth: AuthConfig,
+    pub defaults: DefaultsConfig,
+    pub shutdown: ShutdownConfig,
+    pub scenes: Scenes,
+    pub schedules: Vec<Schedule>,
+}
+
+impl Default for LedConfig {
+    fn default() -> Self {
+        Self {
+            serial: SerialConfig::default(),
+            paths: PathsConfig::default(),
+            outputs: vec![OutputKind::Serial],
+            dmx: DmxConfig::default(),
+            dmx_input: DmxInputConfig::default(),
+            hue: HueConfig::default(),
+            http: HttpConfig::default(),
+            auth: AuthConfig::default(),
+            defaults: DefaultsConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            scenes: Scenes::default(),
+            schedules: Vec::new(),
+        }
+    }
+}
+
+/// Why the config couldn't be loaded
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The file couldn't be read or parsed, or a value has the wrong type
+    Load(config::ConfigError),
+    /// Everything parsed, but some values don't make sense
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Load(e) => write!(f, "Unable to load config: {}", e),
+            ConfigError::Invalid(problems) => {
+                write!(f, "Invalid config:")?;
+                for problem in problems {
+                    write!(f, "\n  - {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<config::ConfigError> for ConfigError {
+    fn from(e: config::ConfigError) -> Self {
+        ConfigError::Load(e)
+    }
+}
+
+/// What changed when reloading the config
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReloadSummary {
+    /// Sections that changed and took effect
+    pub applied: Vec<String>,
+    /// Sections that changed, but only take effect after a restart
+    pub needs_restart: Vec<String>,
+}
 
 impl LedConfig {
-    /// Load from default config path on initialization
-    pub fn new() -> LedConfig {
-        let config_string = if let Ok(res) = fs::read_to_string(LED_CONFIG_PATH) {
-            res
-        } else {
-            warn!("Unable to load config file, {}; empty config file created", LED_CONFIG_PATH);
-            if let Err(e) = fs::write(LED_CONFIG_PATH, "") {
-                warn!("Unable to create empty config file {}: {}", LED_CONFIG_PATH, e);
+    /// Load from the source's path (or `led_config.toml` if there's no path),
+    /// with environment variables then overrides on top. The file is
+    /// optional unless a path is given.
+    pub fn load(source: &ConfigSource) -> Result<LedConfig, ConfigError> {
+        let path = source.path.as_deref();
+        let file = path
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|| PathBuf::from(LED_CONFIG_PATH));
+        if path.is_none() && !file.exists() {
+            info!("No config file at {:?}, using defaults", file);
+        }
+
+        let mut builder = config::Config::builder()
+            .add_source(
+                config::File::from(file.as_path())
+                    .format(config::FileFormat::Toml)
+                    .required(path.is_some()),
+            )
+            .add_source(
+                config::Environment::with_prefix(ENV_PREFIX)
+                    .prefix_separator("__")
+                    .separator("__")
+                    .try_parsing(true)
+                    .list_separator(",")
+                    .with_list_parse_key("outputs"),
+            );
+        for (key, value) in &source.overrides {
+            builder = builder.set_override(key, value.clone())?;
+        }
+        let settings = builder.build()?;
+
+        let mut cfg: LedConfig = settings.clone().try_deserialize()?;
+
+        // The config crate lowercases keys, so scene names are read straight
+        // from the file to keep them case-sensitive
+        if file.exists() {
+            if let Some(scenes) = read_scenes(&file)? {
+                cfg.scenes = scenes;
             }
-            String::new()
-        };
+        }
 
+        for key in UNUSED_KEYS {
+            if settings.get::<config::Value>(key).is_ok() {
+                warn!(
+                    "`{}` in the config is ignored, sequences always play at \
+                     {} frames a second",
+                    key, RESOLUTION
+                );
+            }
+        }
 
-        let toml_config = config_string.parse::<Table>().expect(&format!("Unable to parse TOML config, {}", LED_CONFIG_PATH));
-        let cfg = LedConfig {
-            tty_name: toml_config.get("tty_name").and_then(|v| v.as_str()).unwrap_or("/dev/ttyACM0").to_string(),
-            sequence_resolution: toml_config.get("sequence_resolution").and_then(|v| v.as_float()).unwrap_or(30.0) as f32,
-        };
+        // Old configs had the serial settings at the top level
+        for key in LEGACY_SERIAL_KEYS {
+            let Ok(value) = settings.get::<config::Value>(key) else {
+                continue;
+            };
+            warn!("`{}` in the config should move to `[serial]`", key);
+            if settings
+                .get::<config::Value>(&format!("serial.{}", key))
+                .is_ok()
+            {
+                continue;
+            }
+            match key {
+                "tty_name" => cfg.serial.tty_name = value.into_string()?,
+                _ => cfg.serial.dither = value.into_bool()?,
+            }
+        }
 
+        cfg.validate().map_err(ConfigError::Invalid)?;
         debug!("Loaded LedConfig {:?}", cfg);
+        Ok(cfg)
+    }
+
+    /// Check the values that parse but don't make sense, returning all the
+    /// problems found
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = Vec::new();
+
+        if let Some(ref dir) = self.paths.static_dir {
+            if !dir.is_dir() {
+                problems.push(format!(
+                    "`paths.static_dir`: {:?} isn't a directory",
+                    dir
+                ));
+            }
+        }
+        if self.outputs.is_empty() {
+            problems.push("`outputs` can't be empty".to_string());
+        }
+        if self.outputs.contains(&OutputKind::Serial)
+            && self.serial.tty_name.is_empty()
+        {
+            problems.push("`serial.tty_name` can't be empty".to_string());
+        }
+        if self.serial.baud_rate == 0 {
+            problems.push("`serial.baud_rate` must be above 0".to_string());
+        }
+        if !(1..=MAX_ACK_WINDOW).contains(&self.serial.ack_window) {
+            problems.push(format!(
+                "`serial.ack_window` must be 1-{}",
+                MAX_ACK_WINDOW
+            ));
+        }
+
+        // R, G, B and W (coarse and fine if 16-bit), then the rooms
+        let channels = |sixteen_bit: bool, rooms: bool| {
+            (if sixteen_bit { 8 } else { 4 }) + (if rooms { 3 } else { 0 })
+        };
+        for (section, address, channels) in [
+            (
+                "dmx",
+                self.dmx.address,
+                channels(self.dmx.sixteen_bit, self.dmx.rooms),
+            ),
+            (
+                "dmx_input",
+                self.dmx_input.address,
+                channels(self.dmx_input.sixteen_bit, self.dmx_input.rooms),
+            ),
+        ] {
+            if address == 0 || usize::from(address) + channels - 1 > 512 {
+                problems.push(format!(
+                    "`{}.address` must leave room for {} channels in 1-512",
+                    section, channels
+                ));
+            }
+        }
+        if self.dmx_input.timeout <= 0.0 {
+            problems.push("`dmx_input.timeout` must be above 0".to_string());
+        }
 
-        cfg
+        if self.http.listen.is_empty() && self.http.tls.is_none() {
+            problems
+                .push("`http.listen` can't be empty without TLS".to_string());
+        }
+        if cfg!(not(unix))
+            && self
+                .http
+                .listen
+                .iter()
+                .any(|addr| matches!(addr, ListenAddr::Unix(_)))
+        {
+            problems.push("`http.listen`: no Unix sockets here".to_string());
+        }
+        if let Some(ref tls) = self.http.tls {
+            for file in [&tls.cert, &tls.key] {
+                if !file.is_file() {
+                    problems.push(format!(
+                        "`http.tls`: file {:?} doesn't exist",
+                        file
+                    ));
+                }
+            }
+            if tls.listen.is_empty() {
+                problems.push("`http.tls.listen` can't be empty".to_string());
+            }
+        }
+
+        if self.auth.enabled
+            && self.auth.tokens.is_empty()
+            && self.auth.users.is_empty()
+            && self.auth.trusted_networks.is_empty()
+        {
+            problems.push(
+                "`auth` is enabled without tokens, users or trusted networks"
+                    .to_string(),
+            );
+        }
+        if self.auth.session_hours <= 0.0 {
+            problems.push("`auth.session_hours` must be above 0".to_string());
+        }
+
+        if self.shutdown.fade_secs < 0.0 {
+            problems.push("`shutdown.fade_secs` can't be negative".to_string());
+        }
+        let mut scene_names = Vec::new();
+        if let Some(ref scene) = self.defaults.scene {
+            scene_names.push(("defaults.scene".to_string(), scene));
+        }
+        match self.shutdown.scene {
+            Some(ref scene) => {
+                scene_names.push(("shutdown.scene".to_string(), scene))
+            }
+            None if self.shutdown.policy == ShutdownPolicy::Scene => problems
+                .push("`shutdown.scene` is needed for the scene policy".into()),
+            None => {}
+        }
+        for (i, schedule) in self.schedules.iter().enumerate() {
+            let key = format!("schedules[{}]", i);
+            if let Err(e) = schedule.time() {
+                problems.push(format!("`{}.at`: {}", key, e));
+            }
+            if let Err(e) = schedule.weekdays() {
+                problems.push(format!("`{}.days`: {}", key, e));
+            }
+            scene_names.push((format!("{}.scene", key), &schedule.scene));
+        }
+        for (key, scene) in scene_names {
+            if !self.scenes.contains_key(scene) {
+                problems.push(format!("`{}`: no scene named {:?}", key, scene));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
     }
-}
\ No newline at end of file
+
+    /// Take the sections of `new` that can change while running, keeping the
+    /// rest (which are only read on startup) as they are
+    pub fn reload(&self, new: LedConfig) -> (LedConfig, ReloadSummary) {
+        let mut merged = self.clone();
+        let mut summary = ReloadSummary::default();
+
+        macro_rules! section {
+            ($field:ident, live) => {
+                if self.$field != new.$field {
+                    summary.applied.push(stringify!($field).to_string());
+                    merged.$field = new.$field;
+                }
+            };
+            ($field:ident, restart) => {
+                if self.$field != new.$field {
+                    summary.needs_restart.push(stringify!($field).to_string());
+                }
+            };
+        }
+
+        section!(scenes, live);
+        section!(schedules, live);
+        section!(auth, live);
+        section!(shutdown, live);
+        section!(defaults, restart);
+        section!(serial, restart);
+        section!(paths, restart);
+        section!(outputs, restart);
+        section!(dmx, restart);
+        section!(dmx_input, restart);
+        section!(hue, restart);
+        section!(http, restart);
+
+        (merged, summary)
+    }
+}
+
+/// The `[scenes]` section of a config file, with the names as written
+fn read_scenes(file: &Path) -> Result<Option<Scenes>, ConfigError> {
+    #[derive(Deserialize)]
+    struct ScenesOnly {
+        scenes: Option<Scenes>,
+    }
+
+    let parse_error = |e: Box<dyn std::error::Error + Send + Sync>| {
+        config::ConfigError::FileParse {
+            uri: Some(file.to_string_lossy().into_owned()),
+            cause: e,
+        }
+    };
+    let text =
+        std::fs::read_to_string(file).map_err(|e| parse_error(Box::new(e)))?;
+    let only: ScenesOnly =
+        toml::from_str(&text).map_err(|e| parse_error(Box::new(e)))?;
+    Ok(only.scenes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scenes::Scene;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn test_validate() {
+        let mut cfg = LedConfig::default();
+        assert_eq!(cfg.validate(), Ok(()));
+
+        cfg.dmx.address = 510;
+        cfg.schedules.push(Schedule {
+            at: "25:00".to_string(),
+            days: Vec::new(),
+            scene: "missing".to_string(),
+        });
+        let problems = cfg.validate().unwrap_err();
+        assert_eq!(problems.len(), 3);
+        assert!(problems[0].contains("dmx.address"));
+        assert!(problems[1].contains("schedules[0].at"));
+        assert!(problems[2].contains("no scene named \"missing\""));
+    }
+
+    #[test]
+    fn test_reload() {
+        let old = LedConfig::default();
+        let mut new = old.clone();
+        new.scenes.insert("movie".to_string(), Scene::default());
+        new.serial.tty_name = "/dev/ttyUSB0".to_string();
+
+        let (merged, summary) = old.reload(new);
+        assert_eq!(summary.applied, vec!["scenes"]);
+        assert_eq!(summary.needs_restart, vec!["serial"]);
+        assert!(merged.scenes.contains_key("movie"));
+        assert_eq!(merged.serial.tty_name, "/dev/ttyACM0");
+    }
+
+    #[test]
+    fn test_scene_names_keep_case() {
+        let dir = TestDir::new("config");
+        let path = dir.join("config.toml");
+        std::fs::write(
+            &path,
+            "[scenes.Movie]\ntransition = 5.0\n\n\
+             [[schedules]]\nat = \"20:00\"\nscene = \"Movie\"\n",
+        )
+        .unwrap();
+
+        let cfg = LedConfig::load(&ConfigSource::new(Some(path))).unwrap();
+
+        assert!(cfg.scenes.contains_key("Movie"));
+        assert!(!cfg.scenes.contains_key("movie"));
+        assert_eq!(cfg.schedules[0].scene, "Movie");
+    }
+
+    #[test]
+    fn test_old_config_still_loads() {
+        let dir = TestDir::new("config");
+        let path = dir.join("config.toml");
+        std::fs::write(
+            &path,
+            "tty_name = \"/dev/ttyUSB0\"\nsequence_resolution = 60.0\n",
+        )
+        .unwrap();
+
+        let cfg = LedConfig::load(&ConfigSource::new(Some(path))).unwrap();
+        assert_eq!(cfg.serial.tty_name, "/dev/ttyUSB0");
+    }
+}
@@ -0,0 +1,116 @@
+//! Destinations for the colors and room states the LedSystem produces
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::color::Color;
+use crate::dmx_output::DmxOutput;
+use crate::led_config::LedConfig;
+use crate::mock_output::MockOutput;
+use crate::rooms::Rooms;
+use crate::sequence_source::FadeSegment;
+use crate::serial_manager::SerialManager;
+
+/// Something that can show colors and switch room relays
+pub trait LedOutput: Send + Sync {
+    /// Show a single frame's color
+    fn send_color(&mut self, color: &Color);
+
+    /// Switch the room relays
+    fn send_rooms(&mut self, rooms: &Rooms);
+
+    /// Play fades from `start` on the device itself, instead of being sent
+    /// each frame. Returns whether the output took them; the next
+    /// `send_color` stops them.
+    fn start_fade(
+        &mut self,
+        _start: &Color,
+        _segments: &[FadeSegment],
+    ) -> bool {
+        false
+    }
+
+    /// Whether the fade taken by the last `start_fade` stopped early, e.g.
+    /// because the device refused it or had to be reconnected, so colors
+    /// need sending each frame again
+    fn fade_failed(&self) -> bool {
+        false
+    }
+
+    /// How the output is doing
+    fn status(&self) -> OutputStatus;
+
+    /// Show the final color and rooms, then let go of the device, when the
+    /// server shuts down
+    fn close(&mut self, color: &Color, rooms: &Rooms) {
+        self.send_color(color);
+        self.send_rooms(rooms);
+    }
+
+    /// Everything known about how the output is doing, for
+    /// `/api/diagnostics`
+    fn diagnostics(&self) -> OutputDiagnostics {
+        OutputDiagnostics {
+            status: self.status(),
+            device: None,
+            last_handshake_secs: None,
+            replies: Vec::new(),
+        }
+    }
+}
+
+/// How an output is doing, for diagnostics
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutputStatus {
+    /// Which kind of output this is
+    pub kind: OutputKind,
+    /// Is the output able to show colors right now?
+    pub connected: bool,
+    /// Human-readable details, like the device name or last error
+    pub detail: String,
+}
+
+/// More about an output than `OutputStatus`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutputDiagnostics {
+    #[serde(flatten)]
+    pub status: OutputStatus,
+    /// Device or address the output sends to
+    pub device: Option<String>,
+    /// Seconds since the output last completed a handshake with its device
+    pub last_handshake_secs: Option<f64>,
+    /// Latest replies from the device, oldest first
+    pub replies: Vec<String>,
+}
+
+/// Kinds of outputs that can be selected in `led_config.toml`, e.g.
+///
+/// ```toml
+/// outputs = ["serial", "dmx"]
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputKind {
+    /// Arduino connected over USB serial
+    Serial,
+    /// Prints colors to the terminal, for running without hardware
+    Mock,
+    /// Art-Net or sACN over UDP, set up in the `[dmx]` section
+    Dmx,
+}
+
+/// Create the outputs listed in the config
+pub fn create_outputs(config: &LedConfig) -> Vec<Box<dyn LedOutput>> {
+    config
+        .outputs
+        .iter()
+        .map(|kind| -> Box<dyn LedOutput> {
+            match kind {
+                OutputKind::Serial => {
+                    Box::new(SerialManager::new(&config.serial))
+                }
+                OutputKind::Mock => Box::new(MockOutput::new()),
+                OutputKind::Dmx => Box::new(DmxOutput::new(&config.dmx)),
+            }
+        })
+        .collect()
+}
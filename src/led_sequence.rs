@@ -1,11 +1,22 @@
-use std::collections::VecDeque;
 use std::fs::File;
 use std::io::Read;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use serde_derive::{Deserialize, Serialize};
 
+use crate::audio::{AudioParams, AudioSource};
 use crate::color::Color;
+use crate::effects::{
+    self, BreatheParams, CandleParams, HueWheelParams, LightningParams,
+    PastelDriftParams, StrobeParams,
+};
+use crate::led_state::{led_config, LED_CONFIG};
+use crate::script::{ScriptParams, ScriptSource, SCRIPT_EXTENSION};
+use crate::sequence_source::{
+    ChainSource, ColorPointsSource, FadeSegment, FadeToBlackSource, LerpSource,
+    PixelGradientSource, SequenceSource,
+};
 
 /// 30 "frames" per second for smoothness
 pub const RESOLUTION: f32 = 30.0;
@@ -13,88 +24,201 @@ pub const RESOLUTION: f32 = 30.0;
 /// How long the initial fade between sequences should be
 pub const FADE_DURATION: f32 = 0.5;
 
+/// How long a transition from one color to another takes to play
+const LERP_DURATION: f32 = 1.0;
+
 /// Median filter size for initial
 const MEDIAN_FILTER_SIZE: usize = 51;
 
-/// Folder where all LED sequences are located
+/// Folder where all LED sequences are located, unless `[paths]` says
+/// otherwise
 pub const SEQUENCE_PATH: &str = "led-foot-sequences";
 
+/// Folder the sequences are in: from the server's config, or the default
+/// without one (e.g. in `led-foot-cli`)
+pub fn sequence_dir() -> PathBuf {
+    match LED_CONFIG.try_get() {
+        Some(_) => led_config().paths.sequences.clone(),
+        None => PathBuf::from(SEQUENCE_PATH),
+    }
+}
+
+/// Path of the file `name` in `dir`, which has to be a plain file name, so
+/// requests can't read files from anywhere else
+pub fn plain_file(dir: &Path, name: &str) -> Result<PathBuf, std::io::Error> {
+    if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid file name {:?}", name),
+        ));
+    }
+    Ok(dir.join(name))
+}
+
+/// Paths of the sequences in `sequence_dir()` that can be played by name,
+/// sorted so they keep the same order between calls
+pub fn list_sequences() -> Result<Vec<String>, std::io::Error> {
+    let mut sequences = std::fs::read_dir(sequence_dir())?
+        .filter_map(|e| e.ok())
+        .map(|p| p.path().to_string_lossy().into_owned())
+        .filter(|p| p.ends_with(".png") || p.ends_with(".rhai"))
+        .collect::<Vec<_>>();
+    sequences.sort();
+    Ok(sequences)
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum LedSequenceType {
     Color,
     Gradient,
+    Breathe(BreatheParams),
+    Candle(CandleParams),
+    HueWheel(HueWheelParams),
+    PastelDrift(PastelDriftParams),
+    Lightning(LightningParams),
+    Strobe(StrobeParams),
+    Script(ScriptParams),
+    Audio(AudioParams),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LedSequenceInfo {
     pub sequence_type: LedSequenceType,
+    #[serde(default)]
     pub name: String,
+    #[serde(default)]
     pub duration: f32,
+    #[serde(default)]
     pub repeat: bool,
 }
 
-#[derive(Debug, Deserialize)]
+/// Sequence stored as json; effects only need the `info`
+#[derive(Debug, Serialize, Deserialize)]
 pub struct LedColorPoints {
+    #[serde(default)]
     pub color_points: Vec<Color>,
+    #[serde(default)]
     pub percent_points: Vec<f32>,
     pub info: LedSequenceInfo,
 }
 
+/// A sequence that's playing on the LEDs
+///
+/// Colors are generated from `source` as each frame is played, so cloning a
+/// sequence is cheap and memory use doesn't depend on how long it is
 #[derive(Debug, Clone)]
 pub struct LedSequence {
-    pub colors: VecDeque<Color>,
+    source: Arc<dyn SequenceSource>,
     pub info: LedSequenceInfo,
     index: usize,
     repeat_start: usize,
+    loops: usize,
+    /// What the source's colors are scaled by, from 0.0 to 1.0
+    brightness: f32,
 }
 
 impl LedSequence {
+    /// Load a sequence by the name the API uses for it, either a path to a
+    /// sequence in `sequence_dir()` or the magic `fade-to-black-<duration>`
+    pub fn from_name(
+        fade_from: &Color,
+        name: &str,
+    ) -> Result<Self, std::io::Error> {
+        // TODO: This is hacky - check if the name includes the magic phrase
+        if name.starts_with("fade-to-black-") {
+            let mut tokens = name.split('-');
+            debug!("Parsed fade to black tokens {:?}", tokens);
+            if let Some(Ok(duration)) =
+                tokens.next_back().map(|t| t.parse::<f32>())
+            {
+                debug!("Fading to black {}s", duration);
+                Ok(Self::fade_to_black(fade_from, duration))
+            } else {
+                debug!("Fading to black defaulted to {}s", FADE_DURATION);
+                Ok(Self::fade_to_black(fade_from, FADE_DURATION))
+            }
+        } else if let Some(script) = Path::new(name)
+            .file_stem()
+            .filter(|_| name.ends_with(SCRIPT_EXTENSION))
+        {
+            let info = LedSequenceInfo {
+                sequence_type: LedSequenceType::Script(ScriptParams {
+                    name: script.to_string_lossy().into_owned(),
+                    params: serde_json::Value::Null,
+                }),
+                name: script.to_string_lossy().into_owned(),
+                duration: 0.0,
+                repeat: true,
+            };
+            Self::from_effect(fade_from, info)
+        } else {
+            let seq_path = name.replace("png", "json");
+            debug!("Sequence path: {:?}", seq_path);
+            // TODO: Fix this on the javascript side (generate the colors from
+            // the json)
+            Self::from_color_points(fade_from, Path::new(&seq_path))
+        }
+    }
+
     /// Linearly interpolate between two colors, for the default duration and
     /// resolution
     pub fn from_color_lerp(start_color: &Color, end_color: &Color) -> Self {
-        let mut colors = VecDeque::with_capacity(RESOLUTION as usize);
+        let source = LerpSource {
+            start: start_color.clone(),
+            end: end_color.clone(),
+            duration: LERP_DURATION,
+        };
 
-        for i in 0..=(RESOLUTION as usize) {
-            let percent = i as f32 / RESOLUTION;
-            colors.push_back(start_color.lerp(&end_color, percent));
-        }
+        Self::new(
+            Arc::new(source),
+            LedSequenceInfo {
+                sequence_type: LedSequenceType::Color,
+                name: "lerp".to_string(),
+                duration: FADE_DURATION,
+                repeat: false,
+            },
+        )
+    }
 
-        let mut sequence = Self::default();
-        sequence.colors = colors;
-        sequence.info = LedSequenceInfo {
-            sequence_type: LedSequenceType::Color,
-            name: "lerp".to_string(),
-            duration: FADE_DURATION,
-            repeat: false,
+    /// Linearly interpolate between two colors over `duration` seconds
+    pub fn from_color_transition(
+        start_color: &Color,
+        end_color: &Color,
+        duration: f32,
+    ) -> Self {
+        let source = LerpSource {
+            start: start_color.clone(),
+            end: end_color.clone(),
+            duration,
         };
 
-        sequence
+        Self::new(
+            Arc::new(source),
+            LedSequenceInfo {
+                sequence_type: LedSequenceType::Color,
+                name: "lerp".to_string(),
+                duration,
+                repeat: false,
+            },
+        )
     }
 
     /// Fade from a start color to black, over a duration
     pub fn fade_to_black(start_color: &Color, duration: f32) -> Self {
-        let num_elements = (duration * RESOLUTION) as usize;
-        let end_color = Color::new(0.0, 0.0, 0.0, 0.0);
-        let mut colors = VecDeque::with_capacity(num_elements);
-
-        for i in 0..=(num_elements) {
-            let percent = i as f32 / (duration * RESOLUTION);
-            // Adjust the next color's white value to fade quicker to black
-            let mut next_color = start_color.lerp(&end_color, percent);
-            next_color.w = start_color.w - start_color.w * percent.cbrt();
-            colors.push_back(next_color);
-        }
-
-        let mut sequence = Self::default();
-        sequence.colors = colors;
-        sequence.info = LedSequenceInfo {
-            sequence_type: LedSequenceType::Color,
-            name: "fade-to-black".to_string(),
+        let source = FadeToBlackSource {
+            start: start_color.clone(),
             duration,
-            repeat: false,
         };
 
-        sequence
+        Self::new(
+            Arc::new(source),
+            LedSequenceInfo {
+                sequence_type: LedSequenceType::Color,
+                name: "fade-to-black".to_string(),
+                duration,
+                repeat: false,
+            },
+        )
     }
 
     /// Load a gradient or single colour from a png file
@@ -103,104 +227,96 @@ impl LedSequence {
     ///
     /// Name the files according to this convention:
     ///
-    /// ```
+    /// ```text
     /// <color/gradient>_<name>_<duration?>_<repeat?>.png
     /// ```
     ///
     /// Examples:
     ///
-    /// ```
+    /// ```text
     /// color_red.png
     ///
     /// gradient_sunrise_600.png
     ///
     /// gradient_rainbow_20_repeat.png
     /// ```
-    pub fn from_png(fade_from: &Color, img_path: &Path) -> Self {
+    ///
+    /// Each column is a point in time, from left to right. The top half of
+    /// the image holds the RGB colors, and the white component is read from
+    /// the first row of the bottom half (`height / 2`), as its gray level (the
+    /// red channel, for color images). `color_` images only use the first
+    /// column.
+    ///
+    /// Any png color type and bit depth can be used: palette and grayscale
+    /// images are expanded to RGB, alpha is ignored, and 16-bit images keep
+    /// their full precision.
+    pub fn from_png(
+        fade_from: &Color,
+        img_path: &Path,
+    ) -> Result<Self, std::io::Error> {
+        let invalid_name = || {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "{:?} is not named color_<name>.png or \
+                     gradient_<name>_<duration>[_repeat].png",
+                    img_path
+                ),
+            )
+        };
+
         let name = img_path
             .file_stem()
-            .expect("Unable to extract file name")
-            .to_str()
-            .expect("Invalid_filename")
-            .to_string();
+            .and_then(|n| n.to_str())
+            .ok_or_else(invalid_name)?;
 
         let tokens: Vec<_> = name.split('_').collect();
-        assert!(tokens.len() > 1 && tokens.len() < 5);
-
-        let info = if tokens[0] == "color" {
-            assert_eq!(tokens.len(), 2);
-            LedSequenceInfo {
+        let info = match tokens[..] {
+            ["color", name] => LedSequenceInfo {
                 sequence_type: LedSequenceType::Color,
-                name: tokens[1].to_string(),
+                name: name.to_string(),
                 duration: 0.0,
                 repeat: false,
+            },
+            ["gradient", name, duration, ref rest @ ..]
+                if matches!(rest, [] | ["repeat"]) =>
+            {
+                LedSequenceInfo {
+                    sequence_type: LedSequenceType::Gradient,
+                    name: name.to_string(),
+                    duration: duration
+                        .parse::<f32>()
+                        .ok()
+                        .filter(|d| *d > 0.0)
+                        .ok_or_else(invalid_name)?,
+                    repeat: !rest.is_empty(),
+                }
             }
-        } else if tokens[0] == "gradient" {
-            assert!(tokens.len() > 2);
-            let duration = tokens[2]
-                .parse::<f32>()
-                .expect("Unable to parse gradient duration");
-            LedSequenceInfo {
-                sequence_type: LedSequenceType::Gradient,
-                name: tokens[1].to_string(),
-                duration,
-                repeat: tokens.contains(&"repeat"),
-            }
-        } else {
-            unreachable!("Incorrect first token in png file name");
+            _ => return Err(invalid_name()),
         };
 
-        let decoder =
-            png::Decoder::new(File::open(img_path).unwrap_or_else(|_| {
-                panic!("Unable to open sequence file {:?}", img_path)
-            }));
-
-        let mut reader = decoder.read_info().expect("Unable to decode png");
-        let mut buf = vec![0; reader.output_buffer_size()];
-        reader.next_frame(&mut buf).unwrap();
-
-        let width = reader.info().width;
-        let height = reader.info().height;
-
-        let first_white_index =
-            3 * width as usize * (height as usize / 2) as usize;
-
-        match info.sequence_type {
-            LedSequenceType::Color => {
-                let raw_color =
-                    [buf[0], buf[1], buf[2], buf[first_white_index]];
-                let first_color = Color::from(&raw_color);
-                Self::from_color_lerp(fade_from, &first_color)
-            }
-            LedSequenceType::Gradient => {
-                let mut colors = VecDeque::with_capacity(width as usize);
-                for i in (0..(width as usize * 3)).step_by(3) {
-                    let raw_color = [
-                        buf[i],
-                        buf[i + 1],
-                        buf[i + 2],
-                        buf[i + first_white_index],
-                    ];
-                    let color_i = Color::from(&raw_color);
-                    colors.push_back(color_i);
-                }
-
-                let sequence = Self {
-                    colors,
-                    info,
-                    index: 0,
-                    repeat_start: 0,
-                }
-                .smooth_colors()
-                .resample();
-
-                let initial_fade =
-                    Self::from_color_lerp(fade_from, &sequence.colors[0]);
-                let fade_len = initial_fade.colors.len();
-
-                initial_fade.chain(sequence).with_repeat_start(fade_len)
-            }
+        let image = PngImage::read(img_path).map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!("Unable to decode png {:?}: {}", img_path, e),
+            )
+        })?;
+        let white_row = image.height / 2;
+
+        if matches!(info.sequence_type, LedSequenceType::Color) {
+            let first_color = image.color(0, white_row);
+            return Ok(Self::from_color_lerp(fade_from, &first_color));
         }
+
+        let pixels = (0..image.width)
+            .map(|x| image.color(x, white_row))
+            .collect();
+        let source = PixelGradientSource::new(
+            smooth_colors(pixels),
+            info.duration,
+            RESOLUTION,
+        );
+        Ok(Self::new(Arc::new(source), info).with_initial_fade(fade_from))
     }
 
     pub fn from_color_points(
@@ -213,44 +329,66 @@ impl LedSequence {
         let points: LedColorPoints = serde_json::from_str(&contents)?;
 
         match points.info.sequence_type {
+            LedSequenceType::Color | LedSequenceType::Gradient
+                if points.color_points.is_empty() =>
+            {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{:?} has no color points", points_path),
+                ))
+            }
             LedSequenceType::Color => {
                 Ok(Self::from_color_lerp(fade_from, &points.color_points[0]))
             }
             LedSequenceType::Gradient => {
-                let num_samples = (RESOLUTION * points.info.duration) as usize;
-
-                let mut colors = VecDeque::with_capacity(num_samples);
-                let mut color_index = 0;
-                for sample_index in 0..=num_samples {
-                    let overall_percent =
-                        sample_index as f32 / num_samples as f32;
-                    let lerp_percent = 1.0
-                        - ((points.percent_points[color_index + 1]
-                            - overall_percent)
-                            / (points.percent_points[color_index + 1]
-                                - points.percent_points[color_index]));
-
-                    colors.push_back(points.color_points[color_index].lerp(
-                        &points.color_points[color_index + 1],
-                        lerp_percent,
-                    ));
-
-                    if overall_percent >= points.percent_points[color_index + 1]
-                    {
-                        color_index += 1;
-                    }
-                }
+                let source = ColorPointsSource {
+                    color_points: points.color_points,
+                    percent_points: points.percent_points,
+                    duration: points.info.duration,
+                };
+
+                Ok(Self::new(Arc::new(source), points.info)
+                    .with_initial_fade(fade_from))
+            }
+            _ => Self::from_effect(fade_from, points.info),
+        }
+    }
 
-                let mut sequence = Self::default();
-                sequence.info = points.info;
-                sequence.colors = colors;
+    /// Start one of the built-in effects, a script or audio-reactive colors,
+    /// which loops until something else is played
+    pub fn from_effect(
+        fade_from: &Color,
+        mut info: LedSequenceInfo,
+    ) -> Result<Self, std::io::Error> {
+        let source: Arc<dyn SequenceSource> = match info.sequence_type {
+            LedSequenceType::Script(ref script) => {
+                Arc::new(ScriptSource::load(script, info.duration)?)
+            }
+            LedSequenceType::Audio(ref audio) => {
+                Arc::new(AudioSource::start(audio)?)
+            }
+            _ => effects::effect_source(&info.sequence_type, info.duration)
+                .ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("{:?} is not an effect", info.sequence_type),
+                    )
+                })?,
+        };
 
-                let initial_fade =
-                    Self::from_color_lerp(fade_from, &sequence.colors[0]);
-                let fade_len = initial_fade.colors.len();
+        info.duration = source.duration();
+        info.repeat = true;
+        Ok(Self::new(source, info).with_initial_fade(fade_from))
+    }
 
-                Ok(initial_fade.chain(sequence).with_repeat_start(fade_len))
-            }
+    fn new(source: Arc<dyn SequenceSource>, info: LedSequenceInfo) -> Self {
+        Self {
+            source,
+            info,
+            index: 0,
+            repeat_start: 0,
+            loops: 0,
+            brightness: 1.0,
         }
     }
 
@@ -260,9 +398,115 @@ impl LedSequence {
         self
     }
 
+    /// Scale the sequence's colors from here on, carrying on from the same
+    /// frame
+    pub fn set_brightness(&mut self, brightness: f32) {
+        self.brightness = brightness.clamp(0.0, 1.0);
+    }
+
+    /// Move on by `frames` frames without working out their colors, as if
+    /// `next()` had been called that many times. A sequence that doesn't
+    /// repeat stops on its last frame, so it still ends on its last color.
+    pub fn skip_frames(&mut self, frames: usize) {
+        if frames == 0 {
+            return;
+        }
+        let frame_count = self.frame_count();
+        if !self.info.repeat || self.repeat_start >= frame_count {
+            if self.index < frame_count {
+                self.index = (self.index + frames).min(frame_count - 1);
+            }
+            return;
+        }
+
+        if self.index >= frame_count {
+            self.index = self.repeat_start;
+            self.loops += 1;
+        }
+        // The last frame skipped, if the sequence didn't loop back
+        let last = self.index + frames - 1;
+        if last < frame_count {
+            self.index = last + 1;
+        } else {
+            let loop_len = frame_count - self.repeat_start;
+            let past_end = last - frame_count;
+            self.loops += past_end / loop_len + 1;
+            self.index = self.repeat_start + past_end % loop_len + 1;
+        }
+    }
+
+    /// How many times a repeating sequence has looped back to its start
+    pub fn loop_count(&self) -> usize {
+        self.loops
+    }
+
+    /// Number of frames in one play-through of the sequence
+    pub fn frame_count(&self) -> usize {
+        let frames = (self.source.duration() * RESOLUTION).round() as usize;
+        if self.info.repeat {
+            // The first frame of the next loop takes the place of the end
+            frames
+        } else {
+            frames + 1
+        }
+    }
+
+    /// Color at `t` seconds into the sequence, including the initial fade
+    pub fn color_at(&self, t: f32) -> Color {
+        self.source.color_at(t) * self.brightness
+    }
+
+    /// Seconds one play-through takes, including the initial fade
+    pub fn duration(&self) -> f32 {
+        self.source.duration()
+    }
+
+    /// Seconds the fade in from the previous color takes, before the
+    /// sequence itself starts
+    pub fn initial_fade_duration(&self) -> f32 {
+        self.repeat_start as f32 / RESOLUTION
+    }
+
+    /// The color the sequence ends on
+    pub fn final_color(&self) -> Color {
+        self.color_at(self.source.duration())
+    }
+
+    /// Hasn't played any frames yet
+    pub fn is_new(&self) -> bool {
+        self.index == 0 && self.loops == 0
+    }
+
+    /// The sequence as linear fades from its first color, for outputs that
+    /// can play them by themselves. `None` if it repeats, or isn't only made
+    /// of fades.
+    pub fn fade_segments(&self) -> Option<Vec<FadeSegment>> {
+        if self.info.repeat {
+            return None;
+        }
+        let mut segments = self.source.fade_segments()?;
+        for segment in &mut segments {
+            segment.color = segment.color.clone() * self.brightness;
+        }
+        Some(segments)
+    }
+
+    /// Smoothly fade in from a color, without repeating the fade
+    fn with_initial_fade(self, fade_from: &Color) -> Self {
+        let initial_fade =
+            Self::from_color_lerp(fade_from, &self.source.color_at(0.0));
+        let fade_len =
+            (initial_fade.source.duration() * RESOLUTION).round() as usize;
+
+        initial_fade.chain(self).with_repeat_start(fade_len)
+    }
+
     /// Chain two LED sequences together, consuming both
     fn chain(mut self, other: LedSequence) -> Self {
-        self.colors.extend(other.colors);
+        self.source = Arc::new(ChainSource {
+            first: self.source,
+            second: other.source,
+        });
         self.info = {
             let mut inf = other.info.clone();
             inf.duration = self.info.duration + other.info.duration;
@@ -270,99 +514,427 @@ impl LedSequence {
         };
         self
     }
+}
 
-    /// Downsample (or upsample) the gradient so it is smooth (say, 30 frames
-    /// per second)
-    ///
-    /// Uses a tent filter to obtain a resampled gradient
-    fn resample(mut self) -> Self {
-        let num_samples = RESOLUTION * self.info.duration;
-        let filter_size =
-            (self.colors.len() as f32 / num_samples.round()).round() as isize;
-
-        // Hack to allow upsampling
-        let filter_size = if filter_size < 3 { 3 } else { filter_size };
-
-        let mut new_colors = VecDeque::with_capacity(num_samples as usize);
-
-        for i in 0..(num_samples as usize) {
-            let percent = i as f32 / num_samples;
-            let center_index = (percent * (self.colors.len() as f32)) as isize;
-
-            let mut sum = Color::default();
-            let mut counted = 0;
-            for filter_index in (-filter_size / 2)..(filter_size / 2) {
-                // Absolute value function to mimic tent
-                let tent_value = 1.0
-                    - ((filter_index * 2) as f32 / filter_size as f32).abs();
-
-                let png_index = filter_index + center_index;
-                if png_index >= 0 && png_index < self.colors.len() as isize {
-                    sum = sum
-                        + self.colors[png_index as usize].clone() * tent_value;
-                    counted += 1;
-                }
+/// Decoded png, with every color type and bit depth normalized to RGB(A)
+/// or gray(alpha) samples of 8 or 16 bits
+struct PngImage {
+    width: usize,
+    height: usize,
+    /// Samples per pixel
+    channels: usize,
+    /// Bytes per sample, 1 or 2 (big endian)
+    sample_size: usize,
+    line_size: usize,
+    buf: Vec<u8>,
+}
+
+impl PngImage {
+    fn read(path: &Path) -> Result<Self, std::io::Error> {
+        let mut decoder = png::Decoder::new(File::open(path)?);
+        // Palette to RGB, low bit depth grayscale to 8 bits, and
+        // transparency chunks to alpha
+        decoder.set_transformations(png::Transformations::EXPAND);
+
+        let mut reader = decoder.read_info()?;
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let frame = reader.next_frame(&mut buf)?;
+
+        let channels = match frame.color_type {
+            png::ColorType::Grayscale => 1,
+            png::ColorType::GrayscaleAlpha => 2,
+            png::ColorType::Rgb => 3,
+            png::ColorType::Rgba => 4,
+            png::ColorType::Indexed => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "Palette was not expanded",
+                ))
             }
-            let avg = sum / (counted as f32 / 2.0);
-            new_colors.push_back(avg.clamped());
-        }
-        self.colors = new_colors;
-        self
+        };
+        let sample_size = match frame.bit_depth {
+            png::BitDepth::Sixteen => 2,
+            _ => 1,
+        };
+
+        Ok(Self {
+            width: frame.width as usize,
+            height: frame.height as usize,
+            channels,
+            sample_size,
+            line_size: frame.line_size,
+            buf,
+        })
     }
 
-    /// Use a median filter to eliminate noise, consuming self
-    ///
-    /// Useful for gradients from png images, which tend to have noise
-    /// Also cuts out values <= 1 and makes them 0 to avoid color stuttering
-    fn smooth_colors(mut self) -> Self {
-        let mut r_filter = median::Filter::new(MEDIAN_FILTER_SIZE);
-        let mut g_filter = median::Filter::new(MEDIAN_FILTER_SIZE);
-        let mut b_filter = median::Filter::new(MEDIAN_FILTER_SIZE);
-        let mut w_filter = median::Filter::new(MEDIAN_FILTER_SIZE);
-
-        let mut new_colors = VecDeque::with_capacity(self.colors.len());
-        for color in self.colors {
-            let new_r = r_filter.consume(color.r);
-            let new_g = g_filter.consume(color.g);
-            let new_b = b_filter.consume(color.b);
-            let new_w = w_filter.consume(color.w);
-            new_colors.push_back(Color::new(new_r, new_g, new_b, new_w));
+    /// Sample `channel` of the pixel at `x`, `y`, from 0.0 to 1.0
+    fn sample(&self, x: usize, y: usize, channel: usize) -> f32 {
+        let i = y * self.line_size
+            + (x * self.channels + channel) * self.sample_size;
+        if self.sample_size == 2 {
+            f32::from(u16::from_be_bytes([self.buf[i], self.buf[i + 1]]))
+                / f32::from(u16::MAX)
+        } else {
+            f32::from(self.buf[i]) / f32::from(u8::MAX)
         }
-
-        self.colors = new_colors;
-        self
     }
-}
 
-impl Default for LedSequence {
-    fn default() -> Self {
-        Self {
-            colors: VecDeque::new(),
-            info: LedSequenceInfo {
-                sequence_type: LedSequenceType::Color,
-                name: "default".to_string(),
-                duration: 0.0,
-                repeat: false,
-            },
-            index: 0,
-            repeat_start: 0,
+    /// RGB from column `x` of the top row, and white from the same column of
+    /// `white_row`
+    fn color(&self, x: usize, white_row: usize) -> Color {
+        let w = self.sample(x, white_row, 0);
+        if self.channels < 3 {
+            let gray = self.sample(x, 0, 0);
+            Color::new(gray, gray, gray, w)
+        } else {
+            Color::new(
+                self.sample(x, 0, 0),
+                self.sample(x, 0, 1),
+                self.sample(x, 0, 2),
+                w,
+            )
         }
     }
 }
 
+/// Use a median filter to eliminate noise
+///
+/// Useful for gradients from png images, which tend to have noise
+fn smooth_colors(colors: Vec<Color>) -> Vec<Color> {
+    let mut r_filter = median::Filter::new(MEDIAN_FILTER_SIZE);
+    let mut g_filter = median::Filter::new(MEDIAN_FILTER_SIZE);
+    let mut b_filter = median::Filter::new(MEDIAN_FILTER_SIZE);
+    let mut w_filter = median::Filter::new(MEDIAN_FILTER_SIZE);
+
+    colors
+        .into_iter()
+        .map(|color| {
+            let new_r = r_filter.consume(color.r);
+            let new_g = g_filter.consume(color.g);
+            let new_b = b_filter.consume(color.b);
+            let new_w = w_filter.consume(color.w);
+            Color::new(new_r, new_g, new_b, new_w)
+        })
+        .collect()
+}
+
 impl Iterator for &mut LedSequence {
     type Item = Color;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.index >= self.colors.len() {
-            if self.info.repeat {
+        let frame_count = self.frame_count();
+        if self.index >= frame_count {
+            if self.info.repeat && self.repeat_start < frame_count {
                 self.index = self.repeat_start;
+                self.loops += 1;
             } else {
                 return None;
             }
         }
-        let color = self.colors[self.index].clone();
+        let color = self.color_at(self.index as f32 / RESOLUTION);
         self.index += 1;
         Some(color)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+    use std::io::BufWriter;
+
+    /// Write a 4 x 2 png whose top row is `top` and bottom row is `bottom`
+    /// (one pixel's worth of samples, repeated)
+    fn write_png(
+        dir: &TestDir,
+        name: &str,
+        color_type: png::ColorType,
+        bit_depth: png::BitDepth,
+        top: &[u8],
+        bottom: &[u8],
+        palette: Option<Vec<u8>>,
+    ) -> std::path::PathBuf {
+        let path = dir.join(name);
+
+        let file = BufWriter::new(File::create(&path).unwrap());
+        let mut encoder = png::Encoder::new(file, 4, 2);
+        encoder.set_color(color_type);
+        encoder.set_depth(bit_depth);
+        if let Some(palette) = palette {
+            encoder.set_palette(palette);
+        }
+        let mut writer = encoder.write_header().unwrap();
+        let data = [top.repeat(4), bottom.repeat(4)].concat();
+        writer.write_image_data(&data).unwrap();
+        path
+    }
+
+    fn assert_color(name: &str, color: Color, expected: Color) {
+        for (a, b) in [
+            (color.r, expected.r),
+            (color.g, expected.g),
+            (color.b, expected.b),
+            (color.w, expected.w),
+        ] {
+            assert!(
+                (a - b).abs() < 1e-6,
+                "{}: {:?} != {:?}",
+                name,
+                color,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_fade_segments() {
+        let start = Color::new(1.0, 0.5, 0.0, 1.0);
+        let seq = LedSequence::fade_to_black(&start, 2.0);
+        let segments = seq.fade_segments().unwrap();
+        let mut t = 0.0;
+        for segment in &segments {
+            t += segment.duration;
+            assert_color(
+                "fade to black",
+                seq.color_at(t),
+                segment.color.clone(),
+            );
+        }
+        assert!((t - 2.0).abs() < 1e-5);
+        assert_color(
+            "end",
+            segments.last().unwrap().color.clone(),
+            Color::default(),
+        );
+
+        let points = ColorPointsSource {
+            color_points: vec![start.clone(), Color::default(), start.clone()],
+            percent_points: vec![0.25, 0.5, 1.0],
+            duration: 4.0,
+        };
+        let seq = LedSequence::new(
+            Arc::new(points),
+            LedSequenceInfo {
+                sequence_type: LedSequenceType::Gradient,
+                name: "points".to_string(),
+                duration: 4.0,
+                repeat: false,
+            },
+        )
+        .with_initial_fade(&Color::default());
+        let segments = seq.fade_segments().unwrap();
+        // The initial fade, holding the first point, then the fades between
+        let durations: Vec<_> = segments.iter().map(|s| s.duration).collect();
+        assert_eq!(durations, vec![LERP_DURATION, 1.0, 1.0, 2.0, 0.0]);
+        assert_color(
+            "midway",
+            seq.color_at(LERP_DURATION + 1.5),
+            Color::new(0.5, 0.25, 0.0, 0.5),
+        );
+
+        assert!(seq.is_new());
+        let mut repeating = seq;
+        repeating.info.repeat = true;
+        assert!(repeating.fade_segments().is_none());
+    }
+
+    #[test]
+    fn test_plain_file() {
+        let dir = Path::new("sequences");
+        assert_eq!(
+            plain_file(dir, "sunrise.wav").unwrap(),
+            dir.join("sunrise.wav")
+        );
+        for name in ["", ".rhai", "../secret", "a/b.png", "a\\b.png"] {
+            let error = plain_file(dir, name).unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn test_png_names() {
+        // Names are checked before the file is opened
+        for name in [
+            "red.png",
+            "color_red_blue.png",
+            "gradient_sunrise.png",
+            "gradient_sunrise_soon.png",
+            "gradient_sunrise_600_forever.png",
+        ] {
+            let error =
+                LedSequence::from_png(&Color::default(), Path::new(name))
+                    .unwrap_err();
+            assert_eq!(
+                error.kind(),
+                std::io::ErrorKind::InvalidInput,
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_png_color_types() {
+        let dir = TestDir::new("png");
+        let black = Color::default();
+        let load = |path: &Path| {
+            LedSequence::from_png(&black, path).unwrap().final_color()
+        };
+
+        // 16-bit keeps more precision than 8 bits could
+        let path = write_png(
+            &dir,
+            "color_rgb16.png",
+            png::ColorType::Rgb,
+            png::BitDepth::Sixteen,
+            &[0x03, 0xE8, 0x80, 0x00, 0xFF, 0xFF],
+            &[0x12, 0x34, 0, 0, 0, 0],
+            None,
+        );
+        assert_color(
+            "rgb16",
+            load(&path),
+            Color::new(
+                1000.0 / 65535.0,
+                32768.0 / 65535.0,
+                1.0,
+                4660.0 / 65535.0,
+            ),
+        );
+
+        // Alpha is ignored
+        let path = write_png(
+            &dir,
+            "color_rgba8.png",
+            png::ColorType::Rgba,
+            png::BitDepth::Eight,
+            &[255, 0, 51, 0],
+            &[102, 0, 0, 255],
+            None,
+        );
+        assert_color("rgba8", load(&path), Color::new(1.0, 0.0, 0.2, 0.4));
+
+        let path = write_png(
+            &dir,
+            "color_gray8.png",
+            png::ColorType::Grayscale,
+            png::BitDepth::Eight,
+            &[51],
+            &[255],
+            None,
+        );
+        assert_color("gray8", load(&path), Color::new(0.2, 0.2, 0.2, 1.0));
+
+        let path = write_png(
+            &dir,
+            "color_grayalpha16.png",
+            png::ColorType::GrayscaleAlpha,
+            png::BitDepth::Sixteen,
+            &[0xFF, 0xFF, 0, 0],
+            &[0x80, 0x00, 0xFF, 0xFF],
+            None,
+        );
+        assert_color(
+            "grayalpha16",
+            load(&path),
+            Color::new(1.0, 1.0, 1.0, 32768.0 / 65535.0),
+        );
+
+        let path = write_png(
+            &dir,
+            "color_palette.png",
+            png::ColorType::Indexed,
+            png::BitDepth::Eight,
+            &[1],
+            &[0],
+            Some(vec![153, 153, 153, 255, 0, 102]),
+        );
+        assert_color("palette", load(&path), Color::new(1.0, 0.0, 0.4, 0.6));
+    }
+
+    #[test]
+    fn test_empty_color_points() {
+        let dir = TestDir::new("points");
+        for sequence_type in ["Color", "Gradient"] {
+            let path = dir.join(format!("{}.json", sequence_type));
+            let json = format!(
+                r#"{{"info": {{"sequence_type": "{}"}}}}"#,
+                sequence_type
+            );
+            std::fs::write(&path, json).unwrap();
+            let err = LedSequence::from_color_points(&Color::default(), &path)
+                .unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        }
+    }
+
+    /// Counts how many colors are worked out
+    #[derive(Debug, Default)]
+    struct CountingSource {
+        calls: std::sync::atomic::AtomicUsize,
+    }
+
+    impl SequenceSource for CountingSource {
+        fn color_at(&self, t: f32) -> Color {
+            self.calls
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            Color::new(t, 0.0, 0.0, 0.0)
+        }
+
+        fn duration(&self) -> f32 {
+            2.0
+        }
+    }
+
+    fn counting_sequence(
+        source: &Arc<CountingSource>,
+        repeat: bool,
+    ) -> LedSequence {
+        let info = LedSequenceInfo {
+            sequence_type: LedSequenceType::Color,
+            name: "counting".to_string(),
+            duration: 2.0,
+            repeat,
+        };
+        LedSequence::new(source.clone(), info).with_repeat_start(15)
+    }
+
+    #[test]
+    fn test_skip() {
+        for repeat in [false, true] {
+            for frames in [0, 1, 14, 15, 44, 45, 46, 100, 1000] {
+                let source = Arc::new(CountingSource::default());
+                let mut skipped = counting_sequence(&source, repeat);
+                let mut taken = counting_sequence(&source, repeat);
+                for _ in 0..3 {
+                    skipped.skip_frames(frames);
+                    let expected = (&mut taken).take(frames + 1).last();
+                    assert_eq!((&mut skipped).next(), expected, "{}", frames);
+                    assert_eq!(skipped.loop_count(), taken.loop_count());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_skipped_frames_are_not_generated() {
+        use crate::frame_clock::{FakeClock, FrameClock};
+        use std::sync::atomic::Ordering;
+        use std::time::Duration;
+
+        let source = Arc::new(CountingSource::default());
+        let mut seq = counting_sequence(&source, true);
+        let mut clock = FrameClock::new(FakeClock::default(), RESOLUTION);
+        let mut frames_due = 1;
+        for sent in 1..=20 {
+            seq.skip_frames(frames_due as usize - 1);
+            assert!((&mut seq).next().is_some());
+            assert_eq!(source.calls.load(Ordering::Relaxed), sent);
+
+            // Sending takes much longer than a frame
+            clock.clock_mut().advance(Duration::from_millis(500));
+            frames_due = clock.wait_for_next_frame();
+            assert!(frames_due > 1);
+        }
+    }
+}
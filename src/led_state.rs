@@ -1,21 +1,33 @@
-use std::sync::{Condvar, Mutex, RwLock};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::time::Instant;
 
+use serde_derive::{Deserialize, Serialize};
 use state::InitCell;
 
 use crate::color::Color;
-use crate::led_config::LedConfig;
+use crate::frame_clock::FrameStats;
+use crate::led_config::{
+    ConfigError, ConfigSource, LedConfig, ReloadSummary,
+};
+use crate::led_output::{self, LedOutput, OutputStatus};
 use crate::led_sequence::LedSequence;
+use crate::playlist::Playlist;
 use crate::rooms::Rooms;
-use crate::serial_manager::SerialManager;
 
 /// Overall state that the LEDs are in
 pub static LED_STATE: InitCell<RwLock<LedState>> = InitCell::new();
-/// Initially loaded configuration for the LEDs
-pub static LED_CONFIG: InitCell<LedConfig> = InitCell::new();
+/// Configuration for the LEDs, replaced when it's reloaded (see
+/// `led_config()`)
+pub static LED_CONFIG: InitCell<RwLock<Arc<LedConfig>>> = InitCell::new();
+/// Where the config was loaded from, to reload it the same way
+pub static CONFIG_SOURCE: InitCell<ConfigSource> = InitCell::new();
 /// Management of LEDs active vs. not
 pub static LED_ACTIVE: InitCell<(Mutex<bool>, Condvar)> = InitCell::new();
-/// Communication with serial device
-pub static SERIAL_MANAGER: InitCell<RwLock<SerialManager>> = InitCell::new();
+/// When the server started
+pub static STARTED: InitCell<Instant> = InitCell::new();
+/// Everywhere the colors and room states are sent (serial device, etc.)
+pub static LED_OUTPUTS: InitCell<RwLock<Vec<Box<dyn LedOutput>>>> =
+    InitCell::new();
 
 #[derive(Debug, Clone, Default)]
 pub struct LedState {
@@ -28,11 +40,101 @@ pub struct LedState {
     /// Current sequence the LEDs are running, if any
     pub current_sequence: Option<LedSequence>,
 
+    /// Sequences queued up to play after each other
+    pub playlist: Playlist,
+
+    /// Color to go back to when the strip is turned on again from the Hue or
+    /// WLED APIs, which both keep on/off separately from the color
+    pub last_on_color: Option<Color>,
+
+    /// Latest frame from lighting software, which takes over from the
+    /// sequence while frames keep arriving
+    pub live_frame: Option<LiveFrame>,
+
+    /// How well the LED worker is keeping to its frame deadlines
+    pub frame_stats: FrameStats,
+
+    /// When the LED worker last went around its loop
+    pub worker_heartbeat: Option<Instant>,
+
+    /// Has the `[shutdown]` policy started? Live DMX input and schedules are
+    /// ignored from then on, so they can't override its fade or scene.
+    pub shutting_down: bool,
+
     /// Is the system in the process of shutting down?
     pub shutdown: bool,
 }
 
-pub fn init_global_state() {
+impl LedState {
+    /// The color the LEDs will be on once a transition in progress is done
+    pub fn future_color(&self) -> Color {
+        match self.current_sequence {
+            // If it's a transition / one-off sequence, use the last element
+            Some(ref seq) if !seq.info.repeat => seq.final_color(),
+            // Otherwise use the current color and hope for the best
+            _ => self.current_color.clone(),
+        }
+    }
+}
+
+/// Overview of what the LEDs are doing, from `/api/get-status`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LedStatus {
+    pub color: Color,
+    /// Color once the current transition is done
+    pub future_color: Color,
+    pub rooms: Rooms,
+    /// Name of the sequence that's running, if any
+    pub sequence: Option<String>,
+    pub playlist: Playlist,
+    /// Is lighting software controlling the LEDs over DMX?
+    pub live: bool,
+    pub outputs: Vec<OutputStatus>,
+    pub frames: FrameStats,
+}
+
+/// Color (and rooms) received live over DMX
+#[derive(Debug, Clone)]
+pub struct LiveFrame {
+    pub color: Color,
+    pub rooms: Option<Rooms>,
+    /// When the frame arrived, to tell when the live input has stopped
+    pub received: Instant,
+    /// Color before the live input started, to go back to afterwards
+    pub color_before: Color,
+}
+
+/// The current configuration
+pub fn led_config() -> Arc<LedConfig> {
+    match LED_CONFIG.get().read() {
+        Ok(config) => config.clone(),
+        Err(poisoned) => poisoned.into_inner().clone(),
+    }
+}
+
+/// Load the config again, and apply the parts that can change while running
+pub fn reload_config() -> Result<ReloadSummary, ConfigError> {
+    let new = LedConfig::load(CONFIG_SOURCE.get())?;
+    let mut config = LED_CONFIG
+        .get()
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let (merged, summary) = config.reload(new);
+    *config = Arc::new(merged);
+
+    info!("Reloaded config: {:?}", summary);
+    if !summary.needs_restart.is_empty() {
+        warn!(
+            "Restart to apply the config changes to {}",
+            summary.needs_restart.join(", ")
+        );
+    }
+    Ok(summary)
+}
+
+pub fn init_global_state(config: LedConfig, source: ConfigSource) {
+    STARTED.set(Instant::now());
+
     LED_STATE.set(RwLock::new(LedState {
         current_color: Color::new(0.0, 0.0, 0.0, 0.0),
         current_rooms: Rooms {
@@ -41,13 +143,46 @@ pub fn init_global_state() {
             bedroom: false,
         },
         current_sequence: None,
+        playlist: Playlist::default(),
+        last_on_color: None,
+        live_frame: None,
+        frame_stats: FrameStats::default(),
+        worker_heartbeat: None,
+        shutting_down: false,
         shutdown: false,
     }));
 
-    LED_CONFIG.set(LedConfig::new());
+    let config = Arc::new(config);
+    LED_CONFIG.set(RwLock::new(config.clone()));
+    CONFIG_SOURCE.set(source);
 
     LED_ACTIVE.set((Mutex::new(false), Condvar::new()));
 
-    let mgr = SerialManager::new(&LED_CONFIG.get().tty_name);
-    SERIAL_MANAGER.set(RwLock::new(mgr));
+    let outputs = led_output::create_outputs(&config);
+    LED_OUTPUTS.set(RwLock::new(outputs));
+
+    apply_defaults(&config);
+}
+
+/// Set the LEDs up the way `[defaults]` says to
+fn apply_defaults(config: &LedConfig) {
+    let Ok(mut state) = LED_STATE.get().write() else {
+        return;
+    };
+    if let Some(ref color) = config.defaults.color {
+        let fade_in = LedSequence::from_color_lerp(&state.current_color, color);
+        state.current_sequence = Some(fade_in);
+    }
+    if let Some(ref rooms) = config.defaults.rooms {
+        state.current_rooms = rooms.clone();
+    }
+    if let Some(ref name) = config.defaults.scene {
+        let applied = config
+            .scenes
+            .get(name)
+            .map(|scene| scene.apply(&mut state));
+        if let Some(Err(e)) = applied {
+            error!("Unable to apply default scene {:?}: {:?}", name, e);
+        }
+    }
 }
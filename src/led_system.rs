@@ -1,55 +1,44 @@
 use std::iter::Iterator;
 use std::time::{Duration, Instant};
 
-use crate::led_sequence::RESOLUTION;
-use crate::led_state::{LedState, LED_STATE, SERIAL_MANAGER};
+use crate::color::Color;
+use crate::frame_clock::{FrameClock, SystemClock};
+use crate::led_sequence::{LedSequence, RESOLUTION};
+use crate::led_state::{led_config, LedState, LED_OUTPUTS, LED_STATE};
+use crate::metrics;
+
+/// How often to check whether the shutdown fade has finished
+const SHUTDOWN_POLL: Duration = Duration::from_millis(50);
 
 /// Controls the RGBW LEDs.
 pub struct LedSystem {
     sequence_thread: std::thread::JoinHandle<()>,
 }
 
-struct LedSystemStatus {
-    pub index: usize,
-    pub start_time: Instant,
-    pub previous_time: Instant,
-    pub current_time: Instant,
-    pub nominal_sleep_time: Duration,
-    pub actual_sleep_time: Duration,
-}
-
-impl LedSystemStatus {
-    pub fn new() -> Self {
-        let sleep_time = Duration::from_millis((1000.0 / RESOLUTION) as u64);
-        Self {
-            index: 0,
-            start_time: Instant::now(),
-            current_time: Instant::now(),
-            previous_time: Instant::now(),
-            nominal_sleep_time: sleep_time,
-            actual_sleep_time: sleep_time,
-        }
-    }
-
-    pub fn reinitialize(&mut self) {
-        *self = Self::new();
-    }
-}
-
 /// This impl is responsible for actually controlling the LEDs and room relays,
 /// and nothing else. It runs on a separate thread, which loops and sends data
 /// to the serial USB if there's a sequence running, otherwise it will spin.
 impl LedSystem {
     /// Create a new LedSystem instance. Should be a ~singleton.
     pub fn new() -> Self {
-        let t = std::thread::spawn(|| LedSystem::led_sequence_worker());
+        let t = std::thread::spawn(LedSystem::led_sequence_worker);
 
         Self { sequence_thread: t }
     }
 
-    /// Shut down this LedSystem instance and wait for the sequence worker thread to join.
+    /// Shut down this LedSystem instance: play out the `[shutdown]` policy,
+    /// wait for the sequence worker thread to join, then close the outputs.
     pub fn shutdown(self) -> Result<(), &'static str> {
         debug!("Shutting down LED system...");
+        let config = led_config();
+        let transition = match LED_STATE.get().write() {
+            Ok(mut state) => config.shutdown.begin(&config.scenes, &mut state),
+            Err(_) => return Err("Unable to obtain lock on state"),
+        };
+        if transition {
+            Self::wait_for_sequence(config.shutdown.timeout());
+        }
+
         if let Ok(mut state) = LED_STATE.get().write() {
             state.shutdown = true;
         } else {
@@ -57,97 +46,231 @@ impl LedSystem {
         }
         self.sequence_thread
             .join()
-            .map_err(|_| "Unable to shutdown LED system worker thread")
+            .map_err(|_| "Unable to shutdown LED system worker thread")?;
+
+        // The worker has stopped, so nothing else sends to the outputs now
+        let Ok(state) = LED_STATE.get().read() else {
+            return Err("Unable to obtain lock on state");
+        };
+        let (color, rooms) = (&state.current_color, &state.current_rooms);
+        if let Ok(mut outputs) = LED_OUTPUTS.get().write() {
+            for output in outputs.iter_mut() {
+                output.close(color, rooms);
+            }
+        } else {
+            return Err("Unable to obtain lock on outputs");
+        }
+        Ok(())
+    }
+
+    /// Wait for the current sequence to finish playing, up to `timeout`
+    fn wait_for_sequence(timeout: Duration) {
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            let playing = LED_STATE
+                .get()
+                .read()
+                .is_ok_and(|state| state.current_sequence.is_some());
+            if !playing {
+                return;
+            }
+            std::thread::sleep(SHUTDOWN_POLL);
+        }
+        warn!("Shutdown transition didn't finish within {:?}", timeout);
+    }
+
+    /// Apply the live frame from the DMX receiver, returning its color if it's
+    /// still live. Once frames stop arriving, go back to the sequence that was
+    /// running, or the color from before.
+    fn update_live_frame(state: &mut LedState) -> Option<Color> {
+        if state.shutting_down {
+            return None;
+        }
+        let live = state.live_frame.clone()?;
+        if live.received.elapsed() < led_config().dmx_input.timeout() {
+            if let Some(rooms) = live.rooms {
+                state.current_rooms = rooms;
+            }
+            Some(live.color)
+        } else {
+            info!("Live DMX input stopped, resuming");
+            state.live_frame = None;
+            if state.current_sequence.is_none() {
+                state.current_sequence = Some(LedSequence::from_color_lerp(
+                    &state.current_color,
+                    &live.color_before,
+                ));
+            }
+            None
+        }
+    }
+
+    /// Hand a new sequence's fades to the outputs that can play them by
+    /// themselves, returning which ones took them
+    fn offload_fades(seq: &LedSequence) -> Vec<bool> {
+        let Some(segments) = seq.fade_segments() else {
+            return Vec::new();
+        };
+        let start = seq.color_at(0.0);
+        match LED_OUTPUTS.get().write() {
+            Ok(mut outputs) => outputs
+                .iter_mut()
+                .map(|output| output.start_fade(&start, &segments))
+                .collect(),
+            Err(_) => Vec::new(),
+        }
     }
 
     fn led_sequence_worker() {
-        let mut status = LedSystemStatus::new();
+        let mut clock = FrameClock::new(SystemClock::new(), RESOLUTION);
+        // Frames to move the sequence on by, more than 1 after skipping
+        let mut frames_due: u64 = 1;
+        let mut sequence_running = false;
         let mut last_state = LedState::default();
+        // Outputs playing the current sequence's fades by themselves
+        let mut offloaded: Vec<bool> = Vec::new();
 
-        trace!("Set up LED System with temporal resolution {}fps, nominal sleep time per frame = {:?}", RESOLUTION, status.nominal_sleep_time);
+        trace!(
+            "Set up LED System with temporal resolution {}fps",
+            RESOLUTION
+        );
 
         loop {
+            let lock_start = Instant::now();
             if let Ok(ref mut state) = LED_STATE.get().write() {
+                metrics::STATE_LOCK_WAIT.observe_duration(lock_start.elapsed());
+
+                // Live frames from lighting software take over from the
+                // playlist and sequence while they keep arriving
+                let live_color = Self::update_live_frame(state);
+
                 // Update the rooms (if changed)
                 if last_state.current_rooms != state.current_rooms {
-                    if let Ok(mut ser) = SERIAL_MANAGER.get().write() {
-                        ser.send_rooms(&state.current_rooms);
+                    if let Ok(mut outputs) = LED_OUTPUTS.get().write() {
+                        for output in outputs.iter_mut() {
+                            output.send_rooms(&state.current_rooms);
+                        }
                     }
                 }
 
-                // Update the sequence & current color, if it exists
-                if let Some(ref mut seq) = state.current_sequence.as_mut() {
-                    if let Some(color) = seq.next() {
-                        // starting a new sequence
-                        if status.index == 0 {
-                            status.reinitialize();
+                if let Some(ref color) = live_color {
+                    if *color != state.current_color {
+                        if let Ok(mut outputs) = LED_OUTPUTS.get().write() {
+                            for output in outputs.iter_mut() {
+                                output.send_color(color);
+                            }
                         }
+                        metrics::FRAMES_SENT.inc();
+                        state.current_color = color.clone();
+                    }
+                }
 
+                // Move on to the next playlist item once the current one is done
+                if live_color.is_none()
+                    && state.playlist.is_active()
+                    && state
+                        .playlist
+                        .current_finished(state.current_sequence.as_ref())
+                {
+                    let had_item = state.playlist.current.is_some();
+                    let fade_from = state.current_color.clone();
+                    if let Some(seq) =
+                        state.playlist.advance_sequence(&fade_from)
+                    {
+                        state.current_sequence = Some(seq);
+                        sequence_running = false;
+                    } else if had_item {
+                        debug!("Playlist finished");
+                        state.current_sequence = None;
+                    }
+                }
+
+                // Update the sequence & current color, if it exists
+                if let Some(ref mut seq) = state
+                    .current_sequence
+                    .as_mut()
+                    .filter(|_| live_color.is_none())
+                {
+                    // Starting a new sequence, so count its frames from now
+                    if !sequence_running {
+                        clock.restart();
+                        frames_due = 1;
+                        sequence_running = true;
+                    }
+                    if seq.is_new() {
+                        offloaded = Self::offload_fades(seq);
+                    }
+
+                    // Skip the frames there wasn't time to send, without
+                    // working out their colors, so the sequence keeps its
+                    // real duration (but still ends on its last color)
+                    seq.skip_frames(frames_due as usize - 1);
+                    if let Some(color) = seq.next() {
                         trace!(
-                            "Iteration {} - {}, {}, {}, {}",
-                            status.index,
-                            state.current_color.r,
-                            state.current_color.g,
-                            state.current_color.b,
-                            state.current_color.w,
+                            "Frame {} - {}, {}, {}, {}",
+                            clock.frame(),
+                            color.r,
+                            color.g,
+                            color.b,
+                            color.w,
                         );
 
-                        // Send color to serial
-                        if let Ok(mut ser) = SERIAL_MANAGER.get().write() {
-                            ser.send_color(&color);
+                        // Send color to the outputs, except the ones fading by
+                        // themselves. Outputs whose fade stopped early get
+                        // colors again.
+                        if let Ok(mut outputs) = LED_OUTPUTS.get().write() {
+                            for (i, output) in outputs.iter_mut().enumerate() {
+                                if offloaded.get(i).copied().unwrap_or(false) {
+                                    if !output.fade_failed() {
+                                        continue;
+                                    }
+                                    warn!(
+                                        "Output {} stopped its fade early",
+                                        i
+                                    );
+                                    offloaded[i] = false;
+                                }
+                                output.send_color(&color);
+                            }
                         }
+                        metrics::FRAMES_SENT.inc();
 
                         // Update color in state
                         state.current_color = color;
-
-                        // Calculate how long to sleep based on timing errors.
-                        // Theorectially, we should be `index * delay` milleseconds along.
-                        // But, with timing errors (serial delay) this isn't always the case.
-                        // So, we correct for that here.
-                        let nominal_current_time = status.nominal_sleep_time.checked_mul((status.index) as u32).unwrap_or_default();
-                        let actual_current_time = status.start_time.elapsed();
-                        let over_time = actual_current_time.checked_sub(nominal_current_time);
-                        let under_time = nominal_current_time.checked_sub(actual_current_time);
-
-                        trace!(
-                            "Index {}, Nominal time {:?}, actual time {:?}, over time {:?}, under time {:?}",
-                            status.index, nominal_current_time, actual_current_time, over_time, under_time
-                        );
-
-                        status.actual_sleep_time = if let Some(time_diff) = over_time {
-                            if let Some(time) = status.actual_sleep_time.checked_sub(time_diff) {
-                                time
-                            }  else {
-                                warn!("Cannot subtract negative over_time {:?} from sleep_time {:?}. Defaulting to {:?}", time_diff, status.actual_sleep_time, Duration::from_millis(0));
-                                Duration::from_millis(0)
-                            }
-                        } else if let Some(time_diff) = under_time {
-                            if let Some(time) = status.actual_sleep_time.checked_add(time_diff) {
-                                time
-                            }  else {
-                                warn!("Cannot add negative over_time {:?} to sleep_time {:?}. Defaulting to {:?}", time_diff, status.actual_sleep_time, Duration::from_millis(0));
-                                Duration::from_millis(0)
-                            }
-                        } else {
-                            status.actual_sleep_time
-                        };
-
-                        // Update the timings (only do this when we're actively sending colors to serial)
-                        status.previous_time = status.current_time;
-                        status.current_time = Instant::now();
-                        status.index += 1;
                     } else {
                         // hit the end of a sequence, or no sequence available
                         state.current_sequence = None;
+                        sequence_running = false;
+                        offloaded.clear();
 
-                        // reset to beginning of whatever sequence is next
-                        debug!("Stopped sequence, set index 0. Total time: {:?}", status.current_time - status.start_time);
-                        status.reinitialize();
+                        debug!(
+                            "Stopped sequence. Total time: {:?}",
+                            clock.elapsed()
+                        );
 
                         // TODO: use LED_ACTIVE here to avoid spin-waiting
                     }
+                } else {
+                    sequence_running = false;
+                    // The sequence was stopped part way, so stop the fades
+                    // where the color is now
+                    if offloaded.contains(&true) {
+                        if let Ok(mut outputs) = LED_OUTPUTS.get().write() {
+                            for (output, _) in outputs
+                                .iter_mut()
+                                .zip(&offloaded)
+                                .filter(|(_, &offloaded)| offloaded)
+                            {
+                                output.send_color(&state.current_color);
+                            }
+                        }
+                    }
+                    offloaded.clear();
                 }
 
+                state.frame_stats = clock.stats();
+                state.worker_heartbeat = Some(Instant::now());
+
                 if state.shutdown {
                     debug!("Shutting down / exiting LED spin");
                     break;
@@ -158,10 +281,18 @@ impl LedSystem {
                 break;
             }
 
-            trace!("Sleeping for: {:?}", status.actual_sleep_time);
-            std::thread::sleep(status.actual_sleep_time);
-            status.actual_sleep_time = status.nominal_sleep_time;
-            trace!("Time: {:?}", status.start_time.elapsed());
+            frames_due = clock.wait_for_next_frame();
+            metrics::FRAME_LATENESS.observe_duration(clock.last_jitter());
+            if frames_due > 1 {
+                trace!("Skipping {} late frame(s)", frames_due - 1);
+                metrics::FRAMES_SKIPPED.add(frames_due - 1);
+            }
         }
     }
-}
\ No newline at end of file
+}
+
+impl Default for LedSystem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
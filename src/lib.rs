@@ -0,0 +1,43 @@
+//! Controls RGBW LED strips and room relays, from the `led-foot` server or
+//! the `led-foot-cli` tools
+
+#[macro_use]
+extern crate log;
+
+#[cfg(test)]
+mod arduino_sim;
+pub mod audio;
+pub mod auth;
+pub mod color;
+pub mod diagnostics;
+pub mod dither;
+pub mod dmx;
+pub mod dmx_output;
+pub mod dmx_receiver;
+pub mod effects;
+pub mod frame_clock;
+pub mod http_server;
+pub mod hue;
+pub mod led_config;
+pub mod led_output;
+pub mod led_sequence;
+pub mod led_state;
+pub mod led_system;
+pub mod logging;
+pub mod metrics;
+pub mod mock_output;
+pub mod network;
+pub mod playlist;
+pub mod protocol;
+pub mod rooms;
+pub mod scenes;
+pub mod schedules;
+pub mod script;
+pub mod sequence_source;
+pub mod sequence_tools;
+pub mod serial_manager;
+pub mod shutdown;
+pub mod static_files;
+#[cfg(test)]
+mod test_dir;
+pub mod wled;
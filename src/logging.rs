@@ -0,0 +1,60 @@
+//! Log output formats for the server, with the level still set by `RUST_LOG`
+
+use std::io::Write;
+
+use clap::ValueEnum;
+use log::Level;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum LogFormat {
+    /// Timestamp, level and module, for a terminal
+    #[default]
+    Text,
+    /// Syslog priority prefix and no timestamp, for systemd's journal
+    Journald,
+    /// One JSON object per line, for log collectors
+    Json,
+}
+
+/// Priority that journald understands as a `<N>` prefix
+fn syslog_priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+pub fn init(format: LogFormat) {
+    let mut builder = env_logger::Builder::from_default_env();
+    match format {
+        LogFormat::Text => {}
+        LogFormat::Journald => {
+            builder.format(|buf, record| {
+                writeln!(
+                    buf,
+                    "<{}>{}: {}",
+                    syslog_priority(record.level()),
+                    record.target(),
+                    record.args()
+                )
+            });
+        }
+        LogFormat::Json => {
+            builder.format(|buf, record| {
+                writeln!(
+                    buf,
+                    "{}",
+                    serde_json::json!({
+                        "time": chrono::Local::now().to_rfc3339(),
+                        "level": record.level().as_str(),
+                        "target": record.target(),
+                        "message": record.args().to_string(),
+                    })
+                )
+            });
+        }
+    }
+    builder.init();
+}
@@ -1,26 +1,31 @@
 #[macro_use]
 extern crate log;
 
-pub mod color;
-pub mod led_config;
-pub mod led_sequence;
-pub mod led_state;
-pub mod led_system;
-pub mod rooms;
-pub mod serial_manager;
-
-use std::path::Path;
+use std::path::PathBuf;
 
 use actix_files::Files;
+use actix_web::dev::{ServerHandle, Service};
 use actix_web::http::header::ContentType;
 use actix_web::{
     get, middleware, web, App, HttpResponse, HttpServer,
 };
-
-use crate::color::Color;
-use crate::led_sequence::LedSequence;
-use crate::led_state::LED_STATE;
-use crate::rooms::Rooms;
+use clap::Parser;
+use serde_derive::Deserialize;
+
+use led_foot::color::Color;
+use led_foot::http_server::{self, ListenAddr};
+use led_foot::led_config::{ConfigSource, LedConfig};
+use led_foot::led_sequence::{self, LedSequence, LedSequenceInfo};
+use led_foot::led_state::{
+    self, led_config, LedStatus, LED_OUTPUTS, LED_STATE,
+};
+use led_foot::logging::{self, LogFormat};
+use led_foot::playlist::PlaylistItem;
+use led_foot::rooms::Rooms;
+use led_foot::{
+    auth, diagnostics, dmx_receiver, effects, hue, led_system, metrics,
+    schedules, static_files, wled,
+};
 
 // API Endpoints:
 // /api/get-rgbw
@@ -31,7 +36,109 @@ use crate::rooms::Rooms;
 //
 // /api/get-rooms
 // /api/set-rooms
+//
+// /api/set-effect
+// /api/list-effects
+//
+// /api/get-playlist
+// /api/enqueue-sequence
+// /api/skip-sequence
+// /api/clear-playlist
+//
+// /api/list-scenes
+// /api/apply-scene
+//
+// /api/get-status
+//
+// /api/reload-config
+//
+// /api/login
+// /api/logout
+//
+// /api/health
+// /api/ready
+// /api/diagnostics
+//
+// Prometheus metrics:
+// /metrics
+//
+// WLED-compatible JSON API (see wled.rs):
+// /json
+// /json/si
+// /json/state
+// /json/info
+// /json/effects
+// /json/palettes
+//
+// Philips Hue bridge emulation, if enabled (see hue.rs):
+// /description.xml
+// /api/<username>/lights
+// /api/<username>/lights/<id>/state
+
+/// LED Foot server
+///
+/// Flags override `led_config.toml` and `LED_FOOT__*` environment variables.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Args {
+    /// Config file (default `led_config.toml`, if it exists)
+    #[arg(short, long, env = "LED_FOOT_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Folder with the sequences, scripts and audio files
+    #[arg(long, value_name = "DIR")]
+    sequence_dir: Option<PathBuf>,
+
+    /// Serve `/static` from this folder instead of the built-in files
+    #[arg(long, value_name = "DIR")]
+    static_dir: Option<PathBuf>,
+
+    /// Address to listen on, e.g. `0.0.0.0:5000` or `unix:/run/led-foot.sock`
+    /// (repeat for more than one)
+    #[arg(short, long, value_name = "ADDR")]
+    bind: Vec<String>,
+
+    /// Print the colors instead of sending them anywhere
+    #[arg(long)]
+    mock: bool,
+
+    #[arg(long, value_enum, default_value_t, env = "LED_FOOT_LOG_FORMAT")]
+    log_format: LogFormat,
+
+    /// Check the config and exit
+    #[arg(long)]
+    check_config: bool,
+}
 
+impl Args {
+    /// The config file and the flags that override it
+    fn config_source(&self) -> ConfigSource {
+        let mut source = ConfigSource::new(self.config.clone());
+        if let Some(ref dir) = self.sequence_dir {
+            source.set("paths.sequences", dir.to_string_lossy().into_owned());
+        }
+        if let Some(ref dir) = self.static_dir {
+            source.set("paths.static_dir", dir.to_string_lossy().into_owned());
+        }
+        if !self.bind.is_empty() {
+            source.set("http.listen", self.bind.clone());
+        }
+        if self.mock {
+            source.set("outputs", vec!["mock"]);
+        }
+        source
+    }
+}
+
+/// Load the config and make sure the server could start with it, for
+/// `--check-config`
+fn check_config(source: &ConfigSource) -> Result<(), String> {
+    let config = LedConfig::load(source).map_err(|e| e.to_string())?;
+    if let Some(ref tls) = config.http.tls {
+        http_server::load_tls(tls).map_err(|e| format!("`http.tls`: {}", e))?;
+    }
+    Ok(())
+}
 
 /// Retrieve the current color that the LEDs are on
 async fn get_color() -> HttpResponse {
@@ -48,36 +155,44 @@ async fn get_color() -> HttpResponse {
 /// Retrieve the color that the LEDs WILL on when a transition-in-progress is complete
 async fn get_color_future() -> HttpResponse {
     if let Ok(led_state) = LED_STATE.get().read() {
-        // Default to the current color and hope for the best
-        let mut send_color = led_state.current_color.clone();
-
-        // If it's a transition / one-off sequence, use the last element instead
-        if let Some(ref seq) = led_state.current_sequence {
-            if !seq.info.repeat {
-                if let Some(col) = seq.colors.back() {
-                    send_color = col.clone();
-                }
-            }
-        }
-
         HttpResponse::Ok()
             .content_type(ContentType::json())
-            .json(send_color)
+            .json(led_state.future_color())
     } else {
         error!("Error on /api/get-color: can't get lock on state");
         HttpResponse::InternalServerError().into()
     }
 }
 
+/// Optional `?transition=<seconds>` for how long to fade to a new color
+#[derive(Debug, Deserialize)]
+struct TransitionQuery {
+    transition: Option<f32>,
+}
+
 /// Set the RGBW color for the LEDs and automatically begin a sequence w/transition
-async fn set_color(payload: web::Json<Color>) -> HttpResponse {
+///
+/// Takes over from the playlist, if one is running
+async fn set_color(
+    payload: web::Json<Color>,
+    query: web::Query<TransitionQuery>,
+) -> HttpResponse {
     debug!("Color: {:?}", payload);
     if let Ok(mut led_state) = LED_STATE.get().write() {
+        led_state.playlist.clear();
+
         // does not directly set color - smoothly interpolates to the color.
-        let seq_with_transition = LedSequence::from_color_lerp(
-            &led_state.current_color,
-            &payload,
-        );
+        let seq_with_transition = match query.transition {
+            Some(duration) => LedSequence::from_color_transition(
+                &led_state.current_color,
+                &payload,
+                duration,
+            ),
+            None => LedSequence::from_color_lerp(
+                &led_state.current_color,
+                &payload,
+            ),
+        };
         led_state.current_sequence = Some(seq_with_transition);
 
         HttpResponse::Ok()
@@ -92,7 +207,7 @@ async fn set_color(payload: web::Json<Color>) -> HttpResponse {
 /// Get the sequence that is currently running
 async fn get_sequence() -> HttpResponse {
     if let Ok(led_state) = LED_STATE.get().read() {
-        let current_sequence_name = led_state.current_sequence.clone().map_or(None, |s| Some(s.info.name));
+        let current_sequence_name = led_state.current_sequence.clone().map(|s| s.info.name);
         HttpResponse::Ok()
             .content_type(ContentType::json())
             .json(current_sequence_name)
@@ -103,33 +218,12 @@ async fn get_sequence() -> HttpResponse {
 }
 
 /// Switch to a new sequence
+///
+/// Takes over from the playlist, if one is running
 async fn set_sequence(payload: String) -> HttpResponse {
     if let Ok(ref mut led_state) = LED_STATE.get().write() {
-        // TODO: This is hacky - check if the payload includes the magic phrase
-        let seq_with_transition = if payload.starts_with("fade-to-black-") {
-            let tokens = payload.split("-");
-            debug!("Parsed fade to black tokens {:?}", tokens);
-            let duration_result= tokens
-                .last()
-                .expect("Unable to get final token in payload string for /api/set-sequence")
-                .parse::<f32>();
-            if let Ok(duration) = duration_result {
-                debug!("Fading to black {}s", duration);
-                Ok(LedSequence::fade_to_black(&led_state.current_color, duration))
-            } else {
-                debug!("Fading to black defaulted to 10s");
-                Ok(LedSequence::fade_to_black(&led_state.current_color, crate::led_sequence::FADE_DURATION))
-            }
-        } else {
-            let seq_path = payload.replace("png", "json");
-            debug!("Sequence path: {:?}", seq_path);
-            LedSequence::from_color_points(
-                &led_state.current_color,
-                // TODO: Fix this on the javascript side (generate the colors from the
-                // json)
-                Path::new(&seq_path),
-            )
-        };
+        let seq_with_transition =
+            LedSequence::from_name(&led_state.current_color, &payload);
 
         if let Err(e) = seq_with_transition {
             return HttpResponse::BadRequest()
@@ -137,6 +231,7 @@ async fn set_sequence(payload: String) -> HttpResponse {
                 .body(format!("No sequence named {:?}; {:?}", payload, e))
         }
 
+        led_state.playlist.clear();
         led_state.current_sequence = seq_with_transition.ok();
 
         HttpResponse::Ok()
@@ -148,21 +243,115 @@ async fn set_sequence(payload: String) -> HttpResponse {
     }
 }
 
+/// Start one of the built-in effects, with its parameters given in the
+/// `sequence_type`
+async fn set_effect(payload: web::Json<LedSequenceInfo>) -> HttpResponse {
+    if let Ok(mut led_state) = LED_STATE.get().write() {
+        let effect = LedSequence::from_effect(
+            &led_state.current_color,
+            payload.clone(),
+        );
+
+        match effect {
+            Ok(seq) => {
+                led_state.playlist.clear();
+                led_state.current_sequence = Some(seq);
+                HttpResponse::Ok()
+                    .content_type(ContentType::plaintext())
+                    .body(format!("Set effect to {:?}", payload.sequence_type))
+            }
+            Err(e) => HttpResponse::BadRequest()
+                .content_type(ContentType::plaintext())
+                .body(format!("Invalid effect {:?}; {:?}", payload, e)),
+        }
+    } else {
+        error!("Error on /api/set-effect: can't get lock on state");
+        HttpResponse::InternalServerError().into()
+    }
+}
+
+/// List the names of the built-in effects
+async fn list_effects() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .json(effects::EFFECT_NAMES)
+}
+
+/// Get the playlist item that's playing and the ones queued up after it
+async fn get_playlist() -> HttpResponse {
+    if let Ok(led_state) = LED_STATE.get().read() {
+        HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .json(led_state.playlist.clone())
+    } else {
+        error!("Error on /api/get-playlist: can't get lock on state");
+        HttpResponse::InternalServerError().into()
+    }
+}
 
-async fn list_sequences() -> HttpResponse {
-    if let Ok(paths) = std::fs::read_dir(led_sequence::SEQUENCE_PATH) {
+/// Add a sequence to the end of the playlist
+async fn enqueue_sequence(payload: web::Json<PlaylistItem>) -> HttpResponse {
+    if let Ok(mut led_state) = LED_STATE.get().write() {
+        // Load it now so a bad name is reported to the caller, rather than
+        // only logged when the playlist gets to it
+        let checked =
+            LedSequence::from_name(&led_state.current_color, &payload.sequence);
+        if let Err(e) = checked {
+            return HttpResponse::BadRequest()
+                .content_type(ContentType::plaintext())
+                .body(format!(
+                    "No sequence named {:?}; {:?}",
+                    payload.sequence, e
+                ));
+        }
+
+        led_state.playlist.enqueue(payload.clone());
+        HttpResponse::Ok()
+            .content_type(ContentType::plaintext())
+            .body(format!("Enqueued {:?}", payload))
+    } else {
+        error!("Error on /api/enqueue-sequence: can't get lock on state");
+        HttpResponse::InternalServerError().into()
+    }
+}
+
+/// Stop the current playlist item and move on to the next one
+async fn skip_sequence() -> HttpResponse {
+    if let Ok(mut led_state) = LED_STATE.get().write() {
+        if led_state.playlist.current.is_some() {
+            led_state.playlist.skip();
+            led_state.current_sequence = None;
+        }
+        HttpResponse::Ok()
+            .content_type(ContentType::plaintext())
+            .body("Skipped current playlist item")
+    } else {
+        error!("Error on /api/skip-sequence: can't get lock on state");
+        HttpResponse::InternalServerError().into()
+    }
+}
+
+/// Remove everything from the playlist, leaving the LEDs as they are
+async fn clear_playlist() -> HttpResponse {
+    if let Ok(mut led_state) = LED_STATE.get().write() {
+        led_state.playlist.clear();
+        HttpResponse::Ok()
+            .content_type(ContentType::plaintext())
+            .body("Cleared playlist")
+    } else {
+        error!("Error on /api/clear-playlist: can't get lock on state");
+        HttpResponse::InternalServerError().into()
+    }
+}
 
-        let sequences_list = paths
-            .filter_map(|e| e.ok())
-            .map(|p| p.path().to_string_lossy().into_owned())
-            .filter(|p| p.ends_with(".png"))
-            .collect::<Vec<_>>();
 
+async fn list_sequences() -> HttpResponse {
+    if let Ok(sequences_list) = led_sequence::list_sequences() {
         HttpResponse::Ok()
             .content_type(ContentType::plaintext())
             .body(sequences_list.join("\n"))
     } else {
-        error!("Error on /api/list-sequences: can't read directory {}", led_sequence::SEQUENCE_PATH);
+        error!("Error on /api/list-sequences: can't read directory {:?}", led_sequence::sequence_dir());
         HttpResponse::InternalServerError().into()
     }
 }
@@ -193,6 +382,136 @@ async fn set_rooms(payload: web::Json<Rooms>) -> HttpResponse {
     }
 }
 
+/// Everything about what the LEDs are doing at once, for clients that want an
+/// overview
+async fn get_status() -> HttpResponse {
+    let outputs = LED_OUTPUTS
+        .get()
+        .read()
+        .map(|outputs| outputs.iter().map(|o| o.status()).collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    if let Ok(led_state) = LED_STATE.get().read() {
+        HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .json(LedStatus {
+                color: led_state.current_color.clone(),
+                future_color: led_state.future_color(),
+                rooms: led_state.current_rooms.clone(),
+                sequence: led_state
+                    .current_sequence
+                    .as_ref()
+                    .map(|s| s.info.name.clone()),
+                playlist: led_state.playlist.clone(),
+                live: led_state.live_frame.is_some(),
+                outputs,
+                frames: led_state.frame_stats.clone(),
+            })
+    } else {
+        error!("Error on /api/get-status: can't get lock on state");
+        HttpResponse::InternalServerError().into()
+    }
+}
+
+/// List the scenes set up in the config
+async fn list_scenes() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .json(&led_config().scenes)
+}
+
+/// Apply one of the scenes from the config, by name
+///
+/// Takes over from the playlist, if one is running
+async fn apply_scene(payload: String) -> HttpResponse {
+    let config = led_config();
+    let Some(scene) = config.scenes.get(&payload) else {
+        return HttpResponse::BadRequest()
+            .content_type(ContentType::plaintext())
+            .body(format!("No scene named {:?}", payload));
+    };
+
+    if let Ok(mut led_state) = LED_STATE.get().write() {
+        match scene.apply(&mut led_state) {
+            Ok(()) => HttpResponse::Ok()
+                .content_type(ContentType::plaintext())
+                .body(format!("Applied scene {:?}", payload)),
+            Err(e) => HttpResponse::BadRequest()
+                .content_type(ContentType::plaintext())
+                .body(format!("Unable to apply scene {:?}; {:?}", payload, e)),
+        }
+    } else {
+        error!("Error on /api/apply-scene: can't get lock on state");
+        HttpResponse::InternalServerError().into()
+    }
+}
+
+/// Read the config file again. Scenes, schedules, `[auth]` and `[defaults]`
+/// change straight away; the rest need a restart.
+async fn reload_config() -> HttpResponse {
+    match led_state::reload_config() {
+        Ok(summary) => HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .json(summary),
+        Err(e) => {
+            error!("Error on /api/reload-config: {}", e);
+            HttpResponse::BadRequest()
+                .content_type(ContentType::plaintext())
+                .body(e.to_string())
+        }
+    }
+}
+
+/// Reload the config whenever the process gets SIGHUP
+#[cfg(unix)]
+fn reload_on_sighup() -> std::io::Result<()> {
+    use actix_web::rt::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup())?;
+    actix_web::rt::spawn(async move {
+        while hangups.recv().await.is_some() {
+            info!("Got SIGHUP, reloading config");
+            if let Err(e) = led_state::reload_config() {
+                error!("Unable to reload config: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Stop the server gracefully on SIGINT (Ctrl+C) or SIGTERM, so the LEDs
+/// can be shut down the way `[shutdown]` says after it stops
+fn stop_on_signals(handle: ServerHandle) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use actix_web::rt::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        let handle = handle.clone();
+        actix_web::rt::spawn(async move {
+            if terminate.recv().await.is_some() {
+                info!("Got SIGTERM, shutting down");
+                handle.stop(true).await;
+            }
+        });
+    }
+
+    actix_web::rt::spawn(async move {
+        if actix_web::rt::signal::ctrl_c().await.is_ok() {
+            info!("Got SIGINT, shutting down");
+            handle.stop(true).await;
+        }
+    });
+    Ok(())
+}
+
+/// Counters and histograms in the Prometheus text format
+async fn get_metrics() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4; charset=utf-8")
+        .body(metrics::render())
+}
+
 /// Unused base API URL for LED Foot
 async fn base_api() -> HttpResponse {
     HttpResponse::Ok()
@@ -205,30 +524,96 @@ async fn base_api() -> HttpResponse {
 async fn index() -> HttpResponse {
     HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
-        .body(include_str!("../index.html"))
+        .body(static_files::INDEX_HTML)
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    let args = Args::parse();
+    let source = args.config_source();
+
     // Initialize logging
-    ::env_logger::init();
+    logging::init(args.log_format);
+
+    if args.check_config {
+        match check_config(&source) {
+            Ok(()) => {
+                println!("Config OK");
+                std::process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
     println!("Starting LED server with log level {:?}", ::log::max_level());
 
-    let server = HttpServer::new(|| {
+    let config = match LedConfig::load(&source) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Initialize state
+    led_state::init_global_state(config, source);
+
+    let mut server = HttpServer::new(|| {
         App::new()
+            // Turn away requests without the right token or login, if
+            // `[auth]` is enabled
+            .wrap_fn(|req, srv| {
+                let config = led_config();
+                let response =
+                    match auth::authorize(&config.auth, req.request()) {
+                        Ok(()) => Ok(srv.call(req)),
+                        Err(denied) => {
+                            Err(req.into_response(denied.response()))
+                        }
+                    };
+                async move {
+                    match response {
+                        Ok(response) => response.await,
+                        Err(denied) => Ok(denied),
+                    }
+                }
+            })
             // Enable the logger.
             .wrap(middleware::Logger::default())
+            // Count requests per route for /metrics
+            .wrap_fn(|req, srv| {
+                let route = req
+                    .match_pattern()
+                    .unwrap_or_else(|| "unmatched".to_string());
+                let method = req.method().to_string();
+                let response = srv.call(req);
+                async move {
+                    let response = response.await?;
+                    metrics::HTTP_REQUESTS.inc(&[
+                        &route,
+                        &method,
+                        response.status().as_str(),
+                    ]);
+                    Ok(response)
+                }
+            })
             // Serve sequences as static files (allow to see file list if user wants)
             .service(
-                Files::new("/led-foot-sequences", led_sequence::SEQUENCE_PATH)
+                Files::new("/led-foot-sequences", led_sequence::sequence_dir())
                     .show_files_listing(),
             )
-            // Serve the rest of the static files
-            .service(Files::new("/static", "static"))
+            // Serve the rest of the static files, built in unless
+            // `--static-dir` is given
+            .route("/static/{name}", web::get().to(static_files::get_static))
             // index.html
             .service(index)
             // The rest of the routes for controlling the LEDs
             .route("/api", web::get().to(base_api))
+            .route("/api/login", web::post().to(auth::login))
+            .route("/api/logout", web::post().to(auth::logout))
             .route("/api/get-color", web::get().to(get_color))
             .route("/api/get-color-future", web::get().to(get_color_future))
             .route("/api/set-color", web::post().to(set_color))
@@ -237,24 +622,107 @@ async fn main() -> std::io::Result<()> {
             .route("/api/list-sequences", web::get().to(list_sequences))
             .route("/api/get-rooms", web::get().to(get_rooms))
             .route("/api/set-rooms", web::post().to(set_rooms))
-    })
-    .bind("0.0.0.0:5000")?;
-
-    // Initialize state
-    led_state::init_global_state();
+            .route("/api/set-effect", web::post().to(set_effect))
+            .route("/api/list-effects", web::get().to(list_effects))
+            .route("/api/get-playlist", web::get().to(get_playlist))
+            .route("/api/enqueue-sequence", web::post().to(enqueue_sequence))
+            .route("/api/skip-sequence", web::post().to(skip_sequence))
+            .route("/api/clear-playlist", web::post().to(clear_playlist))
+            .route("/api/list-scenes", web::get().to(list_scenes))
+            .route("/api/apply-scene", web::post().to(apply_scene))
+            .route("/api/get-status", web::get().to(get_status))
+            .route("/api/reload-config", web::post().to(reload_config))
+            .route("/api/health", web::get().to(diagnostics::get_health))
+            .route("/api/ready", web::get().to(diagnostics::get_ready))
+            .route(
+                "/api/diagnostics",
+                web::get().to(diagnostics::get_diagnostics),
+            )
+            .route("/metrics", web::get().to(get_metrics))
+            // Enough of the WLED JSON API for its apps and integrations
+            .route("/json", web::get().to(wled::get_all))
+            .route("/json/si", web::get().to(wled::get_state_info))
+            .route("/json/state", web::get().to(wled::get_state))
+            .route("/json/state", web::post().to(wled::set_state))
+            .route("/json/info", web::get().to(wled::get_info))
+            .route("/json/effects", web::get().to(wled::get_effects))
+            .route("/json/palettes", web::get().to(wled::get_palettes))
+            // Pretend to be a Hue bridge, after the rest of /api
+            .configure(|cfg| {
+                if led_config().hue.enabled {
+                    hue::configure(cfg);
+                }
+            })
+    });
+
+    // Listen where `[http]` says to, plain and/or HTTPS
+    let config = led_config();
+    let http = &config.http;
+    for addr in &http.listen {
+        info!("Listening on {}", String::from(addr.clone()));
+        server = match addr {
+            ListenAddr::Tcp(addr) => server.bind(addr)?,
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                http_server::remove_stale_socket(path)?;
+                server.bind_uds(path)?
+            }
+            #[cfg(not(unix))]
+            ListenAddr::Unix(path) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    format!(
+                        "Unable to listen on {:?} without Unix sockets",
+                        path
+                    ),
+                ));
+            }
+        };
+    }
+    if let Some(ref tls) = http.tls {
+        let tls_config = http_server::load_tls(tls)?;
+        for addr in &tls.listen {
+            info!("Listening for HTTPS on {}", addr);
+            server = server.bind_rustls_0_22(addr, tls_config.clone())?;
+        }
+    }
 
     // Start the LED System
     let sys = led_system::LedSystem::new();
 
-    server.run()
+    // Listen for live frames from lighting software
+    if let Err(e) = dmx_receiver::start(&config.dmx_input) {
+        error!("Unable to start DMX input: {}", e);
+    }
+
+    // Let voice assistants find the Hue bridge
+    if let Err(e) = hue::start_discovery(&config.hue) {
+        error!("Unable to start Hue bridge discovery: {}", e);
+    }
+
+    // Apply scenes at the times in `[[schedules]]`
+    if let Err(e) = schedules::start() {
+        error!("Unable to start schedules: {}", e);
+    }
+
+    #[cfg(unix)]
+    if let Err(e) = reload_on_sighup() {
+        error!("Unable to reload config on SIGHUP: {}", e);
+    }
+
+    // Signals are handled here rather than by actix, to shut down the LEDs
+    // after the server
+    let server = server.disable_signals().run();
+    if let Err(e) = stop_on_signals(server.handle()) {
+        error!("Unable to handle SIGINT/SIGTERM: {}", e);
+    }
+
+    server
         .await
         .and_then(|_| {
             sys.shutdown()
-            .map(|r| {
-                debug!("LED system shutdown normally");
-                r
-            })
-            .map_err(|msg| std::io::Error::new(std::io::ErrorKind::Other, msg))
+            .inspect(|_| debug!("LED system shutdown normally"))
+            .map_err(std::io::Error::other)
         })
 }
 
@@ -262,10 +730,12 @@ async fn main() -> std::io::Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use led_foot::led_output::LedOutput;
+    use led_foot::serial_manager;
 
     #[test]
     fn test_serial_connection() {
-        let mut mgr = serial_manager::SerialManager::new("/dev/ttyACM0");
+        let mut mgr = serial_manager::SerialManager::new(&Default::default());
         mgr.send_rooms(&Rooms { living_room: true, office: true, bedroom: true });
         let color_to_send: Color = Color::new(1.0, 1.0, 1.0, 1.0);
 
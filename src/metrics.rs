@@ -0,0 +1,370 @@
+//! Counters and histograms about the LED engine, the serial link and the HTTP
+//! API, served in the Prometheus text format at `/metrics`
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::led_state::{LED_OUTPUTS, LED_STATE, STARTED};
+
+/// Buckets (in seconds) for frame timing, up to a few frames at 30fps
+const FRAME_BUCKETS: [f64; 8] =
+    [0.001, 0.002, 0.005, 0.01, 0.02, 0.05, 0.1, 0.25];
+/// Buckets (in seconds) for serial round trips, up to the 2s timeout
+const SERIAL_BUCKETS: [f64; 10] =
+    [0.001, 0.002, 0.005, 0.01, 0.02, 0.05, 0.1, 0.25, 0.5, 2.0];
+/// Buckets (in seconds) for waiting on the state lock
+const LOCK_BUCKETS: [f64; 8] =
+    [0.00001, 0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.25];
+
+/// Frames sent to the outputs
+pub static FRAMES_SENT: Counter = Counter::new();
+/// Frames skipped because the previous frame ran late
+pub static FRAMES_SKIPPED: Counter = Counter::new();
+/// How late frames started after their deadline
+pub static FRAME_LATENESS: Histogram = Histogram::new(&FRAME_BUCKETS);
+/// Time to write a command to serial and read the reply
+pub static SERIAL_ROUND_TRIP: Histogram = Histogram::new(&SERIAL_BUCKETS);
+/// Replies from the Arduino, by the reply expected and the one received
+pub static SERIAL_REPLIES: LabeledCounter =
+    LabeledCounter::new(&["expected", "received"]);
+/// Times the serial port was reopened after failing
+pub static SERIAL_RECONNECTS: Counter = Counter::new();
+/// Colors replaced by a newer one before the serial writer got to them
+pub static SERIAL_COALESCED: Counter = Counter::new();
+/// Time the LED worker waited for the write lock on `LED_STATE`
+pub static STATE_LOCK_WAIT: Histogram = Histogram::new(&LOCK_BUCKETS);
+/// HTTP requests, by route pattern, method and status code
+pub static HTTP_REQUESTS: LabeledCounter =
+    LabeledCounter::new(&["route", "method", "status"]);
+
+/// Count that only goes up
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for Counter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Counts split up by the values of some labels
+pub struct LabeledCounter {
+    labels: &'static [&'static str],
+    counts: Mutex<BTreeMap<Vec<String>, u64>>,
+}
+
+impl LabeledCounter {
+    pub const fn new(labels: &'static [&'static str]) -> Self {
+        Self {
+            labels,
+            counts: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Count one more for these label values, in the order of `labels`
+    pub fn inc(&self, values: &[&str]) {
+        if let Ok(mut counts) = self.counts.lock() {
+            let key = values.iter().map(|v| v.to_string()).collect();
+            *counts.entry(key).or_insert(0) += 1;
+        }
+    }
+}
+
+struct HistogramData {
+    /// Observations up to each bucket's bound (not cumulative)
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+/// Distribution of observed values, e.g. durations in seconds
+pub struct Histogram {
+    bounds: &'static [f64],
+    data: Mutex<HistogramData>,
+}
+
+impl Histogram {
+    pub const fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            data: Mutex::new(HistogramData {
+                buckets: Vec::new(),
+                sum: 0.0,
+                count: 0,
+            }),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        if let Ok(mut data) = self.data.lock() {
+            if data.buckets.is_empty() {
+                data.buckets = vec![0; self.bounds.len()];
+            }
+            if let Some(i) = self.bounds.iter().position(|b| value <= *b) {
+                data.buckets[i] += 1;
+            }
+            data.sum += value;
+            data.count += 1;
+        }
+    }
+
+    pub fn observe_duration(&self, duration: Duration) {
+        self.observe(duration.as_secs_f64());
+    }
+}
+
+/// Escape a label value for the text format
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// `{a="1",b="2"}`, or nothing without labels
+fn format_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let pairs = labels
+        .iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect::<Vec<_>>();
+    format!("{{{}}}", pairs.join(","))
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn write_counter(out: &mut String, name: &str, help: &str, counter: &Counter) {
+    write_header(out, name, "counter", help);
+    let _ = writeln!(out, "{} {}", name, counter.get());
+}
+
+fn write_labeled_counter(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    counter: &LabeledCounter,
+) {
+    write_header(out, name, "counter", help);
+    if let Ok(counts) = counter.counts.lock() {
+        for (values, count) in counts.iter() {
+            let labels = counter
+                .labels
+                .iter()
+                .zip(values)
+                .map(|(l, v)| (*l, v.as_str()))
+                .collect::<Vec<_>>();
+            let _ =
+                writeln!(out, "{}{} {}", name, format_labels(&labels), count);
+        }
+    }
+}
+
+fn write_histogram(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    histogram: &Histogram,
+) {
+    write_header(out, name, "histogram", help);
+    if let Ok(data) = histogram.data.lock() {
+        let mut cumulative = 0;
+        for (i, bound) in histogram.bounds.iter().enumerate() {
+            cumulative += data.buckets.get(i).copied().unwrap_or(0);
+            let _ = writeln!(
+                out,
+                "{}_bucket{{le=\"{}\"}} {}",
+                name, bound, cumulative
+            );
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, data.count);
+        let _ = writeln!(out, "{}_sum {}", name, data.sum);
+        let _ = writeln!(out, "{}_count {}", name, data.count);
+    }
+}
+
+fn write_gauge(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    values: &[(Vec<(&str, &str)>, f64)],
+) {
+    write_header(out, name, "gauge", help);
+    for (labels, value) in values {
+        let _ = writeln!(out, "{}{} {}", name, format_labels(labels), value);
+    }
+}
+
+/// All metrics in the Prometheus text format
+pub fn render() -> String {
+    let mut out = String::new();
+
+    write_counter(
+        &mut out,
+        "led_foot_frames_sent_total",
+        "Frames sent to the outputs",
+        &FRAMES_SENT,
+    );
+    write_counter(
+        &mut out,
+        "led_foot_frames_skipped_total",
+        "Frames skipped because the previous frame ran late",
+        &FRAMES_SKIPPED,
+    );
+    write_histogram(
+        &mut out,
+        "led_foot_frame_lateness_seconds",
+        "How late frames started after their deadline",
+        &FRAME_LATENESS,
+    );
+    write_histogram(
+        &mut out,
+        "led_foot_serial_round_trip_seconds",
+        "Time to send a command to the Arduino and read its reply",
+        &SERIAL_ROUND_TRIP,
+    );
+    write_labeled_counter(
+        &mut out,
+        "led_foot_serial_replies_total",
+        "Replies from the Arduino, by expected and received reply",
+        &SERIAL_REPLIES,
+    );
+    write_counter(
+        &mut out,
+        "led_foot_serial_reconnects_total",
+        "Times the serial port was reopened after failing",
+        &SERIAL_RECONNECTS,
+    );
+    write_counter(
+        &mut out,
+        "led_foot_serial_coalesced_total",
+        "Colors replaced by a newer one before being sent to the Arduino",
+        &SERIAL_COALESCED,
+    );
+    write_histogram(
+        &mut out,
+        "led_foot_state_lock_wait_seconds",
+        "Time the LED worker waited for the state lock",
+        &STATE_LOCK_WAIT,
+    );
+    write_labeled_counter(
+        &mut out,
+        "led_foot_http_requests_total",
+        "HTTP requests, by route, method and status",
+        &HTTP_REQUESTS,
+    );
+
+    if let Ok(state) = LED_STATE.get().read() {
+        let sequence = state
+            .current_sequence
+            .as_ref()
+            .map(|seq| seq.info.name.clone());
+        write_gauge(
+            &mut out,
+            "led_foot_active_sequence",
+            "Sequence that's running, if any",
+            &sequence
+                .iter()
+                .map(|name| (vec![("name", name.as_str())], 1.0))
+                .collect::<Vec<_>>(),
+        );
+        write_gauge(
+            &mut out,
+            "led_foot_live_input",
+            "Whether lighting software is controlling the LEDs over DMX",
+            &[(vec![], f64::from(u8::from(state.live_frame.is_some())))],
+        );
+    }
+
+    if let Ok(outputs) = LED_OUTPUTS.get().read() {
+        let statuses = outputs
+            .iter()
+            .map(|output| output.status())
+            .collect::<Vec<_>>();
+        let kinds = statuses
+            .iter()
+            .map(|status| format!("{:?}", status.kind).to_lowercase())
+            .collect::<Vec<_>>();
+        write_gauge(
+            &mut out,
+            "led_foot_output_connected",
+            "Whether each output is able to show colors",
+            &statuses
+                .iter()
+                .zip(&kinds)
+                .map(|(status, kind)| {
+                    (
+                        vec![("output", kind.as_str())],
+                        f64::from(u8::from(status.connected)),
+                    )
+                })
+                .collect::<Vec<_>>(),
+        );
+    }
+
+    write_gauge(
+        &mut out,
+        "led_foot_uptime_seconds",
+        "Time since the server started",
+        &[(vec![], STARTED.get().elapsed().as_secs_f64())],
+    );
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_format() {
+        static HISTOGRAM: Histogram = Histogram::new(&[0.1, 1.0]);
+        HISTOGRAM.observe(0.0625);
+        HISTOGRAM.observe(0.5);
+        HISTOGRAM.observe(4.0);
+
+        let mut out = String::new();
+        write_histogram(&mut out, "test_seconds", "Test", &HISTOGRAM);
+        assert!(out.contains("test_seconds_bucket{le=\"0.1\"} 1\n"));
+        assert!(out.contains("test_seconds_bucket{le=\"1\"} 2\n"));
+        assert!(out.contains("test_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(out.contains("test_seconds_sum 4.5625\n"));
+        assert!(out.contains("test_seconds_count 3\n"));
+    }
+
+    #[test]
+    fn test_labeled_counter_format() {
+        static COUNTER: LabeledCounter = LabeledCounter::new(&["route"]);
+        COUNTER.inc(&["/api/\"quoted\""]);
+        COUNTER.inc(&["/api/\"quoted\""]);
+
+        let mut out = String::new();
+        write_labeled_counter(&mut out, "test_total", "Test", &COUNTER);
+        assert!(out.contains("# TYPE test_total counter\n"));
+        assert!(out.contains("test_total{route=\"/api/\\\"quoted\\\"\"} 2\n"));
+    }
+}
@@ -0,0 +1,67 @@
+//! Output that shows colors in the terminal instead of on real LEDs
+
+use crate::color::Color;
+use crate::led_output::{LedOutput, OutputKind, OutputStatus};
+use crate::rooms::Rooms;
+
+// Defaults for the mockup
+const DEFAULT_MOCKUP_SPAN: usize = 1;
+
+/// Prints every `span`th color as bars of truecolor `#`s
+pub struct MockOutput {
+    index: usize,
+    span: usize,
+}
+
+impl MockOutput {
+    pub fn new() -> Self {
+        Self {
+            index: 0,
+            span: DEFAULT_MOCKUP_SPAN,
+        }
+    }
+}
+
+impl Default for MockOutput {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LedOutput for MockOutput {
+    fn send_color(&mut self, color: &Color) {
+        self.index += 1;
+        if self.index.is_multiple_of(self.span) {
+            println!("{}\n", ansi_color_bars(color, 80));
+        }
+    }
+
+    fn send_rooms(&mut self, rooms: &Rooms) {
+        println!("Serial Mockup: {:?}", rooms);
+    }
+
+    fn status(&self) -> OutputStatus {
+        OutputStatus {
+            kind: OutputKind::Mock,
+            connected: true,
+            detail: "Printing colors to stdout".to_string(),
+        }
+    }
+}
+
+/// Two bars of `#`s in truecolor, the RGB components on the first line and
+/// the white component on the second
+pub fn ansi_color_bars(color: &Color, width: usize) -> String {
+    let to_byte = |c: f32| (c * f32::from(u8::MAX)) as u8;
+    format!(
+        "\x1b[38;2;{};{};{}m{}\x1b[0m\n\x1b[38;2;{};{};{}m{}\x1b[0m",
+        to_byte(color.r),
+        to_byte(color.g),
+        to_byte(color.b),
+        "#".repeat(width),
+        to_byte(color.w),
+        to_byte(color.w),
+        to_byte(color.w),
+        "#".repeat(width),
+    )
+}
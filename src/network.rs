@@ -0,0 +1,38 @@
+//! Details about this machine's network, for protocols that identify or
+//! advertise the server
+
+use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+
+/// MAC address of the first real network interface, as 12 lowercase hex
+/// digits. Clients use it to tell devices apart, so it needs to be stable.
+pub fn mac_address() -> String {
+    let Ok(interfaces) = std::fs::read_dir("/sys/class/net") else {
+        return "000000000000".to_string();
+    };
+    let mut interfaces = interfaces
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_name() != "lo")
+        .collect::<Vec<_>>();
+    interfaces.sort_by_key(|e| e.file_name());
+
+    interfaces
+        .iter()
+        .filter_map(|e| std::fs::read_to_string(e.path().join("address")).ok())
+        .map(|address| address.trim().to_lowercase().replace(':', ""))
+        .find(|address| address.len() == 12 && address != "000000000000")
+        .unwrap_or_else(|| "000000000000".to_string())
+}
+
+/// IP address other machines on the LAN can reach this one on
+///
+/// Connecting a UDP socket doesn't send anything, it just picks the interface
+/// that would be used for the default route.
+pub fn local_ip() -> IpAddr {
+    UdpSocket::bind("0.0.0.0:0")
+        .and_then(|socket| {
+            socket.connect("192.0.2.1:80")?;
+            socket.local_addr()
+        })
+        .map(|addr| addr.ip())
+        .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST))
+}
@@ -0,0 +1,238 @@
+use std::collections::VecDeque;
+use std::time::Instant;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::color::Color;
+use crate::led_sequence::LedSequence;
+
+/// How long a playlist item stays active before moving on to the next one
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum PlaylistItemMode {
+    /// Play the sequence through once (one loop, for repeating sequences)
+    #[default]
+    Once,
+    /// Play a repeating sequence this many times
+    Repeat(usize),
+    /// Keep the sequence (or the color it ends on) for this many seconds
+    Hold(f32),
+    /// Keep playing until skipped or cleared
+    Loop,
+}
+
+/// A single sequence in the playlist
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlaylistItem {
+    /// Sequence name, same as the payload for `/api/set-sequence`
+    pub sequence: String,
+    #[serde(default)]
+    pub mode: PlaylistItemMode,
+}
+
+/// Ordered list of sequences that the `LedSystem` worker advances through
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Playlist {
+    /// Item that's currently playing, if any
+    pub current: Option<PlaylistItem>,
+    /// Items that will play after the current one, in order
+    pub queue: VecDeque<PlaylistItem>,
+    #[serde(skip)]
+    started: Option<Instant>,
+}
+
+impl Playlist {
+    /// Add an item to the end of the playlist
+    pub fn enqueue(&mut self, item: PlaylistItem) {
+        self.queue.push_back(item);
+    }
+
+    /// Stop the current item and remove everything from the playlist
+    pub fn clear(&mut self) {
+        self.queue.clear();
+        self.current = None;
+        self.started = None;
+    }
+
+    /// Stop the current item so the next one starts on the following frame
+    pub fn skip(&mut self) {
+        self.current = None;
+        self.started = None;
+    }
+
+    /// Is the playlist in charge of what the LEDs are doing?
+    pub fn is_active(&self) -> bool {
+        self.current.is_some() || !self.queue.is_empty()
+    }
+
+    /// Has the current item run its course, given the sequence that's
+    /// currently playing? Also true if there's no current item.
+    pub fn current_finished(&self, sequence: Option<&LedSequence>) -> bool {
+        let Some(ref item) = self.current else {
+            return true;
+        };
+
+        match item.mode {
+            PlaylistItemMode::Once => {
+                sequence.is_none_or(|seq| seq.loop_count() >= 1)
+            }
+            PlaylistItemMode::Repeat(times) => {
+                sequence.is_none_or(|seq| seq.loop_count() >= times)
+            }
+            PlaylistItemMode::Hold(seconds) => self
+                .started
+                .is_none_or(|t| t.elapsed().as_secs_f32() >= seconds),
+            PlaylistItemMode::Loop => sequence.is_none(),
+        }
+    }
+
+    /// Move on to the next item in the playlist, returning it (if there is
+    /// one)
+    pub fn advance(&mut self) -> Option<PlaylistItem> {
+        self.current = self.queue.pop_front();
+        self.started = self.current.as_ref().map(|_| Instant::now());
+        self.current.clone()
+    }
+
+    /// Move on to the next item whose sequence loads, returning the
+    /// sequence. Items that fail to load are skipped.
+    pub fn advance_sequence(
+        &mut self,
+        fade_from: &Color,
+    ) -> Option<LedSequence> {
+        while let Some(item) = self.advance() {
+            debug!("Playlist advanced to {:?}", item);
+            match LedSequence::from_name(fade_from, &item.sequence) {
+                Ok(seq) => return Some(seq),
+                Err(e) => error!(
+                    "Unable to load playlist sequence {:?}: {:?}",
+                    item.sequence, e
+                ),
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::effects;
+    use crate::led_sequence::{LedSequenceInfo, LedSequenceType, RESOLUTION};
+
+    fn item(sequence: &str, mode: PlaylistItemMode) -> PlaylistItem {
+        PlaylistItem {
+            sequence: sequence.to_string(),
+            mode,
+        }
+    }
+
+    /// Breathe with a 1s period, after the 1s initial fade
+    fn breathe() -> LedSequence {
+        let mut sequence_type = effects::default_effect("Breathe").unwrap();
+        if let LedSequenceType::Breathe(ref mut params) = sequence_type {
+            params.period = 1.0;
+        }
+        let info = LedSequenceInfo {
+            sequence_type,
+            name: "Breathe".to_string(),
+            duration: 0.0,
+            repeat: true,
+        };
+        LedSequence::from_effect(&Color::default(), info).unwrap()
+    }
+
+    /// Play `seconds` of a sequence
+    fn play(mut seq: &mut LedSequence, seconds: f32) {
+        let frames = (seconds * RESOLUTION).round() as usize;
+        assert_eq!(seq.by_ref().take(frames).count(), frames);
+    }
+
+    #[test]
+    fn test_once_and_repeat() {
+        let mut playlist = Playlist::default();
+        assert!(playlist.current_finished(None));
+
+        playlist.enqueue(item("breathe", PlaylistItemMode::Once));
+        playlist.enqueue(item("breathe", PlaylistItemMode::Repeat(3)));
+        playlist.advance();
+        let mut seq = breathe();
+        assert!(playlist.current_finished(None));
+        assert!(!playlist.current_finished(Some(&seq)));
+        // Finished once the first frame of the next loop is played
+        play(&mut seq, 2.0);
+        assert!(!playlist.current_finished(Some(&seq)));
+        play(&mut seq, 1.0 / RESOLUTION);
+        assert!(playlist.current_finished(Some(&seq)));
+
+        playlist.advance();
+        let mut seq = breathe();
+        play(&mut seq, 4.0);
+        assert!(!playlist.current_finished(Some(&seq)));
+        play(&mut seq, 1.0 / RESOLUTION);
+        assert!(playlist.current_finished(Some(&seq)));
+    }
+
+    #[test]
+    fn test_hold_and_loop() {
+        let mut playlist = Playlist::default();
+        playlist.enqueue(item("breathe", PlaylistItemMode::Hold(0.0)));
+        playlist.enqueue(item("breathe", PlaylistItemMode::Hold(60.0)));
+        playlist.enqueue(item("breathe", PlaylistItemMode::Loop));
+        let mut seq = breathe();
+
+        playlist.advance();
+        assert!(playlist.current_finished(Some(&seq)));
+
+        playlist.advance();
+        assert!(!playlist.current_finished(Some(&seq)));
+        assert!(!playlist.current_finished(None));
+
+        playlist.advance();
+        play(&mut seq, 10.0);
+        assert!(!playlist.current_finished(Some(&seq)));
+        assert!(playlist.current_finished(None));
+    }
+
+    #[test]
+    fn test_advance_skip_clear() {
+        let mut playlist = Playlist::default();
+        assert!(!playlist.is_active());
+        assert_eq!(playlist.advance(), None);
+
+        let first = item("first", PlaylistItemMode::Loop);
+        let second = item("second", PlaylistItemMode::Once);
+        playlist.enqueue(first.clone());
+        playlist.enqueue(second.clone());
+        assert!(playlist.is_active());
+        assert_eq!(playlist.advance(), Some(first));
+        assert!(!playlist.current_finished(Some(&breathe())));
+
+        playlist.skip();
+        assert!(playlist.current.is_none());
+        assert!(playlist.current_finished(Some(&breathe())));
+        assert_eq!(playlist.advance(), Some(second));
+
+        playlist.enqueue(item("third", PlaylistItemMode::Once));
+        playlist.clear();
+        assert!(!playlist.is_active());
+        assert_eq!(playlist.advance(), None);
+    }
+
+    #[test]
+    fn test_advance_past_failed_load() {
+        let mut playlist = Playlist::default();
+        playlist.enqueue(item("missing.png", PlaylistItemMode::Once));
+        playlist.enqueue(item("fade-to-black-2", PlaylistItemMode::Once));
+        playlist.enqueue(item("missing.png", PlaylistItemMode::Once));
+
+        let seq = playlist.advance_sequence(&Color::default()).unwrap();
+        assert_eq!(seq.duration(), 2.0);
+        assert_eq!(
+            playlist.current,
+            Some(item("fade-to-black-2", PlaylistItemMode::Once))
+        );
+
+        assert!(playlist.advance_sequence(&Color::default()).is_none());
+        assert!(!playlist.is_active());
+    }
+}
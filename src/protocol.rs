@@ -0,0 +1,320 @@
+//! The serial protocol between the server and the Arduino
+//!
+//! Version 1 is 9 fixed bytes: a command byte (`COLOR_CMD` or `ROOM_CMD`)
+//! then 8 bytes of payload, answered by `C\r\n`, `R\r\n` or `X\r\n` for an
+//! unknown command. There's no way to find the start of a command again if a
+//! byte goes missing.
+//!
+//! Version 2 sends frames:
+//!
+//! ```text
+//! START | LEN | CMD | SEQ | PAYLOAD (LEN bytes) | CRC (2 bytes)
+//! ```
+//!
+//! The CRC is CRC-16/CCITT-FALSE, big-endian, over LEN through the payload.
+//! Each frame is answered by an `ACK` frame with the same SEQ and the
+//! command it acknowledges, or a `NAK` with an error code. Anything that
+//! isn't a valid frame is skipped by scanning for the next START byte, so
+//! lost or corrupted bytes only cost the frames they were in.
+//!
+//! Both ends start out speaking version 1. The server sends `HELLO`, which
+//! is 9 bytes long like a v1 command: v1 firmware answers `X\r\n` and the
+//! server stays on v1, while v2 firmware answers with an `ACK` frame and
+//! both switch to v2.
+
+use crate::rooms::Rooms;
+
+/// Version 1 command to set the color
+pub const COLOR_CMD: u8 = 0xC0;
+/// Version 1 command to set the room relays
+pub const ROOM_CMD: u8 = 0xF0;
+
+// Magic numbers for each room, in version 1 room commands
+pub const LIVING_ROOM: u8 = 0x1A;
+pub const OFFICE: u8 = 0x1C;
+pub const BEDROOM: u8 = 0x18;
+
+/// Length of a version 1 command
+pub const UPDATE_BYTES: usize = 9;
+/// Length of a version 1 reply, and of the `I\r\n` sent on startup
+pub const CONFIRMATION_BYTES: usize = 3;
+
+/// Latest protocol version
+pub const VERSION: u8 = 2;
+
+/// First byte of every version 2 frame
+pub const START: u8 = 0xA5;
+/// Longest payload a version 2 frame can have
+pub const MAX_PAYLOAD: usize = 16;
+/// Bytes in a version 2 frame besides the payload: START, LEN, CMD, SEQ and
+/// the CRC
+pub const OVERHEAD: usize = 6;
+/// Most frames that can be waiting for an `ACK` at once. The Arduino's
+/// 64-byte receive buffer holds 4 color frames.
+pub const MAX_ACK_WINDOW: u8 = 4;
+
+/// Ask for version 2; payload is `HELLO_MAGIC` then the highest version the
+/// sender speaks
+pub const CMD_HELLO: u8 = 0x48;
+/// Payload is the R, G, B and W levels, 16-bit big-endian
+pub const CMD_COLOR: u8 = COLOR_CMD;
+/// Payload is one byte with a bit for each room (see `rooms_to_bits`)
+pub const CMD_ROOMS: u8 = ROOM_CMD;
+/// Fade linearly from the current color; payload is the flags (e.g.
+/// `FADE_APPEND`), the R, G, B and W levels to end on, then the duration
+/// in milliseconds, 32-bit big-endian. A color command stops the fade.
+pub const CMD_FADE: u8 = 0xFA;
+/// Reply to a frame that was applied; payload is the command, then for
+/// `HELLO` the version that will be used
+pub const CMD_ACK: u8 = 0x06;
+/// Reply to a frame that wasn't applied; payload is one of the `NAK_` codes
+pub const CMD_NAK: u8 = 0x15;
+
+pub const HELLO_MAGIC: [u8; 2] = *b"LF";
+
+/// Play the fade after the ones already queued, instead of replacing them
+pub const FADE_APPEND: u8 = 0x01;
+/// Most fades the firmware can queue up
+pub const MAX_FADES: usize = 16;
+
+/// The frame's CRC didn't match
+pub const NAK_CRC: u8 = 0x01;
+/// The command isn't one the firmware knows
+pub const NAK_UNKNOWN: u8 = 0x02;
+/// The payload is the wrong length for the command
+pub const NAK_LENGTH: u8 = 0x03;
+/// There are already `MAX_FADES` fades queued
+pub const NAK_FULL: u8 = 0x04;
+
+/// A version 2 frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub command: u8,
+    pub seq: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(command: u8, seq: u8, payload: &[u8]) -> Self {
+        debug_assert!(payload.len() <= MAX_PAYLOAD);
+        Self {
+            command,
+            seq,
+            payload: payload.to_vec(),
+        }
+    }
+
+    pub fn hello(seq: u8) -> Self {
+        let [l, f] = HELLO_MAGIC;
+        Self::new(CMD_HELLO, seq, &[l, f, VERSION])
+    }
+
+    pub fn color(seq: u8, levels: &[u16; 4]) -> Self {
+        Self::new(CMD_COLOR, seq, &levels_to_bytes(levels))
+    }
+
+    pub fn rooms(seq: u8, rooms: &Rooms) -> Self {
+        Self::new(CMD_ROOMS, seq, &[rooms_to_bits(rooms)])
+    }
+
+    pub fn fade(
+        seq: u8,
+        levels: &[u16; 4],
+        duration_ms: u32,
+        append: bool,
+    ) -> Self {
+        let mut payload = vec![if append { FADE_APPEND } else { 0 }];
+        payload.extend(levels_to_bytes(levels));
+        payload.extend(duration_ms.to_be_bytes());
+        Self::new(CMD_FADE, seq, &payload)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.payload.len() + OVERHEAD);
+        bytes.extend([START, self.payload.len() as u8, self.command, self.seq]);
+        bytes.extend(&self.payload);
+        let crc = crc16(&bytes[1..]);
+        bytes.extend(crc.to_be_bytes());
+        bytes
+    }
+
+    /// Is this an `ACK` for `command`?
+    pub fn acks(&self, command: u8) -> bool {
+        self.command == CMD_ACK && self.payload.first() == Some(&command)
+    }
+}
+
+/// Why some bytes weren't a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// The CRC didn't match, for the frame with this (possibly corrupted)
+    /// sequence number
+    Crc { seq: u8 },
+}
+
+/// Pulls frames out of a stream of bytes, skipping anything that isn't one
+#[derive(Debug, Clone, Default)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Forget any partial frame
+    pub fn reset(&mut self) {
+        self.buf.clear();
+    }
+
+    /// The next frame (or bad frame) in the bytes so far, if there's a whole
+    /// one. Call until it returns `None` to get all of them.
+    pub fn next_frame(&mut self) -> Option<Result<Frame, FrameError>> {
+        loop {
+            // Drop anything before the next START byte
+            let Some(start) = self.buf.iter().position(|&b| b == START) else {
+                self.buf.clear();
+                return None;
+            };
+            self.buf.drain(..start);
+
+            let len = usize::from(*self.buf.get(1)?);
+            if len > MAX_PAYLOAD {
+                // Not really a START byte, so look for the next one
+                self.buf.remove(0);
+                continue;
+            }
+            let total = len + OVERHEAD;
+            if self.buf.len() < total {
+                return None;
+            }
+
+            let crc =
+                u16::from_be_bytes([self.buf[total - 2], self.buf[total - 1]]);
+            if crc16(&self.buf[1..total - 2]) != crc {
+                let seq = self.buf[3];
+                self.buf.remove(0);
+                return Some(Err(FrameError::Crc { seq }));
+            }
+
+            let frame =
+                Frame::new(self.buf[2], self.buf[3], &self.buf[4..total - 2]);
+            self.buf.drain(..total);
+            return Some(Ok(frame));
+        }
+    }
+}
+
+/// CRC-16/CCITT-FALSE (polynomial 0x1021, starting from 0xFFFF)
+pub fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for &byte in bytes {
+        crc ^= u16::from(byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// R, G, B and W levels as 16-bit big-endian bytes
+pub fn levels_to_bytes(levels: &[u16; 4]) -> [u8; 8] {
+    let [red, green, blue, white] = levels.map(u16::to_be_bytes);
+    [
+        red[0], red[1], green[0], green[1], blue[0], blue[1], white[0],
+        white[1],
+    ]
+}
+
+pub fn bytes_to_levels(bytes: &[u8; 8]) -> [u16; 4] {
+    [0, 2, 4, 6].map(|i| u16::from_be_bytes([bytes[i], bytes[i + 1]]))
+}
+
+/// Bit 0 for the living room, 1 for the office and 2 for the bedroom
+pub fn rooms_to_bits(rooms: &Rooms) -> u8 {
+    u8::from(rooms.living_room)
+        | u8::from(rooms.office) << 1
+        | u8::from(rooms.bedroom) << 2
+}
+
+pub fn bits_to_rooms(bits: u8) -> Rooms {
+    Rooms {
+        living_room: bits & 1 != 0,
+        office: bits & 1 << 1 != 0,
+        bedroom: bits & 1 << 2 != 0,
+    }
+}
+
+/// Convert 16-bit levels to a version 1 color command
+pub fn color_to_bytes(levels: &[u16; 4]) -> [u8; UPDATE_BYTES] {
+    let mut bytes = [COLOR_CMD; UPDATE_BYTES];
+    bytes[1..].copy_from_slice(&levels_to_bytes(levels));
+    bytes
+}
+
+/// Convert to a version 1 room command
+pub fn rooms_to_bytes(rooms: &Rooms) -> [u8; UPDATE_BYTES] {
+    [
+        ROOM_CMD,
+        if rooms.living_room { LIVING_ROOM } else { 0x00 },
+        if rooms.office { OFFICE } else { 0x00 },
+        if rooms.bedroom { BEDROOM } else { 0x00 },
+        0x00,
+        0x00,
+        0x00,
+        0x00,
+        0x00,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc16() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn test_frames() {
+        let levels = [0xFFFF, 0x1234, 0, 1];
+        let color = Frame::color(7, &levels);
+        let two_rooms = Rooms {
+            living_room: true,
+            office: false,
+            bedroom: true,
+        };
+        let rooms = Frame::rooms(8, &two_rooms);
+        // HELLO has to be as long as a version 1 command
+        assert_eq!(Frame::hello(0).encode().len(), UPDATE_BYTES);
+
+        // Noise, a frame cut short, then whole frames
+        let mut decoder = FrameDecoder::new();
+        let mut cut = color.encode();
+        cut.truncate(6);
+        decoder.extend(&[0x00, 0x42]);
+        decoder.extend(&cut);
+        decoder.extend(&color.encode());
+        decoder.extend(&rooms.encode());
+
+        assert_eq!(decoder.next_frame(), Some(Err(FrameError::Crc { seq: 7 })));
+        assert_eq!(decoder.next_frame(), Some(Ok(color.clone())));
+        assert_eq!(decoder.next_frame(), Some(Ok(rooms.clone())));
+        assert_eq!(decoder.next_frame(), None);
+
+        let payload: [u8; 8] = color.payload[..].try_into().unwrap();
+        assert_eq!(bytes_to_levels(&payload), levels);
+        assert_eq!(rooms.payload, vec![0b101]);
+        assert_eq!(bits_to_rooms(rooms.payload[0]), two_rooms);
+    }
+}
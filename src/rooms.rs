@@ -8,8 +8,22 @@ pub enum Room {
     Bedroom,
 }
 
+impl std::str::FromStr for Room {
+    type Err = String;
+
+    /// Parse a room name like `living_room`, `living-room` or `LivingRoom`
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_lowercase().replace(['_', '-', ' '], "").as_str() {
+            "livingroom" | "living" => Ok(Room::LivingRoom),
+            "office" => Ok(Room::Office),
+            "bedroom" => Ok(Room::Bedroom),
+            _ => Err(format!("No room named {:?}", name)),
+        }
+    }
+}
+
 /// Control which rooms are currently active
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
 pub struct Rooms {
     pub living_room: bool,
     pub office: bool,
@@ -29,6 +43,24 @@ impl Rooms {
         }
     }
 
+    /// Is this room on?
+    pub fn is_on(&self, room: &Room) -> bool {
+        match room {
+            Room::LivingRoom => self.living_room,
+            Room::Office => self.office,
+            Room::Bedroom => self.bedroom,
+        }
+    }
+
+    /// Turn one room on or off, leaving the others as they are
+    pub fn set(&mut self, room: &Room, on: bool) {
+        match room {
+            Room::LivingRoom => self.living_room = on,
+            Room::Office => self.office = on,
+            Room::Bedroom => self.bedroom = on,
+        }
+    }
+
     pub fn set_active_rooms(&mut self, active_rooms: &Self) {
         *self = active_rooms.clone();
     }
@@ -37,14 +69,14 @@ impl Rooms {
         &mut self,
         active_rooms: &ScheduledRoomState,
     ) {
-        *self = self.from_scheduled(active_rooms);
+        *self = self.with_scheduled(active_rooms);
     }
 
     pub fn active_rooms(&self) -> &Self {
-        &self
+        self
     }
 
-    fn from_scheduled(&self, scheduled: &ScheduledRoomState) -> Rooms {
+    fn with_scheduled(&self, scheduled: &ScheduledRoomState) -> Rooms {
         let living_room = scheduled.living_room.unwrap_or(self.living_room);
         let office = scheduled.office.unwrap_or(self.office);
         let bedroom = scheduled.bedroom.unwrap_or(self.bedroom);
@@ -56,16 +88,6 @@ impl Rooms {
     }
 }
 
-impl Default for Rooms {
-    fn default() -> Self {
-        Self {
-            living_room: false,
-            office: false,
-            bedroom: false,
-        }
-    }
-}
-
 /// For scheduled events, allow rooms to be unset
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ScheduledRoomState {
@@ -0,0 +1,69 @@
+//! Named presets of color, rooms and sequence that can be applied at once
+
+use std::collections::BTreeMap;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::color::Color;
+use crate::led_sequence::LedSequence;
+use crate::led_state::LedState;
+use crate::rooms::Rooms;
+
+/// Set in `[scenes.<name>]` sections of `led_config.toml`, e.g.
+///
+/// ```toml
+/// [scenes.movie]
+/// color = { r = 0.2, g = 0.0, b = 0.4, w = 0.0 }
+/// rooms = { living_room = true, office = false, bedroom = false }
+/// transition = 5.0
+///
+/// [scenes.wake-up]
+/// sequence = "led-foot-sequences/gradient_sunrise_600.png"
+/// ```
+///
+/// Anything left out of a scene is left as it is. Scene names are
+/// case-sensitive.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Scene {
+    pub color: Option<Color>,
+    pub rooms: Option<Rooms>,
+    /// Sequence to play, by the name `/api/set-sequence` takes. Takes
+    /// precedence over the color.
+    pub sequence: Option<String>,
+    /// Seconds to fade to the color over
+    pub transition: Option<f32>,
+}
+
+pub type Scenes = BTreeMap<String, Scene>;
+
+impl Scene {
+    /// Switch the LEDs over to the scene
+    ///
+    /// Takes over from the playlist, if one is running
+    pub fn apply(&self, state: &mut LedState) -> Result<(), std::io::Error> {
+        let sequence = if let Some(ref name) = self.sequence {
+            Some(LedSequence::from_name(&state.current_color, name)?)
+        } else {
+            self.color.as_ref().map(|color| match self.transition {
+                Some(duration) => LedSequence::from_color_transition(
+                    &state.current_color,
+                    color,
+                    duration,
+                ),
+                None => {
+                    LedSequence::from_color_lerp(&state.current_color, color)
+                }
+            })
+        };
+
+        if let Some(rooms) = self.rooms.clone() {
+            state.current_rooms = rooms;
+        }
+        if sequence.is_some() {
+            state.playlist.clear();
+            state.current_sequence = sequence;
+        }
+        Ok(())
+    }
+}
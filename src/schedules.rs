@@ -0,0 +1,139 @@
+//! Scenes applied at set times of day, from `[[schedules]]` in
+//! `led_config.toml`
+
+use std::time::Duration;
+
+use chrono::{Datelike, Local, NaiveDateTime, NaiveTime, Timelike, Weekday};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::led_state::{led_config, LED_STATE};
+
+/// How often to check whether a schedule is due
+const CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Apply a scene at a local time, e.g.
+///
+/// ```toml
+/// [[schedules]]
+/// at = "06:45"
+/// days = ["mon", "tue", "wed", "thu", "fri"]
+/// scene = "wake-up"
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Schedule {
+    /// Local time, as `HH:MM`
+    pub at: String,
+    /// Days of the week to run on, every day if left out
+    #[serde(default)]
+    pub days: Vec<String>,
+    /// Scene from `[scenes]` to apply
+    pub scene: String,
+}
+
+impl Schedule {
+    pub fn time(&self) -> Result<NaiveTime, String> {
+        NaiveTime::parse_from_str(&self.at, "%H:%M")
+            .map_err(|e| format!("Invalid time {:?} (use HH:MM): {}", self.at, e))
+    }
+
+    pub fn weekdays(&self) -> Result<Vec<Weekday>, String> {
+        self.days
+            .iter()
+            .map(|day| {
+                day.parse::<Weekday>()
+                    .map_err(|_| format!("Invalid day {:?}", day))
+            })
+            .collect()
+    }
+
+    /// Is the schedule due in the same minute as `now`?
+    pub fn is_due(&self, now: &NaiveDateTime) -> bool {
+        let (Ok(time), Ok(days)) = (self.time(), self.weekdays()) else {
+            return false;
+        };
+        (days.is_empty() || days.contains(&now.weekday()))
+            && time.hour() == now.hour()
+            && time.minute() == now.minute()
+    }
+}
+
+/// Apply the scenes of any schedules that are due
+fn run_due(now: &NaiveDateTime) {
+    let config = led_config();
+    for schedule in config.schedules.iter().filter(|s| s.is_due(now)) {
+        let Some(scene) = config.scenes.get(&schedule.scene) else {
+            error!("Scheduled scene {:?} doesn't exist", schedule.scene);
+            continue;
+        };
+
+        info!("Applying scheduled scene {:?}", schedule.scene);
+        if let Ok(mut state) = LED_STATE.get().write() {
+            if state.shutting_down {
+                info!("Shutting down, not applying {:?}", schedule.scene);
+                return;
+            }
+            if let Err(e) = scene.apply(&mut state) {
+                error!(
+                    "Unable to apply scheduled scene {:?}: {:?}",
+                    schedule.scene, e
+                );
+            }
+        } else {
+            error!("Unable to apply scheduled scene: can't get lock on state");
+        }
+    }
+}
+
+/// Check the schedules in the background, once per minute that goes by.
+/// Schedules are read from the current config each time, so they can be
+/// reloaded.
+pub fn start() -> Result<(), std::io::Error> {
+    std::thread::Builder::new()
+        .name("schedules".to_string())
+        .spawn(|| {
+            let mut last_minute = None;
+            loop {
+                let now = Local::now().naive_local();
+                let minute = (now.date(), now.hour(), now.minute());
+                if last_minute != Some(minute) {
+                    last_minute = Some(minute);
+                    run_due(&now);
+                }
+                std::thread::sleep(CHECK_INTERVAL);
+            }
+        })
+        .map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    #[test]
+    fn test_is_due() {
+        let schedule = Schedule {
+            at: "06:45".to_string(),
+            days: vec!["mon".to_string(), "Friday".to_string()],
+            scene: "wake-up".to_string(),
+        };
+        // 2024-03-04 is a Monday
+        let at = |day, h, m| {
+            NaiveDate::from_ymd_opt(2024, 3, day)
+                .unwrap()
+                .and_hms_opt(h, m, 30)
+                .unwrap()
+        };
+
+        assert!(schedule.is_due(&at(4, 6, 45)));
+        assert!(schedule.is_due(&at(8, 6, 45)));
+        assert!(!schedule.is_due(&at(5, 6, 45)));
+        assert!(!schedule.is_due(&at(4, 6, 46)));
+
+        let bad = Schedule {
+            at: "6:45pm".to_string(),
+            ..schedule
+        };
+        assert!(bad.time().is_err());
+    }
+}
@@ -0,0 +1,379 @@
+//! Effects written as Rhai scripts in `sequence_dir()`, so new looks can be
+//! made without recompiling
+//!
+//! A script defines `fn color_at(t, params)`, which gets the time in seconds
+//! since the effect started and the parameters from the API, and returns
+//! `[r, g, b, w]` (0.0 to 1.0). It can also define `fn duration()` to set how
+//! long the effect plays before looping. Scripts are reloaded whenever their
+//! file changes, but a changed `duration()` only takes effect the next time
+//! the effect is started.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use rhai::{Array, Dynamic, Engine, Scope, AST};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::color::Color;
+use crate::effects::DEFAULT_LOOP_DURATION;
+use crate::led_sequence::{plain_file, sequence_dir};
+use crate::sequence_source::SequenceSource;
+
+/// File extension of effect scripts
+pub const SCRIPT_EXTENSION: &str = "rhai";
+
+/// Most operations a script may run to compute a single frame
+const MAX_OPERATIONS: u64 = 100_000;
+
+/// Longest a script may run to compute a single frame, so a bad script can't
+/// stall the LedSystem worker
+const MAX_FRAME_TIME: Duration = Duration::from_millis(10);
+
+/// How often to check whether a running script's file has changed
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Which script to run, and what to give it as `params`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScriptParams {
+    /// Script file name in `sequence_dir()`, without the extension
+    pub name: String,
+    #[serde(default)]
+    pub params: serde_json::Value,
+}
+
+#[derive(Debug)]
+struct LoadedScript {
+    ast: AST,
+    modified: Option<SystemTime>,
+    checked: Instant,
+    last_color: Color,
+    failing: bool,
+}
+
+/// Sequence whose colors come from a script's `color_at`
+pub struct ScriptSource {
+    path: PathBuf,
+    engine: Engine,
+    params: Dynamic,
+    duration: f32,
+    loaded: Mutex<LoadedScript>,
+}
+
+impl std::fmt::Debug for ScriptSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ScriptSource")
+            .field("path", &self.path)
+            .field("params", &self.params)
+            .field("duration", &self.duration)
+            .finish()
+    }
+}
+
+impl ScriptSource {
+    /// Load and compile a script, which loops every `duration` seconds unless
+    /// the script defines its own `duration()`. If neither is positive, it
+    /// loops every `DEFAULT_LOOP_DURATION` seconds.
+    pub fn load(
+        script: &ScriptParams,
+        duration: f32,
+    ) -> Result<Self, std::io::Error> {
+        Self::load_path(script_path(&script.name)?, &script.params, duration)
+    }
+
+    fn load_path(
+        path: PathBuf,
+        params: &serde_json::Value,
+        duration: f32,
+    ) -> Result<Self, std::io::Error> {
+        let engine = sandboxed_engine();
+        let ast = compile(&engine, &path)?;
+
+        let params = rhai::serde::to_dynamic(params)
+            .map_err(|e| invalid_script(&path, e))?;
+
+        let duration = script_duration(&engine, &ast)
+            .into_iter()
+            .chain([duration])
+            .find(|d| *d > 0.0)
+            .unwrap_or(DEFAULT_LOOP_DURATION);
+
+        debug!("Loaded script {:?} with duration {}s", path, duration);
+
+        Ok(Self {
+            loaded: Mutex::new(LoadedScript {
+                ast,
+                modified: modified_time(&path),
+                checked: Instant::now(),
+                last_color: Color::default(),
+                failing: false,
+            }),
+            path,
+            engine,
+            params,
+            duration,
+        })
+    }
+
+    /// Recompile the script if its file has changed since it was loaded,
+    /// checking at most every `RELOAD_CHECK_INTERVAL`
+    fn reload_if_changed(&self, loaded: &mut LoadedScript) {
+        if loaded.checked.elapsed() < RELOAD_CHECK_INTERVAL {
+            return;
+        }
+        loaded.checked = Instant::now();
+
+        let modified = modified_time(&self.path);
+        if modified == loaded.modified {
+            return;
+        }
+        loaded.modified = modified;
+
+        match compile(&self.engine, &self.path) {
+            Ok(ast) => {
+                info!("Reloaded script {:?}", self.path);
+                // The sequence's frame count is already fixed, so keep
+                // playing with the old duration
+                let duration = script_duration(&self.engine, &ast);
+                if duration.is_some_and(|d| d > 0.0 && d != self.duration) {
+                    warn!(
+                        "Duration of script {:?} changed, restart it to use \
+                         the new one",
+                        self.path
+                    );
+                }
+                loaded.ast = ast;
+                loaded.failing = false;
+            }
+            Err(e) => error!("Unable to reload script, keeping old one: {}", e),
+        }
+    }
+}
+
+impl SequenceSource for ScriptSource {
+    fn color_at(&self, t: f32) -> Color {
+        let Ok(mut loaded) = self.loaded.lock() else {
+            return Color::default();
+        };
+        self.reload_if_changed(&mut loaded);
+
+        let result = self.engine.call_fn::<Array>(
+            &mut Scope::new(),
+            &loaded.ast,
+            "color_at",
+            (t as rhai::FLOAT, self.params.clone()),
+        );
+
+        match result.map_err(|e| e.to_string()).and_then(array_to_color) {
+            Ok(color) => {
+                loaded.failing = false;
+                loaded.last_color = color.clamped();
+            }
+            Err(e) => {
+                // Only complain once, instead of every frame
+                if !loaded.failing {
+                    error!("Script {:?} failed: {}", self.path, e);
+                    loaded.failing = true;
+                }
+            }
+        }
+
+        loaded.last_color.clone()
+    }
+
+    fn duration(&self) -> f32 {
+        self.duration
+    }
+}
+
+/// Engine without any access to the outside world, and with limits on how
+/// much work a script can do per call
+fn sandboxed_engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.disable_symbol("eval");
+    engine.set_module_resolver(
+        rhai::module_resolvers::DummyModuleResolver::new(),
+    );
+    engine.on_print(|text| debug!("Script printed: {}", text));
+    engine.on_debug(|text, _, pos| {
+        debug!("Script debug at {}: {}", pos, text)
+    });
+    engine.set_max_operations(MAX_OPERATIONS);
+    engine.set_max_call_levels(32);
+    engine.set_max_expr_depths(64, 32);
+    engine.set_max_string_size(1024);
+    engine.set_max_array_size(1024);
+    engine.set_max_map_size(256);
+
+    // Operation counts don't catch slow built-ins, so also limit by time.
+    // Progress restarts from zero on every call.
+    let started = Arc::new(Mutex::new(Instant::now()));
+    engine.on_progress(move |operations| {
+        let Ok(mut started) = started.lock() else {
+            return None;
+        };
+        if operations <= 1 {
+            *started = Instant::now();
+            None
+        } else if started.elapsed() > MAX_FRAME_TIME {
+            Some("script took too long".into())
+        } else {
+            None
+        }
+    });
+
+    engine
+}
+
+fn script_path(name: &str) -> Result<PathBuf, std::io::Error> {
+    // An empty name becomes `.rhai`, which isn't a plain name either
+    plain_file(&sequence_dir(), &format!("{}.{}", name, SCRIPT_EXTENSION))
+}
+
+fn compile(engine: &Engine, path: &Path) -> Result<AST, std::io::Error> {
+    let source = std::fs::read_to_string(path)?;
+    engine
+        .compile(&source)
+        .map_err(|e| invalid_script(path, e))
+}
+
+fn script_duration(engine: &Engine, ast: &AST) -> Option<f32> {
+    engine
+        .call_fn::<Dynamic>(&mut Scope::new(), ast, "duration", ())
+        .ok()
+        .and_then(|d| as_number(&d))
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn invalid_script(path: &Path, e: impl std::fmt::Display) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("Invalid script {:?}: {}", path, e),
+    )
+}
+
+fn as_number(value: &Dynamic) -> Option<f32> {
+    value
+        .as_float()
+        .ok()
+        .or_else(|| value.as_int().ok().map(|i| i as rhai::FLOAT))
+        .map(|f| f as f32)
+}
+
+fn array_to_color(array: Array) -> Result<Color, String> {
+    let components = array
+        .iter()
+        .map(as_number)
+        .collect::<Option<Vec<_>>>()
+        .filter(|c| c.len() == 4)
+        .ok_or_else(|| format!("expected [r, g, b, w], got {:?}", array))?;
+    Ok(Color::new(
+        components[0],
+        components[1],
+        components[2],
+        components[3],
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    fn load_script(dir: &TestDir, name: &str, source: &str) -> ScriptSource {
+        let path = dir.join(format!("{}.{}", name, SCRIPT_EXTENSION));
+        std::fs::write(&path, source).unwrap();
+        ScriptSource::load_path(path, &serde_json::Value::Null, 0.0).unwrap()
+    }
+
+    #[test]
+    fn test_runaway_script() {
+        let dir = TestDir::new("script");
+        let script = load_script(
+            &dir,
+            "runaway",
+            "fn color_at(t, params) {
+                if t < 1.0 { return [1.0, 0.5, 0.0, 0.25]; }
+                loop {}
+            }",
+        );
+        let good = Color::new(1.0, 0.5, 0.0, 0.25);
+        assert_eq!(script.color_at(0.0), good);
+
+        // Stopped by the operation limit or MAX_FRAME_TIME, whichever comes
+        // first, and falls back to the last good color
+        let started = Instant::now();
+        assert_eq!(script.color_at(2.0), good);
+        assert_eq!(script.color_at(3.0), good);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_script_cannot_import() {
+        let dir = TestDir::new("script");
+        let module = load_script(
+            &dir,
+            "module",
+            "fn color() { [1.0, 0.0, 0.0, 0.0] }
+            fn color_at(t, params) { color() }",
+        );
+        let script = load_script(
+            &dir,
+            "importer",
+            &format!(
+                "fn color_at(t, params) {{
+                    import {:?} as m;
+                    m::color()
+                }}",
+                module.path.with_extension(""),
+            ),
+        );
+        assert_eq!(module.color_at(0.0), Color::new(1.0, 0.0, 0.0, 0.0));
+        assert_eq!(script.color_at(0.0), Color::default());
+    }
+
+    #[test]
+    fn test_reload_is_throttled() {
+        let dir = TestDir::new("script");
+        let script = load_script(
+            &dir,
+            "reload",
+            "fn color_at(t, params) { [1.0, 0.0, 0.0, 0.0] }",
+        );
+        let red = Color::new(1.0, 0.0, 0.0, 0.0);
+        assert_eq!(script.color_at(0.0), red);
+
+        std::fs::write(
+            &script.path,
+            "fn color_at(t, params) { [0.0, 1.0, 0.0, 0.0] }",
+        )
+        .unwrap();
+        // Not checked again until RELOAD_CHECK_INTERVAL has passed
+        assert_eq!(script.color_at(0.0), red);
+
+        script.loaded.lock().unwrap().checked -= RELOAD_CHECK_INTERVAL;
+        assert_eq!(script.color_at(0.0), Color::new(0.0, 1.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn test_script_duration() {
+        let dir = TestDir::new("script");
+        let script = load_script(
+            &dir,
+            "no-duration",
+            "fn color_at(t, params) { [t / 60.0, 0.0, 0.0, 0.0] }",
+        );
+        assert_eq!(script.duration(), DEFAULT_LOOP_DURATION);
+
+        let script = load_script(
+            &dir,
+            "with-duration",
+            "fn duration() { 5 }
+            fn color_at(t, params) { [0.0, 0.0, 0.0, 0.0] }",
+        );
+        assert_eq!(script.duration(), 5.0);
+    }
+}
@@ -0,0 +1,474 @@
+//! Generators that produce the color of a sequence at any point in time,
+//! instead of storing every frame up front
+
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use crate::color::Color;
+
+/// Pieces of white's fade in `FadeToBlackSource::fade_segments`
+const FADE_TO_BLACK_SEGMENTS: usize = 8;
+
+/// Anything that can tell what color a sequence is at a given time
+pub trait SequenceSource: Debug + Send + Sync {
+    /// Color of the sequence `t` seconds after it started
+    fn color_at(&self, t: f32) -> Color;
+
+    /// How long the sequence lasts, in seconds
+    fn duration(&self) -> f32;
+
+    /// The sequence as linear fades from `color_at(0.0)`, if it's made of
+    /// them, so an output can play it without being sent every frame
+    fn fade_segments(&self) -> Option<Vec<FadeSegment>> {
+        None
+    }
+}
+
+/// Fade linearly from the color before to `color` over `duration` seconds
+#[derive(Debug, Clone, PartialEq)]
+pub struct FadeSegment {
+    pub duration: f32,
+    pub color: Color,
+}
+
+/// How far along (0.0 to 1.0) `t` is in a sequence of `duration` seconds
+fn percent_of(t: f32, duration: f32) -> f32 {
+    if duration > 0.0 {
+        (t / duration).clamp(0.0, 1.0)
+    } else {
+        1.0
+    }
+}
+
+/// Linear interpolation between two colors
+#[derive(Debug, Clone)]
+pub struct LerpSource {
+    pub start: Color,
+    pub end: Color,
+    pub duration: f32,
+}
+
+impl SequenceSource for LerpSource {
+    fn color_at(&self, t: f32) -> Color {
+        self.start.lerp(&self.end, percent_of(t, self.duration))
+    }
+
+    fn duration(&self) -> f32 {
+        self.duration
+    }
+
+    fn fade_segments(&self) -> Option<Vec<FadeSegment>> {
+        Some(vec![FadeSegment {
+            duration: self.duration,
+            color: self.end.clone(),
+        }])
+    }
+}
+
+/// Fade to black, with the white channel fading out quicker than the others
+#[derive(Debug, Clone)]
+pub struct FadeToBlackSource {
+    pub start: Color,
+    pub duration: f32,
+}
+
+impl SequenceSource for FadeToBlackSource {
+    fn color_at(&self, t: f32) -> Color {
+        let percent = percent_of(t, self.duration);
+        let mut color = self.start.lerp(&Color::default(), percent);
+        color.w = self.start.w - self.start.w * percent.cbrt();
+        color
+    }
+
+    fn duration(&self) -> f32 {
+        self.duration
+    }
+
+    /// White isn't linear, so it's split into pieces that each take it down
+    /// by the same amount (R, G and B stay exact)
+    fn fade_segments(&self) -> Option<Vec<FadeSegment>> {
+        let mut last = 0.0;
+        let segments = (1..=FADE_TO_BLACK_SEGMENTS)
+            .map(|i| {
+                let percent =
+                    (i as f32 / FADE_TO_BLACK_SEGMENTS as f32).powi(3);
+                let segment = FadeSegment {
+                    duration: (percent - last) * self.duration,
+                    color: self.color_at(percent * self.duration),
+                };
+                last = percent;
+                segment
+            })
+            .collect();
+        Some(segments)
+    }
+}
+
+/// Gradient that linearly interpolates between control points
+#[derive(Debug, Clone)]
+pub struct ColorPointsSource {
+    pub color_points: Vec<Color>,
+    pub percent_points: Vec<f32>,
+    pub duration: f32,
+}
+
+impl SequenceSource for ColorPointsSource {
+    fn color_at(&self, t: f32) -> Color {
+        if self.color_points.len() < 2 || self.percent_points.len() < 2 {
+            return self.color_points.first().cloned().unwrap_or_default();
+        }
+
+        let percent = percent_of(t, self.duration);
+        let last_segment = self.percent_points.len() - 2;
+        let segment = self
+            .percent_points
+            .windows(2)
+            .position(|points| percent <= points[1])
+            .unwrap_or(last_segment)
+            .min(self.color_points.len() - 2);
+
+        let low = self.percent_points[segment];
+        let high = self.percent_points[segment + 1];
+        let lerp_percent = if high > low {
+            ((percent - low) / (high - low)).clamp(0.0, 1.0)
+        } else {
+            1.0
+        };
+
+        self.color_points[segment]
+            .lerp(&self.color_points[segment + 1], lerp_percent)
+    }
+
+    fn duration(&self) -> f32 {
+        self.duration
+    }
+
+    /// A fade to each point in turn, holding the first and last colors
+    /// before and after their points
+    fn fade_segments(&self) -> Option<Vec<FadeSegment>> {
+        if self.color_points.len() < 2 || self.percent_points.len() < 2 {
+            return Some(vec![FadeSegment {
+                duration: self.duration,
+                color: self.color_at(0.0),
+            }]);
+        }
+        // Anything else isn't quite the same as what `color_at` does
+        if self.percent_points.len() != self.color_points.len()
+            || self.percent_points.windows(2).any(|p| p[0] > p[1])
+        {
+            return None;
+        }
+
+        let mut last = 0.0;
+        let mut segments: Vec<_> = self
+            .color_points
+            .iter()
+            .zip(&self.percent_points)
+            .map(|(color, &percent)| {
+                let percent = percent.clamp(0.0, 1.0);
+                let segment = FadeSegment {
+                    duration: (percent - last) * self.duration,
+                    color: color.clone(),
+                };
+                last = percent;
+                segment
+            })
+            .collect();
+        segments.push(FadeSegment {
+            duration: (1.0 - last) * self.duration,
+            color: self.color_points[self.color_points.len() - 1].clone(),
+        });
+        Some(segments)
+    }
+}
+
+/// Gradient sampled from the pixels of a png image
+///
+/// Only the (denoised) pixels are kept in memory, which are resampled with a
+/// tent filter for each point in time
+#[derive(Debug, Clone)]
+pub struct PixelGradientSource {
+    pixels: Vec<Color>,
+    duration: f32,
+    resolution: f32,
+    filter_size: isize,
+}
+
+impl PixelGradientSource {
+    /// Gradient over `duration` seconds through `pixels`, sampled at
+    /// `resolution` frames per second
+    pub fn new(pixels: Vec<Color>, duration: f32, resolution: f32) -> Self {
+        let num_samples = (resolution * duration).round().max(1.0);
+        let filter_size = (pixels.len() as f32 / num_samples).round() as isize;
+
+        // Hack to allow upsampling
+        let filter_size = if filter_size < 3 { 3 } else { filter_size };
+
+        Self {
+            pixels,
+            duration,
+            resolution,
+            filter_size,
+        }
+    }
+}
+
+impl SequenceSource for PixelGradientSource {
+    fn color_at(&self, t: f32) -> Color {
+        // Count in frames, so rounding errors in `t` don't move the center
+        // to the pixel before on frame boundaries
+        let frame = t * self.resolution;
+        let frame = if (frame - frame.round()).abs() < 1e-3 {
+            frame.round()
+        } else {
+            frame
+        };
+        let percent = percent_of(frame, self.duration * self.resolution);
+        let center_index = (percent * (self.pixels.len() as f32)) as isize;
+
+        let mut sum = Color::default();
+        let mut counted = 0;
+        for filter_index in (-self.filter_size / 2)..(self.filter_size / 2) {
+            // Absolute value function to mimic tent
+            let tent_value = 1.0
+                - ((filter_index * 2) as f32 / self.filter_size as f32).abs();
+
+            let png_index = filter_index + center_index;
+            if png_index >= 0 && png_index < self.pixels.len() as isize {
+                sum =
+                    sum + self.pixels[png_index as usize].clone() * tent_value;
+                counted += 1;
+            }
+        }
+
+        if counted == 0 {
+            return self.pixels.last().cloned().unwrap_or_default();
+        }
+        let avg = sum / (counted as f32 / 2.0);
+        avg.clamped()
+    }
+
+    fn duration(&self) -> f32 {
+        self.duration
+    }
+}
+
+/// One sequence followed by another
+#[derive(Debug, Clone)]
+pub struct ChainSource {
+    pub first: Arc<dyn SequenceSource>,
+    pub second: Arc<dyn SequenceSource>,
+}
+
+impl SequenceSource for ChainSource {
+    fn color_at(&self, t: f32) -> Color {
+        let first_duration = self.first.duration();
+        if t < first_duration {
+            self.first.color_at(t)
+        } else {
+            self.second.color_at(t - first_duration)
+        }
+    }
+
+    fn duration(&self) -> f32 {
+        self.first.duration() + self.second.duration()
+    }
+
+    fn fade_segments(&self) -> Option<Vec<FadeSegment>> {
+        let mut segments = self.first.fade_segments()?;
+        segments.extend(self.second.fade_segments()?);
+        Some(segments)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESOLUTION: f32 = 30.0;
+
+    fn assert_frames(name: &str, source: &dyn SequenceSource, old: &[Color]) {
+        for (frame, expected) in old.iter().enumerate() {
+            let color = source.color_at(frame as f32 / RESOLUTION);
+            for (a, b) in [
+                (color.r, expected.r),
+                (color.g, expected.g),
+                (color.b, expected.b),
+                (color.w, expected.w),
+            ] {
+                assert!(
+                    (a - b).abs() < 1e-5,
+                    "{} frame {}: {:?} != {:?}",
+                    name,
+                    frame,
+                    color,
+                    expected
+                );
+            }
+        }
+    }
+
+    /// Frames of `LedSequence::from_color_lerp`, before colors were
+    /// generated on demand
+    fn old_lerp(start: &Color, end: &Color) -> Vec<Color> {
+        (0..=(RESOLUTION as usize))
+            .map(|i| start.lerp(end, i as f32 / RESOLUTION))
+            .collect()
+    }
+
+    /// Frames of `LedSequence::fade_to_black`, as above
+    fn old_fade_to_black(start: &Color, duration: f32) -> Vec<Color> {
+        let num_elements = (duration * RESOLUTION) as usize;
+        (0..=num_elements)
+            .map(|i| {
+                let percent = i as f32 / (duration * RESOLUTION);
+                let mut color = start.lerp(&Color::default(), percent);
+                color.w = start.w - start.w * percent.cbrt();
+                color
+            })
+            .collect()
+    }
+
+    /// Frames of a color points gradient, as above
+    fn old_color_points(points: &ColorPointsSource) -> Vec<Color> {
+        let num_samples = (RESOLUTION * points.duration) as usize;
+        let percent_points = &points.percent_points;
+        let mut color_index = 0;
+        (0..=num_samples)
+            .map(|sample_index| {
+                let overall_percent = sample_index as f32 / num_samples as f32;
+                let lerp_percent = 1.0
+                    - ((percent_points[color_index + 1] - overall_percent)
+                        / (percent_points[color_index + 1]
+                            - percent_points[color_index]));
+                let color = points.color_points[color_index]
+                    .lerp(&points.color_points[color_index + 1], lerp_percent);
+                if overall_percent >= percent_points[color_index + 1] {
+                    color_index += 1;
+                }
+                color
+            })
+            .collect()
+    }
+
+    /// Frames of a png gradient resampled with a tent filter, as above, plus
+    /// the frame at the very end that's now also played
+    fn old_resample(pixels: &[Color], duration: f32) -> Vec<Color> {
+        let num_samples = RESOLUTION * duration;
+        let filter_size =
+            (pixels.len() as f32 / num_samples.round()).round() as isize;
+        let filter_size = if filter_size < 3 { 3 } else { filter_size };
+
+        (0..=(num_samples as usize))
+            .map(|i| {
+                let percent = i as f32 / num_samples;
+                let center_index = (percent * (pixels.len() as f32)) as isize;
+                let mut sum = Color::default();
+                let mut counted = 0;
+                for filter_index in (-filter_size / 2)..(filter_size / 2) {
+                    let tent_value = 1.0
+                        - ((filter_index * 2) as f32 / filter_size as f32)
+                            .abs();
+                    let png_index = filter_index + center_index;
+                    if png_index >= 0 && png_index < pixels.len() as isize {
+                        sum = sum
+                            + pixels[png_index as usize].clone() * tent_value;
+                        counted += 1;
+                    }
+                }
+                (sum / (counted as f32 / 2.0)).clamped()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_lerp_and_fade_to_black() {
+        let start = Color::new(1.0, 0.5, 0.0, 0.8);
+        let end = Color::new(0.0, 0.25, 1.0, 0.0);
+        let lerp = LerpSource {
+            start: start.clone(),
+            end: end.clone(),
+            duration: 1.0,
+        };
+        assert_frames("lerp", &lerp, &old_lerp(&start, &end));
+        assert_eq!(lerp.color_at(lerp.duration()), end);
+
+        for duration in [0.5, 2.0, 3.3] {
+            let fade = FadeToBlackSource {
+                start: start.clone(),
+                duration,
+            };
+            let old = old_fade_to_black(&start, duration);
+            assert_frames("fade to black", &fade, &old);
+            assert_eq!(fade.color_at(duration), Color::default());
+        }
+    }
+
+    #[test]
+    fn test_color_points() {
+        let points = ColorPointsSource {
+            color_points: vec![
+                Color::new(1.0, 0.0, 0.0, 0.0),
+                Color::new(0.0, 1.0, 0.0, 0.5),
+                Color::new(0.0, 0.0, 1.0, 1.0),
+            ],
+            percent_points: vec![0.0, 0.25, 1.0],
+            duration: 2.0,
+        };
+        let old = old_color_points(&points);
+        assert_frames("color points", &points, &old);
+        assert_eq!(points.color_at(points.duration()), points.color_points[2]);
+    }
+
+    #[test]
+    fn test_pixel_gradient() {
+        // More pixels than frames, and fewer
+        for (width, duration) in [(300, 2.0), (20, 3.0)] {
+            let pixels: Vec<_> = (0..width)
+                .map(|i| {
+                    let x = i as f32 / width as f32;
+                    Color::new(x, 1.0 - x, (x * 7.0).sin().abs(), 0.5)
+                })
+                .collect();
+            let source =
+                PixelGradientSource::new(pixels.clone(), duration, RESOLUTION);
+            assert_frames("pixels", &source, &old_resample(&pixels, duration));
+        }
+    }
+
+    #[test]
+    fn test_chain_offsets() {
+        let from = Color::new(0.0, 0.0, 0.0, 1.0);
+        let points = ColorPointsSource {
+            color_points: vec![
+                Color::new(1.0, 0.0, 0.0, 0.0),
+                Color::new(0.0, 0.0, 1.0, 0.0),
+            ],
+            percent_points: vec![0.0, 1.0],
+            duration: 2.0,
+        };
+        let start = points.color_points[0].clone();
+        let chain = ChainSource {
+            first: Arc::new(LerpSource {
+                start: from.clone(),
+                end: start.clone(),
+                duration: 1.0,
+            }),
+            second: Arc::new(points.clone()),
+        };
+        assert_eq!(chain.duration(), 3.0);
+
+        // The old initial fade also played its last color, which is the
+        // same as the gradient's first, so it's now left out
+        let fade = old_lerp(&from, &start);
+        let gradient = old_color_points(&points);
+        assert_eq!(fade.last(), gradient.first());
+        let old: Vec<_> = fade[..fade.len() - 1]
+            .iter()
+            .chain(&gradient)
+            .cloned()
+            .collect();
+        assert_frames("chain", &chain, &old);
+        assert_eq!(chain.color_at(3.0), points.color_points[1]);
+    }
+}
@@ -0,0 +1,280 @@
+//! Offline tools for authoring sequences: sampling, rendering to png strips
+//! and the terminal, and fitting control points to gradients
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use crate::color::Color;
+use crate::led_sequence::{
+    LedColorPoints, LedSequence, LedSequenceInfo, LedSequenceType, RESOLUTION,
+};
+
+/// Size of gradient pngs, as recommended in `LedSequence::from_png`
+pub const GRADIENT_WIDTH: u32 = 1024;
+pub const GRADIENT_HEIGHT: u32 = 100;
+
+/// Load a png gradient or json color points sequence from a file, without
+/// the server
+pub fn load(path: &Path) -> Result<LedSequence, std::io::Error> {
+    if !path.is_file() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("No sequence file {:?}", path),
+        ));
+    }
+
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("png") => LedSequence::from_png(&Color::default(), path),
+        Some("json") => LedSequence::from_color_points(&Color::default(), path),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{:?} is not a png or json sequence", path),
+        )),
+    }
+}
+
+/// Colors of one play-through of a sequence, one per frame from its start to
+/// its end, leaving out the fade in from the previous color. Single color
+/// sequences are one frame.
+pub fn sample_frames(seq: &LedSequence) -> Vec<Color> {
+    if let LedSequenceType::Color = seq.info.sequence_type {
+        return vec![seq.final_color()];
+    }
+
+    let start = seq.initial_fade_duration();
+    let duration = (seq.duration() - start).max(0.0);
+    // Include the last color, so the samples span the whole duration
+    let frames = (duration * RESOLUTION).round() as usize + 1;
+
+    (0..frames)
+        .map(|i| seq.color_at(start + i as f32 / RESOLUTION))
+        .collect()
+}
+
+/// Resample frames to `width` colors, linearly interpolating between them
+pub fn resample(frames: &[Color], width: usize) -> Vec<Color> {
+    match frames.len() {
+        0 => vec![Color::default(); width],
+        1 => vec![frames[0].clone(); width],
+        len => (0..width)
+            .map(|x| {
+                let position = if width > 1 {
+                    x as f32 * (len - 1) as f32 / (width - 1) as f32
+                } else {
+                    0.0
+                };
+                let low = (position.floor() as usize).min(len - 2);
+                frames[low].lerp(&frames[low + 1], position - low as f32)
+            })
+            .collect(),
+    }
+}
+
+/// Write colors to a 16-bit png in the gradient layout
+/// `LedSequence::from_png` reads: RGB in the top half, and the white
+/// component as gray in the bottom half
+pub fn write_gradient_png(
+    frames: &[Color],
+    path: &Path,
+    width: u32,
+    height: u32,
+) -> Result<(), std::io::Error> {
+    let colors = resample(frames, width as usize);
+    let to_bytes =
+        |c: f32| ((c.clamp(0.0, 1.0) * 65535.0).round() as u16).to_be_bytes();
+
+    let mut data = Vec::with_capacity(6 * width as usize * height as usize);
+    for row in 0..height {
+        for color in &colors {
+            let samples = if row < height / 2 {
+                [color.r, color.g, color.b]
+            } else {
+                [color.w; 3]
+            };
+            for sample in samples {
+                data.extend(to_bytes(sample));
+            }
+        }
+    }
+
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(file, width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Sixteen);
+    let mut writer = encoder.write_header().map_err(std::io::Error::other)?;
+    writer
+        .write_image_data(&data)
+        .map_err(std::io::Error::other)
+}
+
+/// File name `LedSequence::from_png` expects for a sequence
+pub fn gradient_file_name(info: &LedSequenceInfo, duration: f32) -> String {
+    match info.sequence_type {
+        LedSequenceType::Color => format!("color_{}.png", info.name),
+        _ if info.repeat => {
+            format!("gradient_{}_{}_repeat.png", info.name, duration)
+        }
+        _ => format!("gradient_{}_{}.png", info.name, duration),
+    }
+}
+
+/// Two lines of truecolor blocks across the terminal, time going left to
+/// right: RGB on the first line and white on the second
+pub fn ansi_strip(frames: &[Color], width: usize) -> String {
+    let to_byte = |c: f32| (c.clamp(0.0, 1.0) * 255.0).round() as u8;
+    let colors = resample(frames, width);
+
+    let rgb = colors
+        .iter()
+        .map(|c| {
+            format!(
+                "\x1b[38;2;{};{};{}m\u{2588}",
+                to_byte(c.r),
+                to_byte(c.g),
+                to_byte(c.b)
+            )
+        })
+        .collect::<String>();
+    let white = colors
+        .iter()
+        .map(|c| {
+            let w = to_byte(c.w);
+            format!("\x1b[38;2;{};{};{}m\u{2588}", w, w, w)
+        })
+        .collect::<String>();
+
+    format!("{}\x1b[0m\n{}\x1b[0m", rgb, white)
+}
+
+/// Largest difference of any component between two colors
+fn color_distance(a: &Color, b: &Color) -> f32 {
+    (a.r - b.r)
+        .abs()
+        .max((a.g - b.g).abs())
+        .max((a.b - b.b).abs())
+        .max((a.w - b.w).abs())
+}
+
+/// Fit the fewest control points that reproduce the frames to within
+/// `tolerance` (0.0 to 1.0 per component), with linear interpolation between
+/// them
+pub fn fit_color_points(
+    frames: &[Color],
+    tolerance: f32,
+) -> (Vec<Color>, Vec<f32>) {
+    if frames.len() < 2 {
+        return (frames.to_vec(), vec![0.0; frames.len()]);
+    }
+
+    // Ramer-Douglas-Peucker, splitting segments at the worst frame until
+    // every frame is close enough
+    let last = frames.len() - 1;
+    let mut keep = vec![false; frames.len()];
+    keep[0] = true;
+    keep[last] = true;
+    let mut segments = vec![(0, last)];
+
+    while let Some((start, end)) = segments.pop() {
+        let worst = (start + 1..end)
+            .map(|i| {
+                let percent = (i - start) as f32 / (end - start) as f32;
+                let expected = frames[start].lerp(&frames[end], percent);
+                (i, color_distance(&frames[i], &expected))
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+
+        if let Some((i, distance)) = worst {
+            if distance > tolerance {
+                keep[i] = true;
+                segments.push((start, i));
+                segments.push((i, end));
+            }
+        }
+    }
+
+    keep.iter()
+        .enumerate()
+        .filter(|(_, keep)| **keep)
+        .map(|(i, _)| (frames[i].clone(), i as f32 / last as f32))
+        .unzip()
+}
+
+/// Convert a loaded sequence to json color points
+pub fn to_color_points(seq: &LedSequence, tolerance: f32) -> LedColorPoints {
+    let frames = sample_frames(seq);
+    let (color_points, percent_points) = fit_color_points(&frames, tolerance);
+
+    let mut info = seq.info.clone();
+    info.duration = seq.duration() - seq.initial_fade_duration();
+    if let LedSequenceType::Color = info.sequence_type {
+        info.duration = 0.0;
+    }
+
+    LedColorPoints {
+        color_points,
+        percent_points,
+        info,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sequence_source::{ColorPointsSource, SequenceSource};
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn test_load_bad_png() {
+        let dir = TestDir::new("tools");
+
+        // Badly named and corrupt pngs are errors, not panics
+        let path = dir.join("foo.png");
+        std::fs::write(&path, b"not a png").unwrap();
+        let error = load(&path).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+
+        let path = dir.join("gradient_corrupt_10.png");
+        std::fs::write(&path, b"not a png").unwrap();
+        assert!(load(&path).is_err());
+    }
+
+    #[test]
+    fn test_fit_color_points() {
+        // Up from black to red, then across to blue
+        let frames = (0..=20)
+            .map(|i| Color::new(i as f32 / 20.0, 0.0, 0.0, 0.0))
+            .chain((1..=10).map(|i| {
+                Color::new(1.0 - i as f32 / 10.0, 0.0, i as f32 / 10.0, 0.0)
+            }))
+            .collect::<Vec<_>>();
+
+        let (colors, percents) = fit_color_points(&frames, 0.01);
+        assert_eq!(colors.len(), 3);
+        assert_eq!(percents, vec![0.0, 20.0 / 30.0, 1.0]);
+        assert_eq!(colors[1], Color::new(1.0, 0.0, 0.0, 0.0));
+
+        // The fitted points reproduce the frames
+        let source = ColorPointsSource {
+            color_points: colors,
+            percent_points: percents,
+            duration: 1.0,
+        };
+        for (i, frame) in frames.iter().enumerate() {
+            let t = i as f32 / (frames.len() - 1) as f32;
+            assert!(color_distance(&source.color_at(t), frame) <= 0.01);
+        }
+    }
+
+    #[test]
+    fn test_resample() {
+        let frames = [
+            Color::new(0.0, 0.0, 0.0, 0.0),
+            Color::new(1.0, 1.0, 1.0, 1.0),
+        ];
+        let colors = resample(&frames, 5);
+        assert_eq!(colors.len(), 5);
+        assert_eq!(colors[2], Color::new(0.5, 0.5, 0.5, 0.5));
+        assert_eq!(colors[4], frames[1]);
+    }
+}
@@ -1,250 +1,1014 @@
 //! Manages the LED Arduino serial connection
 
+use std::collections::VecDeque;
 use std::io::{Error, ErrorKind, Read, Write};
-use std::time::Duration;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
-use serial::{SerialPort, SystemPort};
 use serial::core::{SerialDevice, SerialPortSettings};
+use serial::{SerialPort, SystemPort};
 
 use crate::color::Color;
+use crate::dither::TemporalDither;
+use crate::led_config::SerialConfig;
+use crate::led_output::{
+    LedOutput, OutputDiagnostics, OutputKind, OutputStatus,
+};
+use crate::metrics;
+use crate::protocol::{
+    self, Frame, FrameDecoder, CMD_ACK, CMD_HELLO, CMD_NAK, CONFIRMATION_BYTES,
+    MAX_ACK_WINDOW, MAX_FADES, START, UPDATE_BYTES,
+};
 use crate::rooms::Rooms;
+use crate::sequence_source::FadeSegment;
 
-// Magic numbers for color or room relay commands
-const COLOR_CMD: u8 = 0xC0;
-const ROOM_CMD: u8 = 0xF0;
+/// How long to wait between attempts to reopen a serial port that failed
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
 
-// Magic numbers for each room
-const LIVING_ROOM: u8 = 0x1A;
-const OFFICE: u8 = 0x1C;
-const BEDROOM: u8 = 0x18;
+/// How long to wait for the Arduino to reply
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
 
-// Magic lengths
-const UPDATE_BYTES: usize = 9;
-const CONFIRMATION_BYTES: usize = 3;
+/// How many of the Arduino's latest replies to keep for diagnostics
+const REPLY_HISTORY: usize = 20;
 
-// Defaults for serial mockup
-const DEFAULT_MOCKUP_SPAN: usize = 1;
+/// Most bytes to read looking for the reply to a frame, past noise and
+/// stale replies, before giving up on it
+const MAX_REPLY_BYTES: usize = 64;
 
-pub struct SerialManager {
-    pub serial: Option<SystemPort>,
+/// Replies that can time out in a row with protocol v2 before the port is
+/// treated as lost. A single lost byte only costs the frame it was in.
+const MAX_TIMEOUTS: u32 = 3;
+
+/// A serial port, or something that acts like one (e.g. `ArduinoSim`)
+pub trait SerialLink: Read + Write + Send + Sync {}
+
+impl<T: Read + Write + Send + Sync> SerialLink for T {}
+
+/// Version of the protocol spoken with the Arduino (see `protocol`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolVersion {
+    V1,
+    V2,
+}
+
+/// Something to tell the Arduino
+#[derive(Debug, Clone)]
+enum Command {
+    Color([u16; 4]),
+    Rooms(Rooms),
+    Fade {
+        levels: [u16; 4],
+        duration_ms: u32,
+        append: bool,
+    },
+}
+
+impl Command {
+    /// Version 1 reply that confirms the command, without the `\r\n`
+    fn confirmation(&self) -> &'static str {
+        match self {
+            Command::Color(_) | Command::Fade { .. } => "C",
+            Command::Rooms(_) => "R",
+        }
+    }
+
+    /// Version 1 firmware can't fade, so a fade jumps straight to its end
+    fn to_v1(&self) -> [u8; UPDATE_BYTES] {
+        match self {
+            Command::Color(levels) | Command::Fade { levels, .. } => {
+                protocol::color_to_bytes(levels)
+            }
+            Command::Rooms(rooms) => protocol::rooms_to_bytes(rooms),
+        }
+    }
+
+    fn to_frame(&self, seq: u8) -> Frame {
+        match self {
+            Command::Color(levels) => Frame::color(seq, levels),
+            Command::Rooms(rooms) => Frame::rooms(seq, rooms),
+            Command::Fade {
+                levels,
+                duration_ms,
+                append,
+            } => Frame::fade(seq, levels, *duration_ms, *append),
+        }
+    }
+}
+
+/// A version 2 frame that hasn't been acknowledged yet
+#[derive(Debug, Clone)]
+struct InFlight {
+    seq: u8,
+    command: Command,
+    sent: Instant,
+}
+
+/// Updates waiting for the writer thread. Only the latest color and rooms
+/// are kept, so a slow link drops frames rather than falling behind.
+#[derive(Debug, Default)]
+struct Queue {
+    rooms: Option<Rooms>,
+    color: Option<Color>,
+    /// Color to start from, and fades for the Arduino to play from there
+    fade: Option<(Color, Vec<FadeSegment>)>,
+    /// Final color and rooms to send before closing the port
+    close: Option<(Color, Rooms)>,
+    /// Stop the writer thread without sending anything else
+    stop: bool,
+    /// The writer has sent everything and has no frames waiting for a reply
+    idle: bool,
+}
 
-    serial_mockup_index: usize,
-    serial_mockup_span: usize,
+/// How the link is doing, for `status` and `diagnostics`
+#[derive(Debug)]
+struct LinkStatus {
+    connected: bool,
+    protocol: ProtocolVersion,
+    last_error: Option<String>,
+    last_handshake: Option<Instant>,
+    replies: VecDeque<String>,
+    /// The last fade wasn't taken, or the Arduino was reset while playing it
+    fade_failed: bool,
+}
+
+/// State shared between `SerialManager` and its writer thread
+#[derive(Debug)]
+struct Shared {
+    queue: Mutex<Queue>,
+    /// Signalled when something is queued, and when the writer is idle
+    changed: Condvar,
+    status: Mutex<LinkStatus>,
+}
+
+impl Shared {
+    fn queue(&self) -> MutexGuard<'_, Queue> {
+        self.queue
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn status(&self) -> MutexGuard<'_, LinkStatus> {
+        self.status
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Remember a reply from the Arduino, forgetting the oldest ones
+    fn push_reply(&self, reply: String) {
+        let mut status = self.status();
+        if status.replies.len() == REPLY_HISTORY {
+            status.replies.pop_front();
+        }
+        status.replies.push_back(reply);
+    }
+}
+
+/// Output to the Arduino over USB serial
+///
+/// Colors and rooms are handed to a writer thread, so the LED worker never
+/// waits on the serial port. If the port can't be opened or stops
+/// responding, colors are dropped and the port is reopened every
+/// `RECONNECT_INTERVAL`.
+///
+/// Colors are temporally dithered into the Arduino's 16-bit PWM levels,
+/// unless turned off in `[serial]`.
+pub struct SerialManager {
+    tty_name: String,
+    firmware_fades: bool,
+    shared: Arc<Shared>,
+    writer: Option<JoinHandle<()>>,
 }
 
 impl SerialManager {
-    pub fn new(tty_name: &str) -> Self {
-        let opened = serial::open(tty_name);
-        let serial = match opened {
-            Ok(mut ser) => {
-                SerialPort::set_timeout(&mut ser, Duration::from_secs(2)).unwrap();
-                let settings_result = ser.read_settings();
-                if let Ok(mut settings) = settings_result {
-                    settings.set_baud_rate(serial::BaudRate::Baud9600).expect("Unable to set serial baud rate");
-                    ser.write_settings(&settings).expect("Unable to write serial settings");
+    pub fn new(config: &SerialConfig) -> Self {
+        Self::spawn(config, None)
+    }
+
+    /// Talk to the Arduino over an already open link, e.g. `ArduinoSim`
+    pub fn with_link(config: &SerialConfig, link: Box<dyn SerialLink>) -> Self {
+        Self::spawn(config, Some(link))
+    }
+
+    fn spawn(config: &SerialConfig, link: Option<Box<dyn SerialLink>>) -> Self {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue::default()),
+            changed: Condvar::new(),
+            status: Mutex::new(LinkStatus {
+                connected: false,
+                protocol: ProtocolVersion::V1,
+                last_error: None,
+                last_handshake: None,
+                replies: VecDeque::with_capacity(REPLY_HISTORY),
+                fade_failed: false,
+            }),
+        });
+        let mut writer = SerialWriter::new(config, shared.clone());
+        let thread = std::thread::spawn(move || {
+            match link {
+                Some(link) => writer.start(link),
+                None => writer.connect(),
+            }
+            writer.run();
+        });
+
+        Self {
+            tty_name: config.tty_name.clone(),
+            firmware_fades: config.firmware_fades,
+            shared,
+            writer: Some(thread),
+        }
+    }
+
+    /// Protocol version agreed on in the last handshake
+    pub fn protocol(&self) -> ProtocolVersion {
+        self.shared.status().protocol
+    }
+
+    /// Wait until the writer has sent everything queued and had it
+    /// acknowledged (or given up on it)
+    pub fn flush(&self) {
+        let mut queue = self.shared.queue();
+        while !queue.idle
+            || queue.rooms.is_some()
+            || queue.color.is_some()
+            || queue.fade.is_some()
+        {
+            queue = self
+                .shared
+                .changed
+                .wait(queue)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+    }
+
+    /// Wait for the writer thread to stop, once it's been asked to
+    fn join_writer(&mut self) {
+        self.shared.changed.notify_all();
+        if let Some(thread) = self.writer.take() {
+            if thread.join().is_err() {
+                error!("Serial writer thread for {} panicked", self.tty_name);
+            }
+        }
+    }
+}
+
+impl LedOutput for SerialManager {
+    /// Queue a color for the Arduino, replacing one that hasn't been sent yet
+    fn send_color(&mut self, color: &Color) {
+        let mut queue = self.shared.queue();
+        if queue.color.replace(color.clone()).is_some() {
+            metrics::SERIAL_COALESCED.inc();
+        }
+        queue.fade = None;
+        queue.idle = false;
+        self.shared.changed.notify_all();
+    }
+
+    /// Queue the room state for the Arduino
+    fn send_rooms(&mut self, state: &Rooms) {
+        let mut queue = self.shared.queue();
+        queue.rooms = Some(state.clone());
+        queue.idle = false;
+        self.shared.changed.notify_all();
+    }
+
+    /// Queue a fade for the Arduino to play by itself, if it speaks protocol
+    /// v2 and has room for it
+    fn start_fade(&mut self, start: &Color, segments: &[FadeSegment]) -> bool {
+        let mut status = self.shared.status();
+        if !self.firmware_fades
+            || !status.connected
+            || status.protocol != ProtocolVersion::V2
+            || segments.len() > MAX_FADES
+        {
+            return false;
+        }
+        status.fade_failed = false;
+        drop(status);
+
+        let mut queue = self.shared.queue();
+        queue.color = None;
+        queue.fade = Some((start.clone(), segments.to_vec()));
+        queue.idle = false;
+        self.shared.changed.notify_all();
+        true
+    }
+
+    /// Send the final color (without dithering, so it's exact) and rooms,
+    /// then close the port once the Arduino has confirmed them
+    fn close(&mut self, color: &Color, rooms: &Rooms) {
+        self.shared.queue().close = Some((color.clone(), rooms.clone()));
+        self.join_writer();
+    }
+
+    fn fade_failed(&self) -> bool {
+        self.shared.status().fade_failed
+    }
+
+    fn status(&self) -> OutputStatus {
+        let status = self.shared.status();
+        OutputStatus {
+            kind: OutputKind::Serial,
+            connected: status.connected,
+            detail: match status.last_error {
+                Some(ref e) if !status.connected => e.clone(),
+                _ => format!(
+                    "Connected to {} (protocol {:?})",
+                    self.tty_name, status.protocol
+                ),
+            },
+        }
+    }
+
+    fn diagnostics(&self) -> OutputDiagnostics {
+        let status = self.status();
+        let link = self.shared.status();
+        OutputDiagnostics {
+            status,
+            device: Some(self.tty_name.clone()),
+            last_handshake_secs: link
+                .last_handshake
+                .map(|time| time.elapsed().as_secs_f64()),
+            replies: link.replies.iter().cloned().collect(),
+        }
+    }
+}
+
+impl Drop for SerialManager {
+    fn drop(&mut self) {
+        if self.writer.is_some() {
+            self.shared.queue().stop = true;
+            self.join_writer();
+        }
+    }
+}
+
+impl Default for SerialManager {
+    fn default() -> Self {
+        Self::new(&SerialConfig::default())
+    }
+}
+
+/// What the writer thread should do next
+enum Update {
+    Rooms(Rooms),
+    Color(Color),
+    Fade(Color, Vec<FadeSegment>),
+    /// Read the reply to a frame that's still in flight
+    Reply,
+    Close(Color, Rooms),
+    Stop,
+}
+
+/// Owns the serial port, on its own thread: sends what `SerialManager`
+/// queues and reads the Arduino's replies
+struct SerialWriter {
+    serial: Option<Box<dyn SerialLink>>,
+
+    tty_name: String,
+    baud_rate: u32,
+    dither: Option<TemporalDither>,
+    protocol: ProtocolVersion,
+    ack_window: usize,
+    next_seq: u8,
+    decoder: FrameDecoder,
+    in_flight: VecDeque<InFlight>,
+    timeouts: u32,
+    last_attempt: Instant,
+    shared: Arc<Shared>,
+}
+
+impl SerialWriter {
+    fn new(config: &SerialConfig, shared: Arc<Shared>) -> Self {
+        Self {
+            serial: None,
+            tty_name: config.tty_name.clone(),
+            baud_rate: config.baud_rate,
+            dither: config.dither.then(|| TemporalDither::new(u16::MAX)),
+            protocol: ProtocolVersion::V1,
+            ack_window: usize::from(config.ack_window.clamp(1, MAX_ACK_WINDOW)),
+            next_seq: 0,
+            decoder: FrameDecoder::new(),
+            in_flight: VecDeque::new(),
+            timeouts: 0,
+            last_attempt: Instant::now(),
+            shared,
+        }
+    }
+
+    /// Send updates until the port is closed or the manager is dropped
+    fn run(mut self) {
+        loop {
+            match self.next_update() {
+                Update::Rooms(rooms) => {
+                    self.send_update(&Command::Rooms(rooms));
+                }
+                Update::Color(color) => {
+                    let levels = match self.dither {
+                        Some(ref mut dither) => dither.next(&color),
+                        None => color_levels(&color),
+                    };
+                    self.send_update(&Command::Color(levels));
+                }
+                Update::Fade(start, segments) => {
+                    self.send_fade(&start, &segments);
+                }
+                Update::Reply => {
+                    let result = self.collect_reply();
+                    self.check(result);
                 }
+                Update::Close(color, rooms) => {
+                    self.close(&color, &rooms);
+                    break;
+                }
+                Update::Stop => break,
+            }
+        }
 
-                warn!("Using serial: {}", tty_name);
-                Some(ser)
+        // Nothing left to wait for
+        self.shared.queue().idle = true;
+        self.shared.changed.notify_all();
+    }
+
+    /// Wait for something to do. Rooms go before colors, and replies are
+    /// read once there's nothing new to send.
+    fn next_update(&self) -> Update {
+        let mut queue = self.shared.queue();
+        loop {
+            if let Some((color, rooms)) = queue.close.take() {
+                return Update::Close(color, rooms);
+            }
+            if queue.stop {
+                return Update::Stop;
+            }
+            if let Some(rooms) = queue.rooms.take() {
+                return Update::Rooms(rooms);
+            }
+            if let Some((start, segments)) = queue.fade.take() {
+                return Update::Fade(start, segments);
+            }
+            if let Some(color) = queue.color.take() {
+                return Update::Color(color);
+            }
+            if !self.in_flight.is_empty() {
+                return Update::Reply;
             }
+
+            queue.idle = true;
+            self.shared.changed.notify_all();
+            queue = self
+                .shared
+                .changed
+                .wait(queue)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+    }
+
+    /// Open the serial port and run the setup handshake
+    fn connect(&mut self) {
+        self.last_attempt = Instant::now();
+        match open_port(&self.tty_name, self.baud_rate) {
+            Ok(ser) => self.start(Box::new(ser)),
             Err(err) => {
-                warn!(
-                    "Unable to initialize serial at {}. Using Serial Mockup. Error: {}",
-                    tty_name,
-                    err
-                );
-                None
+                self.disconnect(&format!(
+                    "Unable to initialize serial at {}: {}",
+                    self.tty_name, err
+                ));
             }
-        };
-        
-        
+        }
+    }
 
-        let mut mgr = Self {
-            serial,
-            serial_mockup_index: 0,
-            serial_mockup_span: DEFAULT_MOCKUP_SPAN,
-        };
-        if let Err(io_err) = mgr.setup() {
+    /// Run the setup handshake on a newly opened link
+    fn start(&mut self, link: Box<dyn SerialLink>) {
+        if let Some(ref mut dither) = self.dither {
+            dither.reset();
+        }
+        self.serial = Some(link);
+        if let Err(io_err) = self.setup() {
+            self.disconnect(&format!("Unable to initialize LEDs: {}", io_err));
+        } else {
             warn!(
-                "Unable to initialize LEDs: {}. Using Serial Mockup.",
-                io_err
+                "Using serial: {} at {} baud (protocol {:?})",
+                self.tty_name, self.baud_rate, self.protocol
             );
-            Self {
-                serial: None,
-                serial_mockup_index: 0,
-                serial_mockup_span: DEFAULT_MOCKUP_SPAN,
+            let mut status = self.shared.status();
+            status.connected = true;
+            status.protocol = self.protocol;
+            status.last_error = None;
+            status.last_handshake = Some(Instant::now());
+        }
+    }
+
+    /// Give up on the port after an error, until it's time to reconnect
+    fn disconnect(&mut self, reason: &str) {
+        error!("{}", reason);
+        self.serial = None;
+        self.in_flight.clear();
+        let mut status = self.shared.status();
+        status.connected = false;
+        status.last_error = Some(reason.to_string());
+        // The Arduino starts over from black when it's reconnected
+        status.fade_failed = true;
+    }
+
+    /// Reconnect, if there's no port and it's been a while since last trying
+    fn ensure_connected(&mut self) {
+        if self.serial.is_none()
+            && self.last_attempt.elapsed() >= RECONNECT_INTERVAL
+        {
+            debug!("Trying to reconnect to serial at {}", self.tty_name);
+            self.connect();
+            if self.serial.is_some() {
+                metrics::SERIAL_RECONNECTS.inc();
             }
-        } else {
-            mgr
         }
     }
 
     /// Performs initial setup with the serial connection to the Arduino, MUST
     /// be run before anything else
-    pub fn setup(&mut self) -> Result<(), Error> {
+    fn setup(&mut self) -> Result<(), Error> {
         if let Some(ref mut ser) = self.serial {
             debug!("Setting up serial");
+            self.protocol = ProtocolVersion::V1;
+            self.decoder.reset();
+            self.in_flight.clear();
+            self.timeouts = 0;
+
             // Read the initial statement "I\r\n" that the Arduino sends
             let mut read_buf: [u8; CONFIRMATION_BYTES] =
                 [0; CONFIRMATION_BYTES];
             ser.read_exact(&mut read_buf)?;
+            self.shared.push_reply(text_reply(&read_buf));
 
             if read_buf != "I\r\n".as_bytes() {
-                return Err(Error::new(ErrorKind::Other, format!("Serial initialization reply didn't match `I` (received `{:?}` instead)", read_buf)));
+                return Err(Error::other(format!("Serial initialization reply didn't match `I` (received `{:?}` instead)", read_buf)));
             }
 
-            // Send the default color to be black
-            // let write_bytes: [u8; UPDATE_BYTES] =
-            //     <[u8; UPDATE_BYTES]>::from(&led_system!().current_color);
-            let write_bytes: [u8; UPDATE_BYTES] =
-                [COLOR_CMD, 0, 0, 0, 0, 0, 0, 0, 0];
-            ser.write_all(&write_bytes)?;
+            self.protocol = self.negotiate()?;
+            debug!("Speaking protocol {:?}", self.protocol);
 
-            // Receive confirmation bytes "C\r\n"
-            let mut read_buf: [u8; CONFIRMATION_BYTES] =
-                [0; CONFIRMATION_BYTES];
-            ser.read_exact(&mut read_buf)?;
-
-            if read_buf != "C\r\n".as_bytes() {
-                return Err(Error::new(ErrorKind::Other, format!("Serial color setup reply didn't match `C` (received `{:?}` instead)", read_buf)));
-            }
+            // Send the default color to be black
+            self.confirm(&Command::Color([0; 4]))?;
 
             debug!("Finished serial setup");
             Ok(())
         } else {
-            // If there's no serial, there's no error to speak of
-            Ok(())
+            Err(Error::new(
+                ErrorKind::NotConnected,
+                "Serial port is not open",
+            ))
         }
     }
 
-    /// Send a color to the Arduino over serial (or display the color on screen)
-    pub fn send_color(&mut self, color: &Color) {
-        if let Some(ref mut ser) = self.serial {
-            // Send the color
-            let write_bytes: [u8; UPDATE_BYTES] =
-                <[u8; UPDATE_BYTES]>::from(color);
-            trace!("sending bytes: {:?}", write_bytes);
-            ser.write_all(&write_bytes)
-                .expect("Couldn't write color bytes");
-
-            // Receive confirmation bytes "C\r\n"
-            let mut read_buf: [u8; CONFIRMATION_BYTES] =
-                [0; CONFIRMATION_BYTES];
-            ser.read_exact(&mut read_buf)
-                .expect("Couldn't read confirmation");
-            trace!("received bytes: {:?}", read_buf);
+    /// Offer protocol v2 with `HELLO`, which v1 firmware doesn't know and
+    /// answers with `X\r\n`
+    fn negotiate(&mut self) -> Result<ProtocolVersion, Error> {
+        let seq = self.next_seq();
+        let Some(ref mut ser) = self.serial else {
+            return Err(not_connected());
+        };
+        ser.write_all(&Frame::hello(seq).encode())?;
+
+        let mut first = [0; 1];
+        ser.read_exact(&mut first)?;
+        if first[0] != START {
+            let mut read_buf: [u8; CONFIRMATION_BYTES] = [first[0], 0, 0];
+            ser.read_exact(&mut read_buf[1..])?;
+            self.shared.push_reply(text_reply(&read_buf));
+            return if read_buf == "X\r\n".as_bytes() {
+                Ok(ProtocolVersion::V1)
+            } else {
+                Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unexpected reply to HELLO: `{:?}`", read_buf),
+                ))
+            };
+        }
 
-            if read_buf != "C\r\n".as_bytes() {
-                error!(
-                    "Serial reply didn't match `C` (received `{:?}` instead)",
-                    read_buf
-                );
+        self.decoder.extend(&first);
+        let reply = read_reply(ser.as_mut(), &mut self.decoder, |s| s == seq)?;
+        self.shared.push_reply(describe(&reply));
+        match reply.payload.get(1) {
+            Some(&version) if reply.acks(CMD_HELLO) && version >= 2 => {
+                Ok(ProtocolVersion::V2)
             }
-        } else {
-            self.serial_mockup_index += 1;
-            if self.serial_mockup_index % self.serial_mockup_span == 0 {
-                println!(
-                    "\x1b[38;2;{};{};{}m{}\x1b[0m",
-                    (color.r * f32::from(<u8>::max_value())) as u8,
-                    (color.g * f32::from(<u8>::max_value())) as u8,
-                    (color.b * f32::from(<u8>::max_value())) as u8,
-                    "#".repeat(80),
-                );
-                println!(
-                    "\x1b[38;2;{};{};{}m{}\x1b[0m\n",
-                    (color.w * f32::from(<u8>::max_value())) as u8,
-                    (color.w * f32::from(<u8>::max_value())) as u8,
-                    (color.w * f32::from(<u8>::max_value())) as u8,
-                    "#".repeat(80),
-                );
-            }
-        }
-    }
-
-    /// Send the current room state to the Arduino
-    pub fn send_rooms(&mut self, state: &Rooms) {
-        if let Some(ref mut ser) = self.serial {
-            // Send the color
-            let write_bytes: [u8; UPDATE_BYTES] = rooms_to_bytes(state);
-            trace!("sending bytes: {:?}", write_bytes);
-            ser.write_all(&write_bytes)
-                .expect("Couldn't write color bytes");
+            Some(1) if reply.acks(CMD_HELLO) => Ok(ProtocolVersion::V1),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unexpected reply to HELLO: {}", describe(&reply)),
+            )),
+        }
+    }
 
-            // Receive confirmation bytes "R\r\n"
-            let mut read_buf: [u8; CONFIRMATION_BYTES] =
-                [0; CONFIRMATION_BYTES];
-            ser.read_exact(&mut read_buf)
-                .expect("Couldn't read confirmation");
-            trace!("received bytes: {:?}", read_buf);
+    fn next_seq(&mut self) -> u8 {
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        seq
+    }
 
-            if read_buf != "R\r\n".as_bytes() {
-                error!(
-                    "Serial reply didn't match `R` (received `{:?}` instead)",
-                    read_buf
-                );
+    /// Write a command. With protocol v1 this waits for the reply; with v2
+    /// it only waits once `ack_window` frames are waiting for replies. A
+    /// reply that doesn't confirm a command is an `InvalidData` error.
+    fn send(&mut self, command: &Command) -> Result<(), Error> {
+        let seq = self.next_seq();
+        let Some(ref mut ser) = self.serial else {
+            return Err(not_connected());
+        };
+
+        match self.protocol {
+            ProtocolVersion::V1 => {
+                let sent = Instant::now();
+                let write_bytes = command.to_v1();
+                trace!("sending bytes: {:?}", write_bytes);
+                ser.write_all(&write_bytes)?;
+
+                let mut read_buf: [u8; CONFIRMATION_BYTES] =
+                    [0; CONFIRMATION_BYTES];
+                ser.read_exact(&mut read_buf)?;
+                trace!("received bytes: {:?}", read_buf);
+                let reply = text_reply(&read_buf);
+                self.shared.push_reply(reply.clone());
+                record_reply(command, &reply, sent)
             }
-        } else {
-            println!("Serial Mockup: {:?}", state);
+            ProtocolVersion::V2 => {
+                let frame = command.to_frame(seq);
+                trace!("sending frame: {:?}", frame);
+                ser.write_all(&frame.encode())?;
+                self.in_flight.push_back(InFlight {
+                    seq,
+                    command: command.clone(),
+                    sent: Instant::now(),
+                });
+
+                while self.in_flight.len() >= self.ack_window {
+                    self.collect_reply()?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Send a command and wait for every frame in flight to be acknowledged
+    fn confirm(&mut self, command: &Command) -> Result<(), Error> {
+        self.send(command)?;
+        while !self.in_flight.is_empty() {
+            self.collect_reply()?;
+        }
+        Ok(())
+    }
+
+    /// Read the reply to the oldest frame in flight. Frames before the one
+    /// that's answered got no reply, so they were lost on the way.
+    fn collect_reply(&mut self) -> Result<(), Error> {
+        let Some(ref mut ser) = self.serial else {
+            return Err(not_connected());
+        };
+        let in_flight = &self.in_flight;
+        let reply = match read_reply(ser.as_mut(), &mut self.decoder, |seq| {
+            in_flight.iter().any(|frame| frame.seq == seq)
+        }) {
+            Ok(reply) => reply,
+            Err(e) => {
+                // Replies to these might still turn up, but they'll be
+                // skipped as stale
+                self.in_flight.clear();
+                return Err(e);
+            }
+        };
+        trace!("received frame: {:?}", reply);
+        self.shared.push_reply(describe(&reply));
+
+        while let Some(frame) = self.in_flight.pop_front() {
+            if frame.seq == reply.seq {
+                let command = frame.command.to_frame(frame.seq).command;
+                // Same labels as v1, for the metrics
+                let received = if reply.acks(command) {
+                    frame.command.confirmation()
+                } else {
+                    "X"
+                };
+                return record_reply(&frame.command, received, frame.sent);
+            }
+            warn!("No reply from the Arduino to frame #{}", frame.seq);
+            metrics::SERIAL_REPLIES
+                .inc(&[frame.command.confirmation(), "none"]);
+        }
+        Ok(())
+    }
+
+    /// Deal with the result of talking to the Arduino, returning whether it
+    /// went through
+    fn check(&mut self, result: Result<(), Error>) -> bool {
+        match result {
+            Ok(()) => {
+                self.timeouts = 0;
+                true
+            }
+            Err(e) if e.kind() == ErrorKind::InvalidData => {
+                error!("{}", e);
+                false
+            }
+            Err(e)
+                if e.kind() == ErrorKind::TimedOut
+                    && self.protocol == ProtocolVersion::V2
+                    && self.timeouts < MAX_TIMEOUTS =>
+            {
+                self.timeouts += 1;
+                warn!("No reply from the Arduino, skipping the frame: {}", e);
+                false
+            }
+            Err(e) => {
+                self.disconnect(&format!(
+                    "Lost serial connection to {}: {}",
+                    self.tty_name, e
+                ));
+                false
+            }
+        }
+    }
+
+    /// Send a command, returning whether it went through
+    fn send_update(&mut self, command: &Command) -> bool {
+        self.ensure_connected();
+        if self.serial.is_none() {
+            return false;
+        }
+        let result = self.send(command);
+        self.check(result)
+    }
+
+    /// Send the color a fade starts from (without dithering, so the Arduino
+    /// fades from exactly there), then the fades to play from it. Waits for
+    /// the Arduino to take all of them, and flags the fade as failed if it
+    /// doesn't.
+    fn send_fade(&mut self, start: &Color, segments: &[FadeSegment]) {
+        self.ensure_connected();
+        let taken = self.serial.is_some() && {
+            let result = self.send_all(&fade_commands(start, segments));
+            self.check(result)
+        };
+        if !taken {
+            warn!("Arduino didn't take the fade, colors will be sent instead");
+            self.shared.status().fade_failed = true;
+        }
+    }
+
+    /// Send commands one after the other, then wait for all of them to be
+    /// acknowledged
+    fn send_all(&mut self, commands: &[Command]) -> Result<(), Error> {
+        for command in commands {
+            self.send(command)?;
         }
+        while !self.in_flight.is_empty() {
+            self.collect_reply()?;
+        }
+        Ok(())
+    }
+
+    /// Send the final color and rooms, waiting for the Arduino to confirm
+    /// each, then close the port
+    fn close(&mut self, color: &Color, rooms: &Rooms) {
+        if self.serial.is_none() {
+            return;
+        }
+        // Let the frames already sent finish first
+        while !self.in_flight.is_empty() {
+            let result = self.collect_reply();
+            self.check(result);
+        }
+
+        // Stop at the first failure, rather than reconnecting to retry
+        let color = Command::Color(color_levels(color));
+        let rooms = Command::Rooms(rooms.clone());
+        let result = self.confirm(&color);
+        let confirmed = self.check(result) && {
+            let result = self.confirm(&rooms);
+            self.check(result)
+        };
+        if !confirmed {
+            warn!("Arduino didn't confirm the final color and rooms");
+        }
+
+        debug!("Closing serial at {}", self.tty_name);
+        self.serial = None;
+        let mut status = self.shared.status();
+        status.connected = false;
+        status.last_error = Some("Closed for shutdown".to_string());
     }
 }
 
-impl Default for SerialManager {
-    fn default() -> Self {
-        Self::new("/dev/ttyACM0")
-    }
-}
-
-/// Convert to the format that the Arduino is expecting, including the prefix
-/// magic number COLOR_CMD
-impl From<&Color> for [u8; UPDATE_BYTES] {
-    fn from(color: &Color) -> [u8; UPDATE_BYTES] {
-        let color = color.clamped();
-
-        let red_int = (color.r * f32::from(<u16>::max_value())).round() as u16;
-        let red_byte1 = ((red_int & 0xff00) >> 8) as u8;
-        let red_byte2 = (red_int & 0x00ff) as u8;
-
-        let green_int =
-            (color.g * f32::from(<u16>::max_value())).round() as u16;
-        let green_byte1 = ((green_int & 0xff00) >> 8) as u8;
-        let green_byte2 = (green_int & 0x00ff) as u8;
-
-        let blue_int = (color.b * f32::from(<u16>::max_value())).round() as u16;
-        let blue_byte1 = ((blue_int & 0xff00) >> 8) as u8;
-        let blue_byte2 = (blue_int & 0x00ff) as u8;
-
-        let white_int =
-            (color.w * f32::from(<u16>::max_value())).round() as u16;
-        let white_byte1 = ((white_int & 0xff00) >> 8) as u8;
-        let white_byte2 = (white_int & 0x00ff) as u8;
-
-        [
-            COLOR_CMD,
-            red_byte1,
-            red_byte2,
-            green_byte1,
-            green_byte2,
-            blue_byte1,
-            blue_byte2,
-            white_byte1,
-            white_byte2,
-        ]
-    }
-}
-
-/// Convert to the format that the Arduino is expecting, including the prefix
-/// magic number ROOM_CMD
-fn rooms_to_bytes(rooms: &Rooms) -> [u8; UPDATE_BYTES] {
-    [
-        ROOM_CMD,
-        if rooms.living_room { LIVING_ROOM } else { 0x00 },
-        if rooms.office { OFFICE } else { 0x00 },
-        if rooms.bedroom { BEDROOM } else { 0x00 },
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-    ]
+fn not_connected() -> Error {
+    Error::new(ErrorKind::NotConnected, "Serial port is not open")
+}
+
+/// Record the reply to a command in the metrics, returning an `InvalidData`
+/// error if it doesn't confirm the command
+fn record_reply(
+    command: &Command,
+    received: &str,
+    sent: Instant,
+) -> Result<(), Error> {
+    let expected = command.confirmation();
+    metrics::SERIAL_ROUND_TRIP.observe_duration(sent.elapsed());
+    metrics::SERIAL_REPLIES.inc(&[expected, received]);
+    if received != expected {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Serial reply didn't match `{}` (received `{}` instead)",
+                expected, received
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// A version 1 reply, without the `\r\n`
+fn text_reply(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).trim_end().to_string()
+}
+
+/// A version 2 reply, for diagnostics, e.g. `ACK [C0] #12`
+fn describe(frame: &Frame) -> String {
+    let name = match frame.command {
+        CMD_ACK => "ACK",
+        CMD_NAK => "NAK",
+        _ => "?",
+    };
+    format!("{} {:02X?} #{}", name, frame.payload, frame.seq)
+}
+
+/// Read until a reply to one of the frames `waiting` for one, skipping
+/// noise, bad frames and stale replies to earlier frames
+fn read_reply(
+    ser: &mut dyn SerialLink,
+    decoder: &mut FrameDecoder,
+    waiting: impl Fn(u8) -> bool,
+) -> Result<Frame, Error> {
+    let mut byte = [0; 1];
+    for _ in 0..MAX_REPLY_BYTES {
+        // Bytes left over from before might already hold the reply
+        while let Some(decoded) = decoder.next_frame() {
+            match decoded {
+                Ok(frame) if waiting(frame.seq) => return Ok(frame),
+                Ok(frame) => debug!("Skipping stale reply {:?}", frame),
+                Err(e) => warn!("Bad reply from the Arduino: {:?}", e),
+            }
+        }
+        ser.read_exact(&mut byte)?;
+        decoder.extend(&byte);
+    }
+    Err(Error::new(
+        ErrorKind::InvalidData,
+        "No reply from the Arduino in the bytes received",
+    ))
+}
+
+fn open_port(
+    tty_name: &str,
+    baud_rate: u32,
+) -> Result<SystemPort, serial::Error> {
+    let mut ser = serial::open(tty_name)?;
+    SerialPort::set_timeout(&mut ser, REPLY_TIMEOUT)?;
+    let mut settings = ser.read_settings()?;
+    settings.set_baud_rate(serial::BaudRate::from_speed(baud_rate as usize))?;
+    ser.write_settings(&settings)?;
+    Ok(ser)
+}
+
+/// Nearest 16-bit PWM levels for the R, G, B and W components
+fn color_levels(color: &Color) -> [u16; 4] {
+    let color = color.clamped();
+    [color.r, color.g, color.b, color.w]
+        .map(|c| (c * f32::from(u16::MAX)).round() as u16)
+}
+
+/// The color a fade starts from, then its segments, each added to the end of
+/// the one before
+fn fade_commands(start: &Color, segments: &[FadeSegment]) -> Vec<Command> {
+    let fades = segments
+        .iter()
+        .enumerate()
+        .map(|(i, segment)| Command::Fade {
+            levels: color_levels(&segment.color),
+            duration_ms: (segment.duration * 1000.0).round() as u32,
+            append: i > 0,
+        });
+    std::iter::once(Command::Color(color_levels(start)))
+        .chain(fades)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arduino_sim::ArduinoSim;
+
+    fn sim_config(ack_window: u8) -> SerialConfig {
+        SerialConfig {
+            tty_name: "sim".to_string(),
+            dither: false,
+            ack_window,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_firmware_versions() {
+        let rooms = Rooms {
+            living_room: true,
+            office: false,
+            bedroom: true,
+        };
+        for (firmware, version) in
+            [(1, ProtocolVersion::V1), (2, ProtocolVersion::V2)]
+        {
+            let port = ArduinoSim::new(firmware).into_port();
+            let mut mgr = SerialManager::with_link(
+                &sim_config(1),
+                Box::new(port.clone()),
+            );
+            mgr.flush();
+            assert!(mgr.status().connected);
+            assert_eq!(mgr.protocol(), version);
+
+            mgr.send_rooms(&rooms);
+            mgr.send_color(&Color::new(1.0, 0.0, 0.5, 0.0));
+            mgr.flush();
+            assert_eq!(port.sim().levels, [65535, 0, 32768, 0]);
+            assert_eq!(port.sim().relays, rooms);
+        }
+    }
+
+    #[test]
+    fn test_v2_recovers_from_noise() {
+        let port = ArduinoSim::new(2).into_port();
+        let mut mgr =
+            SerialManager::with_link(&sim_config(1), Box::new(port.clone()));
+        mgr.flush();
+
+        // Half a frame the firmware is still waiting on the rest of
+        port.sim().receive(&[START, 0x03]);
+        mgr.send_color(&Color::new(0.0, 1.0, 0.0, 0.0));
+        mgr.flush();
+        assert_eq!(port.sim().levels, [0, 65535, 0, 0]);
+        assert!(mgr.status().connected);
+    }
+
+    #[test]
+    fn test_firmware_fade() {
+        let segments = [
+            FadeSegment {
+                duration: 0.1,
+                color: Color::new(1.0, 0.0, 0.0, 0.0),
+            },
+            FadeSegment {
+                duration: 0.2,
+                color: Color::new(0.0, 0.0, 0.0, 0.0),
+            },
+        ];
+        let port = ArduinoSim::new(1).into_port();
+        let mut mgr =
+            SerialManager::with_link(&sim_config(1), Box::new(port.clone()));
+        mgr.flush();
+        assert!(!mgr.start_fade(&Color::default(), &segments));
+
+        let port = ArduinoSim::new(2).into_port();
+        let mut mgr =
+            SerialManager::with_link(&sim_config(1), Box::new(port.clone()));
+        mgr.flush();
+        assert!(mgr.start_fade(&Color::default(), &segments));
+        mgr.flush();
+        port.sim().advance(200);
+        assert_eq!(port.sim().levels, [32768, 0, 0, 0]);
+        port.sim().advance(100);
+        assert_eq!(port.sim().levels, [0; 4]);
+        assert!(!mgr.fade_failed());
+
+        // Firmware without room for the whole fade answers NAK_FULL
+        port.sim().max_fades = 1;
+        assert!(mgr.start_fade(&Color::default(), &segments));
+        mgr.flush();
+        assert!(mgr.fade_failed());
+
+        port.sim().max_fades = MAX_FADES;
+        assert!(mgr.start_fade(&Color::default(), &segments));
+        mgr.flush();
+        assert!(!mgr.fade_failed());
+    }
+
+    #[test]
+    fn test_ack_window() {
+        let port = ArduinoSim::new(2).into_port();
+        let mut mgr =
+            SerialManager::with_link(&sim_config(4), Box::new(port.clone()));
+        for i in 1..=100 {
+            mgr.send_color(&Color::new(i as f32 / 100.0, 0.0, 0.0, 0.0));
+        }
+        mgr.close(&Color::new(0.0, 0.0, 0.0, 0.5), &Rooms::default());
+        assert_eq!(port.sim().levels, [0, 0, 0, 32768]);
+        assert!(!mgr.status().connected);
+        assert_eq!(mgr.status().detail, "Closed for shutdown");
+    }
 }
@@ -0,0 +1,140 @@
+//! What the LEDs do when the server stops, set in the `[shutdown]` section of
+//! `led_config.toml`
+
+use std::time::Duration;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::led_sequence::LedSequence;
+use crate::led_state::LedState;
+use crate::rooms::Rooms;
+use crate::scenes::Scenes;
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum ShutdownPolicy {
+    /// Leave the LEDs and relays as they are
+    #[default]
+    Leave,
+    /// Fade to black over `fade_secs`
+    FadeToBlack,
+    /// Apply `scene`, e.g. a dim night light
+    Scene,
+}
+
+/// The `[shutdown]` section, e.g.
+///
+/// ```toml
+/// [shutdown]
+/// policy = "fade-to-black"  # or "leave", or "scene"
+/// fade_secs = 3.0
+/// rooms_off = true
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    pub policy: ShutdownPolicy,
+    /// Seconds to fade to black over
+    pub fade_secs: f32,
+    /// Switch the room relays off when fading to black
+    pub rooms_off: bool,
+    /// Scene from `[scenes]`, for the `scene` policy
+    pub scene: Option<String>,
+    /// Longest to wait for the fade or the scene's transition to finish
+    pub timeout_secs: f32,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            policy: ShutdownPolicy::Leave,
+            fade_secs: 2.0,
+            rooms_off: true,
+            scene: None,
+            timeout_secs: 10.0,
+        }
+    }
+}
+
+impl ShutdownConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs_f32(self.timeout_secs.max(0.0))
+    }
+
+    /// Start the fade or scene for the policy. Returns whether there's a
+    /// transition to wait for before stopping the LED worker.
+    pub fn begin(&self, scenes: &Scenes, state: &mut LedState) -> bool {
+        state.shutting_down = true;
+        state.live_frame = None;
+
+        match self.policy {
+            ShutdownPolicy::Leave => return false,
+            ShutdownPolicy::FadeToBlack => {
+                info!("Fading to black over {}s to shut down", self.fade_secs);
+                state.current_sequence = Some(LedSequence::fade_to_black(
+                    &state.current_color,
+                    self.fade_secs,
+                ));
+                if self.rooms_off {
+                    state.current_rooms = Rooms::default();
+                }
+            }
+            ShutdownPolicy::Scene => {
+                let name = self.scene.as_deref().unwrap_or_default();
+                let Some(scene) = scenes.get(name) else {
+                    error!("Shutdown scene {:?} doesn't exist", name);
+                    return false;
+                };
+                info!("Applying scene {:?} to shut down", name);
+                if let Err(e) = scene.apply(state) {
+                    error!(
+                        "Unable to apply shutdown scene {:?}: {:?}",
+                        name, e
+                    );
+                    return false;
+                }
+            }
+        }
+
+        state.playlist.clear();
+        // Repeating sequences never finish, so only wait for ones that do
+        state
+            .current_sequence
+            .as_ref()
+            .is_some_and(|seq| !seq.info.repeat)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Color;
+
+    #[test]
+    fn test_fade_to_black() {
+        let mut state = LedState {
+            current_color: Color::new(1.0, 0.5, 0.0, 0.2),
+            current_rooms: Rooms {
+                living_room: true,
+                office: false,
+                bedroom: true,
+            },
+            ..Default::default()
+        };
+        let config = ShutdownConfig {
+            policy: ShutdownPolicy::FadeToBlack,
+            ..Default::default()
+        };
+
+        assert!(config.begin(&Scenes::new(), &mut state));
+        assert_eq!(state.current_rooms, Rooms::default());
+        assert_eq!(state.future_color(), Color::default());
+
+        let mut state = LedState::default();
+        assert!(!ShutdownConfig::default().begin(&Scenes::new(), &mut state));
+        assert!(state.current_sequence.is_none());
+        assert!(state.shutting_down);
+    }
+}
@@ -0,0 +1,71 @@
+//! The web UI, built into the server so it can be deployed as a single file
+
+use actix_files::NamedFile;
+use actix_web::{web, HttpRequest, HttpResponse};
+
+use crate::led_sequence::plain_file;
+use crate::led_state::led_config;
+
+/// `index.html`, served at `/`
+pub const INDEX_HTML: &str = include_str!("../index.html");
+
+/// Files in `static/`, by name, with their content type
+const STATIC_FILES: [(&str, &str, &str); 3] = [
+    (
+        "index.css",
+        "text/css; charset=utf-8",
+        include_str!("../static/index.css"),
+    ),
+    (
+        "index.js",
+        "text/javascript; charset=utf-8",
+        include_str!("../static/index.js"),
+    ),
+    (
+        "ranges.css",
+        "text/css; charset=utf-8",
+        include_str!("../static/ranges.css"),
+    ),
+];
+
+/// Look up one of the built-in static files, returning its content type and
+/// contents
+pub fn find(name: &str) -> Option<(&'static str, &'static str)> {
+    STATIC_FILES
+        .iter()
+        .find(|(file, _, _)| *file == name)
+        .map(|&(_, content_type, contents)| (content_type, contents))
+}
+
+/// `/static/{name}`, from `paths.static_dir` if it's set, otherwise built in
+pub async fn get_static(
+    req: HttpRequest,
+    name: web::Path<String>,
+) -> HttpResponse {
+    if let Some(ref dir) = led_config().paths.static_dir {
+        return match plain_file(dir, &name).and_then(NamedFile::open) {
+            Ok(file) => file.into_response(&req),
+            Err(_) => HttpResponse::NotFound().into(),
+        };
+    }
+
+    match find(&name) {
+        Some((content_type, contents)) => {
+            HttpResponse::Ok().content_type(content_type).body(contents)
+        }
+        None => HttpResponse::NotFound().into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find() {
+        let (content_type, contents) = find("index.js").unwrap();
+        assert!(content_type.starts_with("text/javascript"));
+        assert_eq!(contents, include_str!("../static/index.js"));
+        assert!(find("../Cargo.toml").is_none());
+    }
+}
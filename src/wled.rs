@@ -0,0 +1,532 @@
+//! Subset of the WLED JSON API, so Home Assistant's WLED integration and the
+//! WLED apps can control the LEDs
+//!
+//! The whole strip is a single segment with an RGBW color. Effect 0 is a
+//! solid color, followed by the built-in effects and then the sequences in
+//! `sequence_dir()`.
+
+use std::path::Path;
+
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpResponse};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::color::Color;
+use crate::effects;
+use crate::led_sequence::{
+    self, LedSequence, LedSequenceInfo, LedSequenceType,
+};
+use crate::led_state::{LedState, LED_STATE, STARTED};
+use crate::network;
+
+/// WLED version the responses are modeled after
+const WLED_VERSION: &str = "0.14.0";
+
+/// Name of effect 0
+const SOLID_EFFECT: &str = "Solid";
+
+/// `on` can be a boolean or `"t"` to toggle
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum WledOn {
+    Set(bool),
+    Toggle(String),
+}
+
+/// Segment fields that can be changed
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct WledSegmentUpdate {
+    pub on: Option<WledOn>,
+    /// Primary, secondary and tertiary colors; only the primary is used
+    pub col: Vec<Vec<u8>>,
+    pub fx: Option<usize>,
+}
+
+/// Body of `POST /json/state`. Anything else WLED supports is ignored.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct WledStateUpdate {
+    pub on: Option<WledOn>,
+    pub bri: Option<u8>,
+    pub seg: Vec<WledSegmentUpdate>,
+    /// Respond with the full state instead of `{"success": true}`
+    pub v: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WledSegment {
+    pub id: usize,
+    pub start: usize,
+    pub stop: usize,
+    pub len: usize,
+    pub on: bool,
+    pub bri: u8,
+    pub col: [[u8; 4]; 3],
+    pub fx: usize,
+    pub sx: u8,
+    pub ix: u8,
+    pub pal: usize,
+    pub sel: bool,
+    pub rev: bool,
+    pub mi: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WledState {
+    pub on: bool,
+    pub bri: u8,
+    pub transition: u32,
+    pub ps: i32,
+    pub pl: i32,
+    pub mainseg: usize,
+    pub seg: Vec<WledSegment>,
+}
+
+/// Split a color into WLED's full-brightness color and a brightness
+fn split_brightness(color: &Color) -> ([u8; 4], u8) {
+    let color = color.clamped();
+    let max = color.r.max(color.g).max(color.b).max(color.w);
+    if max <= 0.0 {
+        return ([0; 4], 0);
+    }
+    let to_byte = |c: f32| (c / max * 255.0).round() as u8;
+    (
+        [
+            to_byte(color.r),
+            to_byte(color.g),
+            to_byte(color.b),
+            to_byte(color.w),
+        ],
+        (max * 255.0).round() as u8,
+    )
+}
+
+/// Combine WLED's color and brightness back into a single color
+fn apply_brightness(col: &[u8], bri: u8) -> Color {
+    let component = |i: usize| {
+        col.get(i).copied().unwrap_or(0) as f32 / 255.0 * bri as f32 / 255.0
+    };
+    Color::new(component(0), component(1), component(2), component(3))
+}
+
+/// Names of the effects WLED clients can pick, by index
+fn effect_names(sequences: &[String]) -> Vec<String> {
+    std::iter::once(SOLID_EFFECT.to_string())
+        .chain(effects::EFFECT_NAMES.iter().map(|name| name.to_string()))
+        .chain(sequences.iter().map(|path| sequence_stem(path)))
+        .collect()
+}
+
+fn sequence_stem(path: &str) -> String {
+    Path::new(path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.to_string())
+}
+
+/// Which effect index the running sequence is, if any
+fn current_effect(state: &LedState, sequences: &[String]) -> usize {
+    let Some(ref seq) = state.current_sequence else {
+        return 0;
+    };
+
+    if let Some(name) = effects::effect_name(&seq.info.sequence_type) {
+        return 1 + effects::EFFECT_NAMES
+            .iter()
+            .position(|n| *n == name)
+            .unwrap_or_default();
+    }
+
+    let name = match seq.info.sequence_type {
+        LedSequenceType::Script(ref script) => &script.name,
+        _ => &seq.info.name,
+    };
+    sequences
+        .iter()
+        .position(|path| sequence_stem(path) == *name)
+        .map(|i| 1 + effects::EFFECT_NAMES.len() + i)
+        .unwrap_or_default()
+}
+
+fn wled_state(state: &LedState, sequences: &[String]) -> WledState {
+    let (col, bri) = split_brightness(&state.future_color());
+    let on = bri > 0;
+
+    // While off, report the color and brightness it'll turn back on with
+    let (col, bri) = if on {
+        (col, bri)
+    } else if let Some(ref last) = state.last_on_color {
+        split_brightness(last)
+    } else {
+        ([255; 4], 255)
+    };
+
+    WledState {
+        on,
+        bri,
+        transition: 7,
+        ps: -1,
+        pl: -1,
+        mainseg: 0,
+        seg: vec![WledSegment {
+            id: 0,
+            start: 0,
+            stop: 1,
+            len: 1,
+            on,
+            bri: 255,
+            col: [col, [0; 4], [0; 4]],
+            fx: current_effect(state, sequences),
+            sx: 128,
+            ix: 128,
+            pal: 0,
+            sel: true,
+            rev: false,
+            mi: false,
+        }],
+    }
+}
+
+fn wled_info(state: &LedState, sequences: &[String]) -> serde_json::Value {
+    json!({
+        "ver": WLED_VERSION,
+        "vid": 0,
+        "leds": {
+            "count": 1,
+            "rgbw": true,
+            "wv": true,
+            "cct": false,
+            "fps": led_sequence::RESOLUTION as u32,
+            "pwr": 0,
+            "maxpwr": 0,
+            "maxseg": 1,
+            "lc": 3,
+            "seglc": [3],
+        },
+        "str": false,
+        "name": "LED Foot",
+        "udpport": 0,
+        "live": state.live_frame.is_some(),
+        "fxcount": effect_names(sequences).len(),
+        "palcount": 1,
+        "wifi": {
+            "bssid": "",
+            "rssi": 0,
+            "signal": 100,
+            "channel": 0,
+        },
+        "arch": std::env::consts::ARCH,
+        "core": env!("CARGO_PKG_VERSION"),
+        "freeheap": 0,
+        "uptime": STARTED.get().elapsed().as_secs(),
+        "brand": "WLED",
+        "product": "LED Foot",
+        "mac": network::mac_address(),
+        "ip": "",
+    })
+}
+
+/// Start the effect or sequence WLED clients know by `index`
+fn start_effect(
+    state: &LedState,
+    sequences: &[String],
+    index: usize,
+) -> Result<LedSequence, String> {
+    let effect_count = effects::EFFECT_NAMES.len();
+    if let Some(name) = index
+        .checked_sub(1)
+        .filter(|i| *i < effect_count)
+        .map(|i| effects::EFFECT_NAMES[i])
+    {
+        let sequence_type = effects::default_effect(name)
+            .ok_or_else(|| format!("Unknown effect {}", name))?;
+        let info = LedSequenceInfo {
+            sequence_type,
+            name: name.to_string(),
+            duration: 0.0,
+            repeat: true,
+        };
+        LedSequence::from_effect(&state.current_color, info)
+            .map_err(|e| format!("Unable to start effect {}: {:?}", name, e))
+    } else if let Some(path) = index
+        .checked_sub(1 + effect_count)
+        .and_then(|i| sequences.get(i))
+    {
+        LedSequence::from_name(&state.current_color, path)
+            .map_err(|e| format!("Unable to start sequence {}: {:?}", path, e))
+    } else {
+        Err(format!("No effect with index {}", index))
+    }
+}
+
+/// Apply a state update the way WLED would
+fn update_state(
+    state: &mut LedState,
+    sequences: &[String],
+    update: &WledStateUpdate,
+) -> Result<(), String> {
+    let current = wled_state(state, sequences);
+    let segment = update.seg.first().cloned().unwrap_or_default();
+
+    let mut on = current.on;
+    for toggle in [&update.on, &segment.on].into_iter().flatten() {
+        on = match toggle {
+            WledOn::Set(on) => *on,
+            WledOn::Toggle(_) => !on,
+        };
+    }
+    let bri = update.bri.unwrap_or(current.bri);
+    if bri == 0 {
+        on = false;
+    }
+
+    if !on {
+        if current.on {
+            state.last_on_color = Some(state.future_color());
+            state.playlist.clear();
+            state.current_sequence = Some(LedSequence::from_color_lerp(
+                &state.current_color,
+                &Color::default(),
+            ));
+        }
+        return Ok(());
+    }
+
+    let brightness = bri as f32 / 255.0;
+    if let Some(fx) =
+        segment.fx.filter(|fx| *fx > 0 && *fx != current.seg[0].fx)
+    {
+        let mut seq = start_effect(state, sequences, fx)?;
+        if update.bri.is_some() {
+            seq.set_brightness(brightness);
+        }
+        state.playlist.clear();
+        state.current_sequence = Some(seq);
+        return Ok(());
+    }
+
+    // Only change to a solid color if something about the color changed, to
+    // leave running effects alone. Their brightness is changed in place.
+    let effect = state
+        .current_sequence
+        .as_mut()
+        .filter(|seq| seq.info.repeat);
+    let solid = segment.fx == Some(0)
+        || !segment.col.is_empty()
+        || !current.on
+        || (update.bri.is_some() && effect.is_none());
+    if solid {
+        let col = segment
+            .col
+            .first()
+            .map_or(&current.seg[0].col[0][..], |c| &c[..]);
+        let color = apply_brightness(col, bri);
+        state.playlist.clear();
+        state.current_sequence =
+            Some(LedSequence::from_color_lerp(&state.current_color, &color));
+    } else if let Some(seq) = effect.filter(|_| update.bri.is_some()) {
+        seq.set_brightness(brightness);
+    }
+    Ok(())
+}
+
+fn sequences() -> Vec<String> {
+    led_sequence::list_sequences().unwrap_or_else(|e| {
+        warn!("Unable to list sequences for WLED effects: {:?}", e);
+        Vec::new()
+    })
+}
+
+/// Everything at once: state, info, effects and palettes
+pub async fn get_all() -> HttpResponse {
+    let sequences = sequences();
+    if let Ok(led_state) = LED_STATE.get().read() {
+        HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .json(json!({
+                "state": wled_state(&led_state, &sequences),
+                "info": wled_info(&led_state, &sequences),
+                "effects": effect_names(&sequences),
+                "palettes": ["Default"],
+            }))
+    } else {
+        error!("Error on /json: can't get lock on state");
+        HttpResponse::InternalServerError().into()
+    }
+}
+
+/// State and info, which is what clients poll
+pub async fn get_state_info() -> HttpResponse {
+    let sequences = sequences();
+    if let Ok(led_state) = LED_STATE.get().read() {
+        HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .json(json!({
+                "state": wled_state(&led_state, &sequences),
+                "info": wled_info(&led_state, &sequences),
+            }))
+    } else {
+        error!("Error on /json/si: can't get lock on state");
+        HttpResponse::InternalServerError().into()
+    }
+}
+
+pub async fn get_state() -> HttpResponse {
+    let sequences = sequences();
+    if let Ok(led_state) = LED_STATE.get().read() {
+        HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .json(wled_state(&led_state, &sequences))
+    } else {
+        error!("Error on /json/state: can't get lock on state");
+        HttpResponse::InternalServerError().into()
+    }
+}
+
+/// Turn on/off, set the brightness, color or effect
+///
+/// Takes over from the playlist, if one is running
+pub async fn set_state(payload: web::Json<WledStateUpdate>) -> HttpResponse {
+    debug!("WLED state: {:?}", payload);
+    let sequences = sequences();
+    if let Ok(mut led_state) = LED_STATE.get().write() {
+        if let Err(e) = update_state(&mut led_state, &sequences, &payload) {
+            return HttpResponse::BadRequest()
+                .content_type(ContentType::json())
+                .json(json!({ "error": e }));
+        }
+
+        if payload.v {
+            HttpResponse::Ok()
+                .content_type(ContentType::json())
+                .json(wled_state(&led_state, &sequences))
+        } else {
+            HttpResponse::Ok()
+                .content_type(ContentType::json())
+                .json(json!({ "success": true }))
+        }
+    } else {
+        error!("Error on /json/state: can't get lock on state");
+        HttpResponse::InternalServerError().into()
+    }
+}
+
+pub async fn get_info() -> HttpResponse {
+    let sequences = sequences();
+    if let Ok(led_state) = LED_STATE.get().read() {
+        HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .json(wled_info(&led_state, &sequences))
+    } else {
+        error!("Error on /json/info: can't get lock on state");
+        HttpResponse::InternalServerError().into()
+    }
+}
+
+pub async fn get_effects() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .json(effect_names(&sequences()))
+}
+
+/// There's only the one palette, since the strip is a single color
+pub async fn get_palettes() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .json(["Default"])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_brightness_round_trip() {
+        let color = Color::new(0.5, 0.25, 0.0, 0.1);
+        let (col, bri) = split_brightness(&color);
+        assert_eq!(col, [255, 128, 0, 51]);
+        assert_eq!(bri, 128);
+
+        let back = apply_brightness(&col, bri);
+        assert!((back.r - 0.5).abs() < 0.01);
+        assert!((back.g - 0.25).abs() < 0.01);
+        assert!((back.w - 0.1).abs() < 0.01);
+
+        // RGB colors leave white off
+        assert_eq!(apply_brightness(&[255, 0, 0], 255).w, 0.0);
+        assert_eq!(split_brightness(&Color::default()), ([0; 4], 0));
+    }
+
+    #[test]
+    fn test_update_state() {
+        let mut state = LedState {
+            current_color: Color::new(1.0, 0.0, 0.0, 0.0),
+            ..Default::default()
+        };
+        let sequences =
+            vec![format!("{}/sunrise.png", led_sequence::SEQUENCE_PATH)];
+
+        let update: WledStateUpdate = serde_json::from_str(
+            r#"{"seg": [{"col": [[0, 0, 255, 255]]}], "bri": 51}"#,
+        )
+        .unwrap();
+        update_state(&mut state, &sequences, &update).unwrap();
+        let color = state.future_color();
+        assert_eq!(color, Color::new(0.0, 0.0, 0.2, 0.2));
+
+        let update: WledStateUpdate =
+            serde_json::from_str(r#"{"seg": [{"fx": 3}]}"#).unwrap();
+        update_state(&mut state, &sequences, &update).unwrap();
+        assert_eq!(current_effect(&state, &sequences), 3);
+        assert_eq!(effect_names(&sequences)[3], "HueWheel");
+        assert_eq!(effect_names(&sequences)[7], "sunrise");
+
+        let update: WledStateUpdate =
+            serde_json::from_str(r#"{"on": "t"}"#).unwrap();
+        update_state(&mut state, &sequences, &update).unwrap();
+        assert_eq!(state.future_color(), Color::default());
+        assert!(!wled_state(&state, &sequences).on);
+    }
+
+    #[test]
+    fn test_brightness_keeps_effect() {
+        let mut state = LedState {
+            current_color: Color::new(1.0, 0.0, 0.0, 0.0),
+            ..Default::default()
+        };
+        let sequences = Vec::new();
+
+        let update: WledStateUpdate =
+            serde_json::from_str(r#"{"seg": [{"fx": 3}]}"#).unwrap();
+        update_state(&mut state, &sequences, &update).unwrap();
+        let seq = state.current_sequence.as_ref().unwrap();
+        let full = seq.color_at(10.0);
+
+        let update: WledStateUpdate =
+            serde_json::from_str(r#"{"bri": 128}"#).unwrap();
+        update_state(&mut state, &sequences, &update).unwrap();
+        assert_eq!(current_effect(&state, &sequences), 3);
+        let seq = state.current_sequence.as_ref().unwrap();
+        assert_eq!(seq.color_at(10.0), full * (128.0 / 255.0));
+    }
+
+    #[test]
+    fn test_turn_on_with_last_color() {
+        // Turned off from the Hue API
+        let mut state = LedState {
+            last_on_color: Some(Color::new(0.0, 0.6, 0.0, 0.2)),
+            ..Default::default()
+        };
+        let sequences = Vec::new();
+
+        let update: WledStateUpdate =
+            serde_json::from_str(r#"{"on": true}"#).unwrap();
+        update_state(&mut state, &sequences, &update).unwrap();
+        let color = state.future_color();
+        assert!((color.g - 0.6).abs() < 0.01);
+        assert!((color.w - 0.2).abs() < 0.01);
+        assert_eq!(color.r, 0.0);
+    }
+}